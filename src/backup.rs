@@ -0,0 +1,86 @@
+//! Offline backup and restore of the database file
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, Context as _};
+use color_eyre::Result;
+use tracing::info;
+
+use crate::db::{RedbStorage, Storage as _};
+
+/// `path` with a `suffix` appended to the file name
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = OsString::from(path.as_os_str());
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+/// Snapshot database at `db_path` into a new file at `dst`
+pub async fn backup(db_path: &Path, dst: &Path) -> Result<()> {
+    if dst.exists() {
+        bail!("Backup destination {} already exists", dst.display());
+    }
+
+    let db = RedbStorage::open_existing(db_path)
+        .await
+        .wrap_err("Failed to open database; use the `/backup` endpoint of a running perfitd")?;
+
+    // Write to a temporary file first, so there's never a partial backup at `dst`
+    let tmp_path = with_suffix(dst, ".tmp");
+    tokio::task::spawn_blocking({
+        let tmp_path = tmp_path.clone();
+        move || db.backup_to(&tmp_path)
+    })
+    .await??;
+    tokio::fs::rename(&tmp_path, dst).await?;
+
+    info!(path = %dst.display(), "Backup complete");
+    Ok(())
+}
+
+/// Replace database at `db_path` with a backup from `src`
+///
+/// The backup is verified before anything is touched, and the previous
+/// database file (if any) is kept with a `.pre-restore` suffix (which must not
+/// exist yet).
+pub async fn restore(db_path: &Path, src: &Path) -> Result<()> {
+    let db_exists = tokio::fs::try_exists(db_path).await?;
+    let pre_restore_path = with_suffix(db_path, ".pre-restore");
+    if db_exists {
+        if tokio::fs::try_exists(&pre_restore_path).await? {
+            bail!(
+                "Previous database {} already exists; move it away first",
+                pre_restore_path.display()
+            );
+        }
+        // Fails if the database is currently in use, e.g. by a running perfitd
+        drop(
+            redb::Database::open(db_path)
+                .wrap_err("Failed to open current database; is perfitd still running?")?,
+        );
+    }
+
+    let tmp_path = with_suffix(db_path, ".restore.tmp");
+    tokio::fs::copy(src, &tmp_path).await?;
+
+    let tmp_path_verify = tmp_path.clone();
+    if let Err(err) = tokio::task::spawn_blocking(move || RedbStorage::verify(&tmp_path_verify))
+        .await?
+        .wrap_err("Backup verification failed")
+    {
+        tokio::fs::remove_file(&tmp_path).await?;
+        return Err(err);
+    }
+    info!(path = %src.display(), "Backup verified");
+
+    if db_exists {
+        tokio::fs::rename(db_path, &pre_restore_path).await?;
+        info!(path = %pre_restore_path.display(), "Previous database moved");
+    }
+
+    tokio::fs::rename(&tmp_path, db_path).await?;
+
+    info!(path = %db_path.display(), "Restore complete");
+    Ok(())
+}
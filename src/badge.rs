@@ -0,0 +1,246 @@
+//! Shields-style status badges
+
+use std::fmt::Write as _;
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::DataPointRecord;
+use crate::models::ts::Ts;
+use crate::stats;
+
+const COLOR_LABEL: &str = "#555";
+const COLOR_OK: &str = "#4c1";
+const COLOR_WARN: &str = "#dfb317";
+const COLOR_CRIT: &str = "#e05d44";
+const COLOR_UNKNOWN: &str = "#9f9f9f";
+
+const HEIGHT: u32 = 20;
+const SPARKLINE_WIDTH: u32 = 40;
+const SPARKLINE_POINTS: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct BadgeOpts {
+    /// Text on the left side of the badge
+    #[serde(default = "BadgeOpts::default_label")]
+    pub label: String,
+    /// Unit appended to the value
+    #[serde(default)]
+    pub unit: String,
+    /// Value at which the badge turns yellow
+    #[serde(default)]
+    pub warn: Option<f64>,
+    /// Value at which the badge turns red
+    #[serde(default)]
+    pub crit: Option<f64>,
+    /// Number of previous data points to compute median from, when no
+    /// thresholds were set
+    #[serde(default = "BadgeOpts::default_trailing")]
+    pub trailing: usize,
+    /// Relative change vs trailing median at which the badge turns yellow
+    #[serde(default = "BadgeOpts::default_delta_warn")]
+    pub delta_warn: f64,
+    /// Relative change vs trailing median at which the badge turns red
+    #[serde(default = "BadgeOpts::default_delta_crit")]
+    pub delta_crit: f64,
+    /// By default growing values are considered a regression
+    #[serde(default)]
+    pub higher_is_better: bool,
+    /// Draw a tiny chart of recent values
+    #[serde(default)]
+    pub sparkline: bool,
+}
+
+impl BadgeOpts {
+    const MAX_TRAILING: usize = 100;
+
+    fn default_label() -> String {
+        "perfit".into()
+    }
+
+    fn default_trailing() -> usize {
+        10
+    }
+
+    fn default_delta_warn() -> f64 {
+        0.1
+    }
+
+    fn default_delta_crit() -> f64 {
+        0.25
+    }
+
+    /// Number of latest data points needed to render the badge
+    pub fn num_data_points(&self) -> usize {
+        let trailing = self.trailing.min(Self::MAX_TRAILING) + 1;
+        if self.sparkline {
+            trailing.max(SPARKLINE_POINTS)
+        } else {
+            trailing
+        }
+    }
+
+    fn color(&self, latest: f64, trailing: &[f64]) -> &'static str {
+        // Positive when things got worse
+        let worse_by = |val: f64, threshold: f64| {
+            if self.higher_is_better {
+                threshold - val
+            } else {
+                val - threshold
+            }
+        };
+
+        if self.warn.is_some() || self.crit.is_some() {
+            return if self.crit.is_some_and(|crit| 0. <= worse_by(latest, crit)) {
+                COLOR_CRIT
+            } else if self.warn.is_some_and(|warn| 0. <= worse_by(latest, warn)) {
+                COLOR_WARN
+            } else {
+                COLOR_OK
+            };
+        }
+
+        let Some(median) = stats::median(&stats::sorted(trailing.iter().copied())) else {
+            return COLOR_UNKNOWN;
+        };
+
+        if median == 0. {
+            return COLOR_UNKNOWN;
+        }
+
+        let delta = worse_by(latest, median) / median.abs();
+        if self.delta_crit <= delta {
+            COLOR_CRIT
+        } else if self.delta_warn <= delta {
+            COLOR_WARN
+        } else {
+            COLOR_OK
+        }
+    }
+}
+
+fn fmt_value(val: f64) -> String {
+    let abs = val.abs();
+    if 100. <= abs {
+        format!("{val:.0}")
+    } else if 10. <= abs {
+        format!("{val:.1}")
+    } else {
+        format!("{val:.2}")
+    }
+}
+
+/// Rough width of a text rendered with 11px Verdana
+fn text_width(s: &str) -> u32 {
+    s.chars().count() as u32 * 7 + 10
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Render a badge for `measurements`, which should be the latest data points
+/// of a metric, ordered oldest to newest
+pub fn render_badge(measurements: &[(Ts, DataPointRecord)], opts: &BadgeOpts) -> String {
+    let values: Vec<f64> = measurements
+        .iter()
+        .map(|(_, r)| f64::from(r.value.as_f32()))
+        .collect();
+
+    let (value_text, color) = match values.split_last() {
+        Some((&latest, previous)) => {
+            let trailing = &previous[previous.len().saturating_sub(opts.trailing)..];
+            (
+                format!("{}{}", fmt_value(latest), opts.unit),
+                opts.color(latest, trailing),
+            )
+        }
+        None => ("no data".to_owned(), COLOR_UNKNOWN),
+    };
+
+    let label_width = text_width(&opts.label);
+    let value_width = text_width(&value_text);
+
+    let label = escape_xml(&opts.label);
+    let value_text = escape_xml(&value_text);
+    let sparkline_width = if opts.sparkline && 1 < values.len() {
+        SPARKLINE_WIDTH
+    } else {
+        0
+    };
+    let width = label_width + value_width + sparkline_width;
+
+    let label_x = label_width / 2;
+    let value_x = label_width + value_width / 2;
+
+    let mut svg = String::new();
+    write!(
+        svg,
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{HEIGHT}" role="img" aria-label="{label}: {value_text}">"##
+    )
+    .expect("Can't fail");
+    write!(
+        svg,
+        r##"<title>{label}: {value_text}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{width}" height="{HEIGHT}" rx="3" fill="#fff"/></clipPath>"##
+    )
+    .expect("Can't fail");
+    write!(
+        svg,
+        r##"<g clip-path="url(#r)"><rect width="{label_width}" height="{HEIGHT}" fill="{COLOR_LABEL}"/><rect x="{label_width}" width="{}" height="{HEIGHT}" fill="{color}"/><rect width="{width}" height="{HEIGHT}" fill="url(#s)"/></g>"##,
+        value_width + sparkline_width
+    )
+    .expect("Can't fail");
+    write!(
+        svg,
+        r##"<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11"><text x="{label_x}" y="15" fill="#010101" fill-opacity=".3">{label}</text><text x="{label_x}" y="14">{label}</text><text x="{value_x}" y="15" fill="#010101" fill-opacity=".3">{value_text}</text><text x="{value_x}" y="14">{value_text}</text></g>"##
+    )
+    .expect("Can't fail");
+
+    if 0 < sparkline_width {
+        let recent = &values[values.len().saturating_sub(SPARKLINE_POINTS)..];
+        let x0 = f64::from(label_width + value_width);
+        write!(
+            svg,
+            r##"<polyline fill="none" stroke="#fff" stroke-width="1.2" stroke-opacity=".9" points="{}"/>"##,
+            sparkline_points(recent, x0, f64::from(sparkline_width))
+        )
+        .expect("Can't fail");
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Coordinates of a sparkline fitting in a box starting at `x0` of `width`
+fn sparkline_points(values: &[f64], x0: f64, width: f64) -> String {
+    const PADDING: f64 = 3.;
+
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let range = if max - min == 0. { 1. } else { max - min };
+
+    let step = (width - PADDING * 2.) / (values.len().saturating_sub(1).max(1)) as f64;
+    let height = f64::from(HEIGHT) - PADDING * 2.;
+
+    let mut points = String::new();
+    for (i, v) in values.iter().enumerate() {
+        let x = x0 + PADDING + i as f64 * step;
+        let y = PADDING + height - (v - min) / range * height;
+        if !points.is_empty() {
+            points.push(' ');
+        }
+        write!(points, "{x:.1},{y:.1}").expect("Can't fail");
+    }
+    points
+}
@@ -0,0 +1,199 @@
+//! Configuration files of `perfit`
+//!
+//! Two files are read, if they exist: the user one (`perfit/config.toml` in
+//! the XDG config directory, or `PERFIT_CONFIG`), and a project one
+//! (`.perfit.toml` in the current directory or any of its parents). Values
+//! of the project file take precedence, and flags and env variables take
+//! precedence over both.
+//!
+//! A profile is always taken whole from one file, so that a project file
+//! can't send the access token of a user profile to a server of its choice.
+//!
+//! ```toml
+//! profile = "prod"
+//!
+//! [profiles.prod]
+//! server = "https://perfit.example.com"
+//! access-token = "..."
+//!
+//! [metrics]
+//! build-time = "AH-57edqR8SiMSRr4ArSWg"
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use color_eyre::eyre::{bail, WrapErr as _};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::opts::{MetricArgs, ServerArgs};
+
+const PROJECT_FILE_NAME: &str = ".perfit.toml";
+/// Profile used when none is selected
+const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Profile {
+    pub server: Option<Url>,
+    pub access_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Profile to use, unless selected with `--profile`
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Metric ids by alias
+    #[serde(default)]
+    pub metrics: BTreeMap<String, String>,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self> {
+        toml::from_str(&fs::read_to_string(path)?)
+            .wrap_err_with(|| format!("Parsing {}", path.display()))
+    }
+
+    /// Layer `other` on top of `self`
+    fn merge(mut self, other: ConfigFile) -> Self {
+        if other.profile.is_some() {
+            self.profile = other.profile;
+        }
+        // Whole profiles only, never a server from one file with an access
+        // token from another
+        self.profiles.extend(other.profiles);
+        self.metrics.extend(other.metrics);
+        self
+    }
+}
+
+/// Merged configuration files
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Files that were read, in the order of precedence (lowest first)
+    pub files: Vec<PathBuf>,
+    pub file: ConfigFile,
+}
+
+/// A server to talk to, with everything needed
+#[derive(Debug, Clone)]
+pub struct Server {
+    pub url: Url,
+    pub access_token: String,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let mut config = Config::default();
+        for path in [user_config_path(), project_config_path()?]
+            .into_iter()
+            .flatten()
+        {
+            if path.exists() {
+                config.file = config.file.merge(ConfigFile::read(&path)?);
+                config.files.push(path);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Name of the selected profile, if any
+    pub fn profile_name(&self, server_args: &ServerArgs) -> Result<Option<String>> {
+        let name = server_args
+            .profile
+            .clone()
+            .or_else(|| self.file.profile.clone());
+        match name {
+            Some(name) if !self.file.profiles.contains_key(&name) => {
+                bail!("Profile `{name}` not found in the configuration files")
+            }
+            Some(name) => Ok(Some(name)),
+            None => Ok(self
+                .file
+                .profiles
+                .contains_key(DEFAULT_PROFILE)
+                .then(|| DEFAULT_PROFILE.to_owned())),
+        }
+    }
+
+    fn profile(&self, server_args: &ServerArgs) -> Result<Profile> {
+        Ok(self
+            .profile_name(server_args)?
+            .and_then(|name| self.file.profiles.get(&name).cloned())
+            .unwrap_or_default())
+    }
+
+    pub fn server(&self, server_args: &ServerArgs) -> Result<Server> {
+        let profile = self.profile(server_args)?;
+        let Some(url) = server_args.server.clone().or(profile.server) else {
+            bail!("No server set; use `--server`, `PERFIT_SERVER` or a configuration file profile");
+        };
+        let Some(access_token) = server_args.access_token.clone().or(profile.access_token) else {
+            bail!("No access token set; use `--access-token`, `PERFIT_ACCESS_TOKEN` or a configuration file profile");
+        };
+        Ok(Server { url, access_token })
+    }
+
+    /// Resolve a metric alias to its id; anything else is taken as an id
+    pub fn metric_id(&self, metric: &str) -> String {
+        self.file
+            .metrics
+            .get(metric)
+            .cloned()
+            .unwrap_or_else(|| metric.to_owned())
+    }
+
+    pub fn metric(&self, metric_args: &MetricArgs) -> String {
+        self.metric_id(&metric_args.metric)
+    }
+
+    pub fn effective(&self, server_args: &ServerArgs) -> Result<Effective> {
+        let profile = self.profile(server_args)?;
+        Ok(Effective {
+            files: self.files.clone(),
+            profile: self.profile_name(server_args)?,
+            server: server_args.server.clone().or(profile.server),
+            access_token: server_args
+                .access_token
+                .as_ref()
+                .or(profile.access_token.as_ref())
+                .map(|_| "<hidden>"),
+            metrics: self.file.metrics.clone(),
+        })
+    }
+}
+
+/// The configuration in effect, as shown by `perfit config`
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Effective {
+    /// Configuration files read, lowest precedence first
+    pub files: Vec<PathBuf>,
+    pub profile: Option<String>,
+    pub server: Option<Url>,
+    /// Never shown, only whether it's set
+    pub access_token: Option<&'static str>,
+    pub metrics: BTreeMap<String, String>,
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("PERFIT_CONFIG") {
+        return Some(path.into());
+    }
+    dirs::config_dir().map(|dir| dir.join("perfit").join("config.toml"))
+}
+
+/// The closest project file, looking up from the current directory
+fn project_config_path() -> Result<Option<PathBuf>> {
+    let cwd = env::current_dir()?;
+    Ok(cwd
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_FILE_NAME))
+        .find(|path| path.exists()))
+}
@@ -0,0 +1,222 @@
+//! Results of benchmark tools, for `perfit ingest`
+//!
+//! All the values are durations, converted to seconds like the ones of
+//! `perfit run`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::str::FromStr as _;
+
+use color_eyre::eyre::{bail, format_err, WrapErr as _};
+use color_eyre::Result;
+use serde::Deserialize;
+
+use crate::opts::IngestFormat;
+
+const NANOS_PER_SEC: f64 = 1_000_000_000.;
+
+/// Result of a single benchmark
+#[derive(Debug, Clone)]
+pub struct Benchmark {
+    pub name: String,
+    /// In seconds
+    pub value: f64,
+}
+
+pub fn parse(format: IngestFormat, path: &Path) -> Result<Vec<Benchmark>> {
+    let benchmarks = match format {
+        IngestFormat::Criterion => parse_criterion(path)?,
+        IngestFormat::Hyperfine => parse_hyperfine(&read(path)?)?,
+        IngestFormat::LibtestBench => parse_libtest_bench(&read(path)?)?,
+        IngestFormat::Junit => parse_junit(&read(path)?)?,
+    };
+    if benchmarks.is_empty() {
+        bail!("No benchmarks found in {}", path.display());
+    }
+    Ok(benchmarks)
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).wrap_err_with(|| format!("Reading {}", path.display()))
+}
+
+#[derive(Deserialize)]
+struct CriterionBenchmark {
+    full_id: String,
+}
+
+#[derive(Deserialize)]
+struct CriterionEstimate {
+    /// In nanoseconds
+    point_estimate: f64,
+}
+
+#[derive(Deserialize)]
+struct CriterionEstimates {
+    mean: CriterionEstimate,
+}
+
+/// Every benchmark has a `<...>/new/benchmark.json` and
+/// `<...>/new/estimates.json` in the Criterion output directory
+fn parse_criterion(dir: &Path) -> Result<Vec<Benchmark>> {
+    let mut benchmarks = vec![];
+    for new_dir in find_dirs_named(dir, "new")? {
+        let benchmark_path = new_dir.join("benchmark.json");
+        let estimates_path = new_dir.join("estimates.json");
+        if !benchmark_path.exists() || !estimates_path.exists() {
+            continue;
+        }
+        let benchmark: CriterionBenchmark = serde_json::from_str(&read(&benchmark_path)?)
+            .wrap_err_with(|| format!("Parsing {}", benchmark_path.display()))?;
+        let estimates: CriterionEstimates = serde_json::from_str(&read(&estimates_path)?)
+            .wrap_err_with(|| format!("Parsing {}", estimates_path.display()))?;
+        benchmarks.push(Benchmark {
+            name: benchmark.full_id,
+            value: estimates.mean.point_estimate / NANOS_PER_SEC,
+        });
+    }
+    benchmarks.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(benchmarks)
+}
+
+/// All directories called `name` under `dir`
+fn find_dirs_named(dir: &Path, name: &str) -> Result<Vec<PathBuf>> {
+    let mut found = vec![];
+    let mut pending = vec![dir.to_owned()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).wrap_err_with(|| format!("Reading {}", dir.display()))? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if entry.file_name() == name {
+                found.push(entry.path());
+            } else {
+                pending.push(entry.path());
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[derive(Deserialize)]
+struct HyperfineResult {
+    /// The command, or its `--command-name`
+    command: String,
+    /// In seconds
+    mean: f64,
+}
+
+#[derive(Deserialize)]
+struct HyperfineResults {
+    results: Vec<HyperfineResult>,
+}
+
+fn parse_hyperfine(s: &str) -> Result<Vec<Benchmark>> {
+    let results: HyperfineResults = serde_json::from_str(s)?;
+    Ok(results
+        .results
+        .into_iter()
+        .map(|result| Benchmark {
+            name: result.command,
+            value: result.mean,
+        })
+        .collect())
+}
+
+/// Lines like `test name ... bench:   1,234 ns/iter (+/- 56)`
+fn parse_libtest_bench(s: &str) -> Result<Vec<Benchmark>> {
+    let mut benchmarks = vec![];
+    for line in s.lines() {
+        let Some(line) = line.strip_prefix("test ") else {
+            continue;
+        };
+        let Some((name, result)) = line.split_once(" ... bench:") else {
+            continue;
+        };
+        let Some((nanos, _)) = result.trim_start().split_once(" ns/iter") else {
+            bail!("Unexpected benchmark result: {line}");
+        };
+        let nanos = f64::from_str(&nanos.replace(',', ""))
+            .map_err(|_| format_err!("Invalid benchmark duration: {nanos}"))?;
+        benchmarks.push(Benchmark {
+            name: name.trim().to_owned(),
+            value: nanos / NANOS_PER_SEC,
+        });
+    }
+    Ok(benchmarks)
+}
+
+/// Every `<testcase>` with a `time`, named `<classname>/<name>`
+fn parse_junit(s: &str) -> Result<Vec<Benchmark>> {
+    let doc = roxmltree::Document::parse(s)?;
+    let mut benchmarks = vec![];
+    for testcase in doc.descendants().filter(|n| n.has_tag_name("testcase")) {
+        let (Some(name), Some(time)) = (testcase.attribute("name"), testcase.attribute("time"))
+        else {
+            continue;
+        };
+        let name = match testcase.attribute("classname") {
+            Some(classname) if !classname.is_empty() => format!("{classname}/{name}"),
+            _ => name.to_owned(),
+        };
+        let value =
+            f64::from_str(time).map_err(|_| format_err!("Invalid test case time: {time}"))?;
+        benchmarks.push(Benchmark { name, value });
+    }
+    Ok(benchmarks)
+}
+
+/// Metrics of benchmarks, kept in a TOML file of `"<benchmark>" = "<metric>"`
+/// lines
+#[derive(Debug)]
+pub struct Mapping {
+    path: PathBuf,
+    metrics: BTreeMap<String, String>,
+}
+
+impl Mapping {
+    /// A missing file is an empty mapping, to be filled with
+    /// `--create-metrics`
+    pub fn load(path: &Path) -> Result<Self> {
+        let metrics = if path.exists() {
+            toml::from_str(&read(path)?).wrap_err_with(|| format!("Parsing {}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            metrics,
+        })
+    }
+
+    pub fn get(&self, benchmark: &str) -> Option<&str> {
+        self.metrics.get(benchmark).map(String::as_str)
+    }
+
+    /// Add a benchmark, appending it to the file to keep anything already
+    /// there (like comments) intact
+    pub fn insert(&mut self, benchmark: &str, metric: &str) -> Result<()> {
+        let needs_newline = fs::read(&self.path)
+            .map(|content| !content.is_empty() && !content.ends_with(b"\n"))
+            .unwrap_or(false);
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .wrap_err_with(|| format!("Opening {}", self.path.display()))?;
+        if needs_newline {
+            writeln!(file)?;
+        }
+        writeln!(
+            file,
+            "{} = {}",
+            toml::Value::from(benchmark),
+            toml::Value::from(metric)
+        )?;
+        self.metrics.insert(benchmark.to_owned(), metric.to_owned());
+        Ok(())
+    }
+}
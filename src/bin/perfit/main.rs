@@ -1,21 +1,36 @@
+use std::collections::BTreeMap;
+use std::io::{IsTerminal as _, Write as _};
 use std::process::{exit, ExitStatus};
 use std::time::Duration;
 
 use clap::Parser as _;
-use color_eyre::eyre::bail;
+use color_eyre::eyre::{bail, format_err};
 use color_eyre::Result;
-use opts::{MetricArgs, ServerArgs};
+use config::{Config, Server};
+use opts::{Aggregate, MetricArgs, RepeatArgs, RetentionArgs, SendArgs, ServerArgs, SpoolArgs};
 use perfitd::models::access_token::AccessToken;
 use perfitd::models::AccessTokenType;
+use perfitd::stats;
 use reqwest::header::AUTHORIZATION;
-use reqwest::Method;
+use reqwest::{Method, StatusCode};
 use serde::Serialize;
 use serde_json::json;
-use tracing::info;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+use url::Url;
+use uuid::Uuid;
 
 use crate::opts::Opts;
 
+mod config;
+mod ingest;
+mod marks;
+mod metadata;
 mod opts;
+mod run;
+mod show;
+mod spool;
 
 const LOG_PERFIT: &str = "perfit";
 
@@ -23,56 +38,179 @@ const LOG_PERFIT: &str = "perfit";
 async fn main() -> Result<()> {
     install_tracing();
     let _opts = Opts::parse();
+    let config = Config::load()?;
 
     match _opts.cmd {
         opts::Command::Run {
             server_args,
             data_point_args,
+            send_args,
+            repeat_args,
             cmd,
             send_on_failure,
             fail_on_send_failure,
+            phase_metrics,
+            timeout,
             metric_args,
         } => {
-            let (duration, exit_status) = run_and_time(cmd)?;
-            let exit_code = exit_status.code().unwrap_or(255);
+            let (measurements, exit_status) =
+                run_repeatedly(&cmd, &repeat_args, timeout, send_on_failure).await?;
+            let mut data_point_args = data_point_args.with_detected_metadata();
+            let exit_code = run::exit_code(exit_status);
 
-            info!(target: LOG_PERFIT, duration_millis = duration.as_millis(), exit_code, "Command complete");
-            if !exit_status.success() && !send_on_failure {
+            // Stopped runs are always reported, so they show up on the chart
+            let stopped = measurements.interruption.is_some();
+            if (!exit_status.success() && !send_on_failure && !stopped)
+                || measurements.durations.is_empty()
+            {
                 exit(exit_code);
             }
+            data_point_args.outcome = match measurements.interruption {
+                Some(run::Interruption::Timeout) => opts::Outcome::Timeout,
+                Some(run::Interruption::Signal(_)) => opts::Outcome::Interrupted,
+                None if !exit_status.success() => opts::Outcome::Failure,
+                None => opts::Outcome::Success,
+            };
 
-            if let Err(err) = send_data_point(
-                &server_args,
+            let server = config.server(&server_args)?;
+            for (metric, data_point_args, value) in run_data_points(
+                &config,
                 &metric_args,
                 &data_point_args,
-                duration.as_micros() as f32 / 1_000_000.,
-            )
-            .await
-            {
-                if fail_on_send_failure {
-                    return Err(err);
+                &repeat_args,
+                &phase_metrics,
+                &measurements,
+            )? {
+                if let Err(err) =
+                    send_data_point(&server, &metric, &data_point_args, &send_args, value).await
+                {
+                    if fail_on_send_failure {
+                        return Err(err);
+                    }
+                    eprintln!("Failed to report data point: {}", err);
                 }
-                eprintln!("Failed to report data point: {}", err);
             }
             exit(exit_code);
         }
         opts::Command::Post {
             server_args,
             data_point_args,
+            send_args,
             data_point,
             metric_args,
-        } => send_data_point(&server_args, &metric_args, &data_point_args, data_point).await?,
+        } => {
+            send_data_point(
+                &config.server(&server_args)?,
+                &config.metric(&metric_args),
+                &data_point_args.with_detected_metadata(),
+                &send_args,
+                data_point,
+            )
+            .await?
+        }
+        opts::Command::Ingest {
+            server_args,
+            data_point_args,
+            send_args,
+            format,
+            mapping,
+            create_metrics,
+            path,
+        } => {
+            ingest(
+                &config,
+                &config.server(&server_args)?,
+                &data_point_args.with_detected_metadata(),
+                &send_args,
+                &ingest::parse(format, &path)?,
+                &mut ingest::Mapping::load(&mapping)?,
+                create_metrics,
+            )
+            .await?
+        }
+        opts::Command::Mark { phase } => marks::mark(&phase)?,
+        opts::Command::Flush {
+            server_args,
+            spool_args,
+        } => flush(&config.server(&server_args)?, &spool_args).await?,
 
+        opts::Command::Annotate {
+            server_args,
+            metric,
+            url,
+            ts,
+            label,
+        } => {
+            annotate(
+                &config.server(&server_args)?,
+                &label,
+                metric.map(|metric| config.metric_id(&metric)).as_deref(),
+                url.as_ref(),
+                ts.as_deref(),
+            )
+            .await?
+        }
+        opts::Command::Config { server_args } => config_show(&config, &server_args)?,
         opts::Command::Account(opts::AccountCommand::New { server_args }) => {
-            account_new(&server_args).await?
+            account_new(&config.server(&server_args)?).await?
+        }
+        opts::Command::Account(opts::AccountCommand::Retention {
+            server_args,
+            retention_args,
+        }) => {
+            retention_set(
+                &config.server(&server_args)?,
+                "a/retention",
+                &retention_args,
+            )
+            .await?
+        }
+        opts::Command::Account(opts::AccountCommand::Export {
+            server_args,
+            disable,
+        }) => {
+            make_request_json(
+                &config.server(&server_args)?,
+                Method::PUT,
+                "a/export",
+                &!disable,
+            )
+            .await?;
         }
         opts::Command::Metric(opts::MetricCommand::New { server_args }) => {
-            metric_new(&server_args).await?
+            metric_new(&config.server(&server_args)?).await?
         }
         opts::Command::Metric(opts::MetricCommand::Get {
             server_args,
             metric_args,
-        }) => metric_get(&server_args, &metric_args).await?,
+        }) => metric_get(&config.server(&server_args)?, &config.metric(&metric_args)).await?,
+        opts::Command::Metric(opts::MetricCommand::Show {
+            server_args,
+            metric_args,
+            last,
+            follow,
+            interval,
+        }) => {
+            metric_show(
+                &config.server(&server_args)?,
+                &config.metric(&metric_args),
+                last,
+                follow.then_some(interval),
+            )
+            .await?
+        }
+        opts::Command::Metric(opts::MetricCommand::Retention {
+            server_args,
+            metric_args,
+            retention_args,
+        }) => {
+            retention_set(
+                &config.server(&server_args)?,
+                &format!("m/{}/retention", config.metric(&metric_args)),
+                &retention_args,
+            )
+            .await?
+        }
         opts::Command::Token(opts::TokenCommand::Gen) => {
             println!("{}", AccessToken::generate())
         }
@@ -80,7 +218,7 @@ async fn main() -> Result<()> {
             server_args,
             r#type,
         }) => {
-            token_new(&server_args, &r#type).await?;
+            token_new(&config.server(&server_args)?, &r#type).await?;
         }
     }
 
@@ -88,18 +226,15 @@ async fn main() -> Result<()> {
 }
 
 async fn make_request(
-    server_args: &ServerArgs,
+    server: &Server,
     method: reqwest::Method,
     path: &str,
     body: impl Into<reqwest::Body>,
 ) -> Result<reqwest::Response> {
     let client = reqwest::Client::new();
     let response = client
-        .request(method, server_args.server.join(path)?)
-        .header(
-            AUTHORIZATION,
-            format!("Bearer {}", &server_args.access_token),
-        )
+        .request(method, server.url.join(path)?)
+        .header(AUTHORIZATION, format!("Bearer {}", &server.access_token))
         .body(body.into())
         .send()
         .await?;
@@ -111,7 +246,7 @@ async fn make_request(
 }
 
 async fn make_request_json<T>(
-    server_args: &ServerArgs,
+    server: &Server,
     method: reqwest::Method,
     path: &str,
     payload: &T,
@@ -121,11 +256,8 @@ where
 {
     let client = reqwest::Client::new();
     let response = client
-        .request(method, server_args.server.join(path)?)
-        .header(
-            AUTHORIZATION,
-            format!("Bearer {}", server_args.access_token),
-        )
+        .request(method, server.url.join(path)?)
+        .header(AUTHORIZATION, format!("Bearer {}", server.access_token))
         .json(payload)
         .send()
         .await?;
@@ -139,23 +271,38 @@ where
     Ok(response)
 }
 
-async fn account_new(server_args: &ServerArgs) -> Result<()> {
-    let response = make_request(server_args, Method::PUT, "a/", "").await?;
+fn config_show(config: &Config, server_args: &ServerArgs) -> Result<()> {
+    print!(
+        "{}",
+        toml::to_string_pretty(&config.effective(server_args)?)?
+    );
+
+    Ok(())
+}
+
+async fn account_new(server: &Server) -> Result<()> {
+    let response = make_request(server, Method::PUT, "a/", "").await?;
     println!("{}", response.text().await?);
 
     Ok(())
 }
 
-async fn metric_new(server_args: &ServerArgs) -> Result<()> {
-    let response = make_request(server_args, Method::PUT, "m/", "").await?;
+async fn metric_new(server: &Server) -> Result<()> {
+    let response = make_request(server, Method::PUT, "m/", "").await?;
     println!("{}", response.text().await?);
 
     Ok(())
 }
 
-async fn token_new(server_args: &ServerArgs, r#type: &AccessTokenType) -> Result<()> {
+/// Create a new metric, returning its id
+async fn metric_create(server: &Server) -> Result<String> {
+    let response = make_request(server, Method::PUT, "m/", "").await?;
+    Ok(response.json().await?)
+}
+
+async fn token_new(server: &Server, r#type: &AccessTokenType) -> Result<()> {
     let response = make_request_json(
-        server_args,
+        server,
         Method::PUT,
         "t/",
         &json! ({
@@ -169,58 +316,499 @@ async fn token_new(server_args: &ServerArgs, r#type: &AccessTokenType) -> Result
     Ok(())
 }
 
-async fn metric_get(server_args: &ServerArgs, metric_args: &MetricArgs) -> Result<()> {
-    let response = make_request(
-        server_args,
-        Method::GET,
-        &format!("m/{}/json", metric_args.metric),
-        "",
+async fn annotate(
+    server: &Server,
+    label: &str,
+    metric: Option<&str>,
+    url: Option<&Url>,
+    ts: Option<&str>,
+) -> Result<()> {
+    let response = make_request_json(
+        server,
+        Method::PUT,
+        "n/",
+        &json! ({
+            "label": label,
+            "metric-id": metric,
+            "url": url.map(Url::as_str),
+            "ts": ts,
+        }),
     )
     .await?;
+
+    println!("{}", response.text().await?);
+
+    Ok(())
+}
+
+async fn retention_set(server: &Server, path: &str, retention_args: &RetentionArgs) -> Result<()> {
+    let policy = retention_args
+        .raw_days
+        .filter(|_| !retention_args.clear)
+        .map(|raw_days| {
+            json! ({
+                "raw-days": raw_days,
+                "then": retention_args.then,
+            })
+        });
+    make_request_json(server, Method::PUT, path, &policy).await?;
+
+    Ok(())
+}
+
+async fn metric_get(server: &Server, metric: &str) -> Result<()> {
+    let response = make_request(server, Method::GET, &format!("m/{metric}/json"), "").await?;
     println!("{}", response.text().await?);
 
     Ok(())
 }
 
+/// Print the latest `last` data points of a metric, and with `follow` keep
+/// polling and printing them again whenever they change
+async fn metric_show(
+    server: &Server,
+    metric: &str,
+    last: u32,
+    follow: Option<Duration>,
+) -> Result<()> {
+    let redraw = follow.is_some() && std::io::stdout().is_terminal();
+    let mut shown = None;
+
+    loop {
+        let response = make_request(
+            server,
+            Method::GET,
+            &format!("m/{metric}/json?outcome=success&latest={last}"),
+            "",
+        )
+        .await?;
+        let data_points: Vec<show::DataPoint> = response.json().await?;
+
+        if shown.as_ref() != Some(&data_points) {
+            if redraw {
+                // Clear the screen and move to its top
+                print!("\x1b[2J\x1b[H");
+            }
+            print!("{}", show::render(metric, &data_points));
+            std::io::stdout().flush()?;
+            shown = Some(data_points);
+        }
+
+        let Some(interval) = follow else {
+            return Ok(());
+        };
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Failure to send a data point
+enum SendError {
+    /// Might go away by itself: server unreachable, restarting, overloaded...
+    Transient(color_eyre::Report),
+    /// Sending the same data point again will fail the same way
+    Permanent(color_eyre::Report),
+}
+
+impl SendError {
+    fn into_report(self) -> color_eyre::Report {
+        match self {
+            SendError::Transient(err) | SendError::Permanent(err) => err,
+        }
+    }
+}
+
+/// Post a data point
+///
+/// Its time is sent only for a data point `from_spool`, otherwise the server
+/// records it at the time it receives it, so a client with its clock off
+/// doesn't get it rejected.
+async fn post_data_point(
+    server: &Server,
+    data_point: &spool::DataPoint,
+    from_spool: bool,
+) -> Result<(), SendError> {
+    let url = server
+        .url
+        .join(&format!("m/{}", data_point.metric))
+        .map_err(|err| SendError::Permanent(err.into()))?;
+    let mut payload = json! ({
+        "value": data_point.value,
+        "metadata": data_point.metadata,
+        "outcome": data_point.outcome,
+    });
+    if from_spool {
+        payload["ts"] = data_point
+            .ts
+            .format(&Rfc3339)
+            .map_err(|err| SendError::Permanent(err.into()))?
+            .into();
+    }
+    let response = reqwest::Client::new()
+        .post(url)
+        .header(AUTHORIZATION, format!("Bearer {}", server.access_token))
+        .header("Idempotency-Key", &data_point.idempotency_key)
+        .json(&payload)
+        .send()
+        .await
+        .map_err(|err| SendError::Transient(err.into()))?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    // The server tells what was wrong in the `message` of a JSON body
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body["message"].as_str().map(ToOwned::to_owned))
+        .unwrap_or(body);
+    let err = format_err!(
+        "Http request failed: {status}: {}",
+        if message.is_empty() {
+            status.canonical_reason().unwrap_or("unknown response code")
+        } else {
+            &message
+        }
+    );
+    if status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+    {
+        Err(SendError::Transient(err))
+    } else {
+        Err(SendError::Permanent(err))
+    }
+}
+
+/// Initial delay before retrying to send a data point, doubled on each retry
+const RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+async fn post_data_point_with_retries(
+    server: &Server,
+    data_point: &spool::DataPoint,
+    retries: u32,
+) -> Result<(), SendError> {
+    let mut delay = RETRY_INITIAL_DELAY;
+    let mut retry = 0;
+    loop {
+        match post_data_point(server, data_point, false).await {
+            Err(SendError::Transient(err)) if retry < retries => {
+                retry += 1;
+                warn!(target: LOG_PERFIT, %err, retry, delay_millis = delay.as_millis(), "Failed to send data point, retrying");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            res => return res,
+        }
+    }
+}
+
 async fn send_data_point(
-    server_args: &ServerArgs,
-    metric_args: &MetricArgs,
+    server: &Server,
+    metric: &str,
     data_point_args: &opts::DataPointArgs,
+    send_args: &SendArgs,
     value: f32,
 ) -> Result<()> {
     info!(target: LOG_PERFIT,
-         server = %server_args.server,
-         metric = %metric_args.metric,
+         server = %server.url,
+         %metric,
          %value,
          metadata = %data_point_args.metadata.as_deref().unwrap_or(""),
+         outcome = ?data_point_args.outcome,
          "Sending data point");
-    make_request_json(
-        server_args,
-        Method::POST,
-        &format!("m/{}", metric_args.metric),
-        &json! ({
-            "value": value,
-            "metadata": data_point_args.metadata,
-        }),
-    )
-    .await?;
+    let data_point = spool::DataPoint {
+        server: server.url.to_string(),
+        metric: metric.to_owned(),
+        value,
+        metadata: data_point_args.metadata.clone(),
+        outcome: data_point_args.outcome,
+        ts: OffsetDateTime::now_utc(),
+        idempotency_key: data_point_args
+            .idempotency_key
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string()),
+    };
+
+    match post_data_point_with_retries(server, &data_point, send_args.retries).await {
+        Ok(()) => Ok(()),
+        Err(SendError::Transient(err)) if !send_args.no_spool => {
+            let path = spool::store(&send_args.spool_args.dir()?, &data_point)?;
+            warn!(target: LOG_PERFIT, path = %path.display(), "Data point kept for `perfit flush`");
+            Err(err)
+        }
+        Err(err) => Err(err.into_report()),
+    }
+}
+
+/// Send a data point for every benchmark, to its metric in `mapping`
+///
+/// Keeps going when some fail to send, and fails at the end.
+async fn ingest(
+    config: &Config,
+    server: &Server,
+    data_point_args: &opts::DataPointArgs,
+    send_args: &SendArgs,
+    benchmarks: &[ingest::Benchmark],
+    mapping: &mut ingest::Mapping,
+    create_metrics: bool,
+) -> Result<()> {
+    let (mut sent, mut skipped, mut failed) = (0, 0, 0);
+
+    for benchmark in benchmarks {
+        let metric = match mapping.get(&benchmark.name) {
+            Some(metric) => config.metric_id(metric),
+            None if create_metrics => {
+                let metric = metric_create(server).await?;
+                mapping.insert(&benchmark.name, &metric)?;
+                info!(target: LOG_PERFIT, benchmark = %benchmark.name, %metric, "Created metric");
+                metric
+            }
+            None => {
+                warn!(target: LOG_PERFIT, benchmark = %benchmark.name, "Benchmark missing from the mapping file, skipping");
+                skipped += 1;
+                continue;
+            }
+        };
+
+        // All of them come from one run, so need distinct keys. Based on the
+        // name, so that running it again after a failure skips the ones
+        // already sent, even if the tool lists them in another order.
+        let data_point_args = opts::DataPointArgs {
+            idempotency_key: data_point_args
+                .idempotency_key
+                .as_ref()
+                .map(|key| format!("{key}-{}", benchmark.name)),
+            ..data_point_args.clone()
+        };
+        match send_data_point(
+            server,
+            &metric,
+            &data_point_args,
+            send_args,
+            benchmark.value as f32,
+        )
+        .await
+        {
+            Ok(()) => sent += 1,
+            Err(err) => {
+                eprintln!("Failed to report benchmark {}: {}", benchmark.name, err);
+                failed += 1;
+            }
+        }
+    }
+
+    info!(target: LOG_PERFIT, sent, skipped, failed, "Ingested benchmarks");
+    if 0 < failed {
+        bail!("Failed to report {failed} benchmarks");
+    }
     Ok(())
 }
 
-fn run_and_time(cmd: Vec<std::ffi::OsString>) -> Result<(Duration, ExitStatus)> {
-    if cmd.is_empty() {
-        bail!("Empty command");
+/// Send the kept data points meant for the server, removing the sent ones
+///
+/// The ones the server rejects are moved away with [`spool::reject`], as
+/// sending them again would only fail again.
+async fn flush(server: &Server, spool_args: &SpoolArgs) -> Result<()> {
+    let url = server.url.to_string();
+    let dir = spool_args.dir()?;
+    let (mut sent, mut failed, mut rejected) = (0, 0, 0);
+
+    for (path, data_point) in spool::list(&dir)? {
+        if data_point.server != url {
+            continue;
+        }
+        match post_data_point(server, &data_point, true).await {
+            Ok(()) => {
+                std::fs::remove_file(&path)?;
+                sent += 1;
+            }
+            Err(SendError::Transient(err)) => {
+                eprintln!("Failed to send data point {}: {}", path.display(), err);
+                failed += 1;
+            }
+            Err(SendError::Permanent(err)) => {
+                let rejected_path = spool::reject(&dir, &path)?;
+                eprintln!(
+                    "Data point rejected, moved to {}: {}",
+                    rejected_path.display(),
+                    err
+                );
+                rejected += 1;
+            }
+        }
+    }
+
+    info!(target: LOG_PERFIT, sent, failed, rejected, "Flushed data points");
+    if 0 < failed || 0 < rejected {
+        bail!("Failed to send {failed} data points, {rejected} rejected");
     }
+    Ok(())
+}
+
+/// Durations (in seconds) of the measured runs of a command
+#[derive(Debug, Default)]
+struct Measurements {
+    durations: Vec<f64>,
+    /// Of the phases marked with `perfit mark`, in the runs they were marked in
+    phases: BTreeMap<String, Vec<f64>>,
+    /// Of the first run that was stopped
+    interruption: Option<run::Interruption>,
+}
 
-    // Note: showing only num_args, in case there was something sensitive there
-    info!(target: LOG_PERFIT, cmd = %&cmd[0].to_string_lossy(), num_args = %cmd.len() - 1, "Running command");
-    let start = std::time::Instant::now();
+/// Run `cmd` `--warmup` + `--repeat` times, returning the measurements of the
+/// measured runs, and the exit status to exit with
+///
+/// Stops at the first failed run, unless `keep_going`, and always when perfit
+/// gets a signal.
+async fn run_repeatedly(
+    cmd: &[std::ffi::OsString],
+    repeat_args: &RepeatArgs,
+    timeout: Option<Duration>,
+    keep_going: bool,
+) -> Result<(Measurements, ExitStatus)> {
+    let mut measurements = Measurements::default();
+    let mut first_failure = None;
+    let mut last = None;
+    let mut signals = run::Signals::new()?;
 
-    let mut command = std::process::Command::new(&cmd[0]);
-    command.args(&cmd[1..]);
-    let exit_status = command.spawn()?.wait()?;
+    for i in 0..repeat_args.warmup + repeat_args.repeat {
+        if let Some(signal) = signals.pending() {
+            info!(target: LOG_PERFIT, signal, "Got a signal between runs, stopping");
+            measurements
+                .interruption
+                .get_or_insert(run::Interruption::Signal(signal));
+            first_failure.get_or_insert(run::signal_exit_status(signal));
+            break;
+        }
+        let warmup = i < repeat_args.warmup;
+        let run::Run {
+            duration,
+            phases,
+            exit_status,
+            interruption,
+        } = run::run_and_time(cmd, timeout, &mut signals).await?;
+        let exit_code = run::exit_code(exit_status);
+
+        info!(target: LOG_PERFIT, duration_millis = duration.as_millis(), num_phases = phases.len(), exit_code, warmup, outcome = interruption.map(run::Interruption::as_str), "Command complete");
+        if !warmup {
+            measurements
+                .durations
+                .push(duration.as_micros() as f64 / 1_000_000.);
+            for (phase, duration) in phases {
+                measurements
+                    .phases
+                    .entry(phase)
+                    .or_default()
+                    .push(duration.as_micros() as f64 / 1_000_000.);
+            }
+        }
+        last = Some(exit_status);
+        if let Some(interruption) = interruption {
+            measurements.interruption.get_or_insert(interruption);
+            if let run::Interruption::Signal(_) = interruption {
+                first_failure.get_or_insert(exit_status);
+                break;
+            }
+        }
+        if !exit_status.success() {
+            first_failure.get_or_insert(exit_status);
+            if !keep_going {
+                break;
+            }
+        }
+    }
+
+    Ok((
+        measurements,
+        first_failure.or(last).expect("`--repeat` is at least 1"),
+    ))
+}
+
+fn aggregate(durations: &[f64], aggregate: Aggregate) -> Option<f64> {
+    match aggregate {
+        Aggregate::Median => stats::median(&stats::sorted(durations.iter().copied())),
+        Aggregate::Min => stats::sorted(durations.iter().copied()).first().copied(),
+        Aggregate::Mean => stats::mean(durations),
+    }
+}
+
+/// Data points to report for the measured runs: the aggregate of their
+/// durations, optionally each of them and their standard deviation, and the
+/// aggregates of the phases with a metric
+fn run_data_points(
+    config: &Config,
+    metric_args: &MetricArgs,
+    data_point_args: &opts::DataPointArgs,
+    repeat_args: &RepeatArgs,
+    phase_metrics: &[(String, String)],
+    Measurements {
+        durations, phases, ..
+    }: &Measurements,
+) -> Result<Vec<(String, opts::DataPointArgs, f32)>> {
+    let value = aggregate(durations, repeat_args.aggregate)
+        .ok_or_else(|| format_err!("No measured runs"))?;
+    let mut data_points = vec![(
+        config.metric(metric_args),
+        data_point_args.clone(),
+        value as f32,
+    )];
+
+    if let Some(samples_metric) = &repeat_args.samples_metric {
+        for (i, duration) in durations.iter().enumerate() {
+            // All go to the same metric, so they need distinct keys
+            let data_point_args = opts::DataPointArgs {
+                idempotency_key: data_point_args
+                    .idempotency_key
+                    .as_ref()
+                    .map(|key| format!("{key}-{i}")),
+                ..data_point_args.clone()
+            };
+            data_points.push((
+                config.metric_id(samples_metric),
+                data_point_args,
+                *duration as f32,
+            ));
+        }
+    }
+
+    if let Some(stddev_metric) = &repeat_args.stddev_metric {
+        let stddev = stats::stddev(durations).ok_or_else(|| format_err!("No measured runs"))?;
+        data_points.push((
+            config.metric_id(stddev_metric),
+            data_point_args.clone(),
+            stddev as f32,
+        ));
+    }
+
+    for (phase, phase_metric) in phase_metrics {
+        let Some(value) = phases
+            .get(phase)
+            .and_then(|durations| aggregate(durations, repeat_args.aggregate))
+        else {
+            warn!(target: LOG_PERFIT, %phase, "Phase was never marked");
+            continue;
+        };
+        let data_point_args = opts::DataPointArgs {
+            idempotency_key: data_point_args
+                .idempotency_key
+                .as_ref()
+                .map(|key| format!("{key}-{phase}")),
+            ..data_point_args.clone()
+        };
+        data_points.push((
+            config.metric_id(phase_metric),
+            data_point_args,
+            value as f32,
+        ));
+    }
+    for phase in phases.keys() {
+        if !phase_metrics.iter().any(|(p, _)| p == phase) {
+            info!(target: LOG_PERFIT, %phase, "No `--phase-metric` for phase, not reporting it");
+        }
+    }
 
-    Ok((start.elapsed(), exit_status))
+    Ok(data_points)
 }
 
 fn install_tracing() {
@@ -0,0 +1,85 @@
+//! Phases of a command run by `perfit run`, marked with `perfit mark`
+//!
+//! `perfit run` points the `PERFIT_MARK` env variable of the command at a
+//! file, and every `perfit mark` appends the phase and the time to it. As
+//! marks come from other processes, they use the wall clock.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write as _;
+use std::path::Path;
+use std::str::FromStr as _;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{bail, format_err, WrapErr as _};
+use color_eyre::Result;
+use tempfile::NamedTempFile;
+
+pub const MARK_ENV: &str = "PERFIT_MARK";
+
+fn nanos(ts: SystemTime) -> Result<u128> {
+    Ok(ts.duration_since(UNIX_EPOCH)?.as_nanos())
+}
+
+/// Record the start of `phase`, for the `perfit run` this runs under
+pub fn mark(phase: &str) -> Result<()> {
+    if phase.is_empty() || phase.contains(['\n', '\r']) {
+        bail!("Invalid phase name");
+    }
+    let Some(path) = std::env::var_os(MARK_ENV) else {
+        bail!("`{MARK_ENV}` not set; `perfit mark` is meant to run under `perfit run`");
+    };
+    let path = Path::new(&path);
+
+    // A single small append, so marks of concurrent processes don't mix
+    let line = format!("{} {phase}\n", nanos(SystemTime::now())?);
+    fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .wrap_err_with(|| format!("Opening {}", path.display()))?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Marks of a single run of the command
+pub struct Marks {
+    file: NamedTempFile,
+}
+
+impl Marks {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            file: NamedTempFile::new()?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Total durations of the phases, with the command ending at `end`
+    ///
+    /// A phase lasts until the next mark; one marked more than once adds up.
+    pub fn phases(&self, end: SystemTime) -> Result<BTreeMap<String, Duration>> {
+        let end = nanos(end)?;
+        let mut marks = vec![];
+        for line in fs::read_to_string(self.path())?.lines() {
+            let (nanos, phase) = line
+                .split_once(' ')
+                .ok_or_else(|| format_err!("Invalid mark: {line}"))?;
+            let nanos =
+                u128::from_str(nanos).map_err(|_| format_err!("Invalid mark time: {nanos}"))?;
+            marks.push((nanos, phase.to_owned()));
+        }
+        marks.sort();
+
+        let mut phases = BTreeMap::new();
+        for (i, (start, phase)) in marks.iter().enumerate() {
+            let end = marks.get(i + 1).map(|(next, _)| *next).unwrap_or(end);
+            let duration =
+                Duration::from_nanos(u64::try_from(end.saturating_sub(*start)).unwrap_or(u64::MAX));
+            *phases.entry(phase.clone()).or_default() += duration;
+        }
+        Ok(phases)
+    }
+}
@@ -0,0 +1,169 @@
+//! Metadata detected from the environment: git checkout and CI job
+//!
+//! Sent in the `key=value,...` format of data point metadata, with the keys
+//! perfitd links to on the charts: `commit`, `branch`, `repo` (web URL of the
+//! repository) and `job` (web URL of the CI job).
+
+use std::env;
+use std::process::Command;
+
+use tracing::warn;
+
+use crate::opts::DataPointArgs;
+use crate::LOG_PERFIT;
+
+/// Longest metadata perfitd accepts
+const MAX_LEN: usize = 256;
+
+#[derive(Debug, Default)]
+struct Detected {
+    commit: Option<String>,
+    branch: Option<String>,
+    repo: Option<String>,
+    job: Option<String>,
+}
+
+/// Value of a set and non-empty env variable
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Output of a git command, if it succeeded
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let out = String::from_utf8(output.stdout).ok()?;
+    let out = out.trim();
+    (!out.is_empty()).then(|| out.to_owned())
+}
+
+/// Web URL of a repository from its git remote URL, like
+/// `git@github.com:org/repo.git` or `https://github.com/org/repo.git`
+fn repo_web_url(remote: &str) -> Option<String> {
+    let remote = remote.strip_suffix(".git").unwrap_or(remote);
+    if remote.starts_with("https://") || remote.starts_with("http://") {
+        let mut url = url::Url::parse(remote).ok()?;
+        // Drop any credentials
+        url.set_username("").ok()?;
+        url.set_password(None).ok()?;
+        return Some(url.to_string());
+    }
+    let (host, path) = remote.strip_prefix("git@")?.split_once(':')?;
+    Some(format!("https://{host}/{path}"))
+}
+
+fn detect_github_actions() -> Option<Detected> {
+    var("GITHUB_ACTIONS").filter(|v| v == "true")?;
+    let repo = var("GITHUB_SERVER_URL")
+        .zip(var("GITHUB_REPOSITORY"))
+        .map(|(server, repository)| format!("{server}/{repository}"));
+    Some(Detected {
+        commit: var("GITHUB_SHA"),
+        // Set (to the source branch) only for pull requests
+        branch: var("GITHUB_HEAD_REF").or_else(|| var("GITHUB_REF_NAME")),
+        job: repo
+            .as_ref()
+            .zip(var("GITHUB_RUN_ID"))
+            .map(|(repo, run_id)| format!("{repo}/actions/runs/{run_id}")),
+        repo,
+    })
+}
+
+fn detect_gitlab() -> Option<Detected> {
+    var("GITLAB_CI").filter(|v| v == "true")?;
+    Some(Detected {
+        commit: var("CI_COMMIT_SHA"),
+        branch: var("CI_COMMIT_REF_NAME"),
+        repo: var("CI_PROJECT_URL"),
+        job: var("CI_JOB_URL"),
+    })
+}
+
+fn detect_buildkite() -> Option<Detected> {
+    var("BUILDKITE").filter(|v| v == "true")?;
+    Some(Detected {
+        // Can be `HEAD` until the checkout resolves it
+        commit: var("BUILDKITE_COMMIT").filter(|c| c != "HEAD"),
+        branch: var("BUILDKITE_BRANCH"),
+        repo: var("BUILDKITE_REPO").and_then(|remote| repo_web_url(&remote)),
+        job: var("BUILDKITE_BUILD_URL").map(|build| match var("BUILDKITE_JOB_ID") {
+            Some(job_id) => format!("{build}#{job_id}"),
+            None => build,
+        }),
+    })
+}
+
+fn detect() -> Detected {
+    let mut detected = detect_github_actions()
+        .or_else(detect_gitlab)
+        .or_else(detect_buildkite)
+        .unwrap_or_default();
+
+    if detected.commit.is_none() {
+        detected.commit = git(&["rev-parse", "HEAD"]);
+    }
+    if detected.branch.is_none() {
+        // `HEAD` when detached, as often in CI
+        detected.branch = git(&["rev-parse", "--abbrev-ref", "HEAD"]).filter(|b| b != "HEAD");
+    }
+    if detected.repo.is_none() {
+        detected.repo =
+            git(&["remote", "get-url", "origin"]).and_then(|remote| repo_web_url(&remote));
+    }
+    detected
+}
+
+impl DataPointArgs {
+    /// With the detected fields added to `--metadata`, unless
+    /// `--no-auto-metadata`
+    pub fn with_detected_metadata(self) -> Self {
+        if self.no_auto_metadata {
+            return self;
+        }
+        Self {
+            metadata: with_detected(self.metadata.as_deref()),
+            ..self
+        }
+    }
+}
+
+/// `metadata` with the detected fields added
+///
+/// Fields that would make it longer than perfitd accepts are left out.
+fn with_detected(metadata: Option<&str>) -> Option<String> {
+    let Detected {
+        commit,
+        branch,
+        repo,
+        job,
+    } = detect();
+
+    let mut fields: Vec<String> = metadata.into_iter().map(ToOwned::to_owned).collect();
+    let mut len = metadata.map(str::len).unwrap_or_default();
+    for (key, value) in [
+        ("commit", commit),
+        ("branch", branch),
+        ("job", job),
+        ("repo", repo),
+    ] {
+        let Some(value) = value else {
+            continue;
+        };
+        // Would break the `key=value,...` format
+        if value.contains(',') {
+            continue;
+        }
+        let field = format!("{key}={value}");
+        let field_len = field.len() + usize::from(!fields.is_empty());
+        if MAX_LEN < len + field_len {
+            warn!(target: LOG_PERFIT, %key, "Metadata too long, leaving out a detected field");
+            continue;
+        }
+        len += field_len;
+        fields.push(field);
+    }
+
+    (!fields.is_empty()).then(|| fields.join(","))
+}
@@ -1,7 +1,10 @@
 use std::ffi;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use perfitd::models::AccessTokenType;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
 #[derive(Parser, Clone, Debug)]
@@ -13,16 +16,22 @@ pub struct Opts {
 
 #[derive(Args, Clone, Debug)]
 pub struct ServerArgs {
+    /// Profile from the configuration files to take the server and access
+    /// token from
+    #[arg(long, env = "PERFIT_PROFILE")]
+    pub profile: Option<String>,
+
     #[arg(long, env = "PERFIT_SERVER")]
-    pub server: Url,
+    pub server: Option<Url>,
 
     #[arg(long, env = "PERFIT_ACCESS_TOKEN")]
-    pub access_token: String,
+    pub access_token: Option<String>,
 }
 
 #[derive(Args, Clone, Debug)]
 pub struct MetricArgs {
-    #[arg(long, env = "PERFIT_METRIC")]
+    /// Metric id, or its alias from the configuration files
+    #[arg(long, env = "PERFIT_METRIC", allow_hyphen_values = true)]
     pub metric: String,
 }
 
@@ -30,6 +39,114 @@ pub struct MetricArgs {
 pub struct DataPointArgs {
     #[arg(long, env = "PERFIT_METADATA")]
     pub metadata: Option<String>,
+
+    /// Key identifying the data point, so that sending it more than once
+    /// records it only once
+    ///
+    /// A random one is generated if not set, which already makes retries safe.
+    #[arg(long, env = "PERFIT_IDEMPOTENCY_KEY")]
+    pub idempotency_key: Option<String>,
+
+    /// Don't add the git commit, branch and CI job to the metadata
+    #[arg(long, env = "PERFIT_NO_AUTO_METADATA")]
+    pub no_auto_metadata: bool,
+
+    /// How the measured run ended
+    ///
+    /// Data points of runs that didn't succeed are drawn with a different
+    /// marker, and left out of aggregates like badges. `perfit run` sets it
+    /// from the outcome of the command.
+    #[arg(long, value_enum, default_value_t, env = "PERFIT_OUTCOME")]
+    pub outcome: Outcome,
+}
+
+/// How a measured run ended
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    #[default]
+    Success,
+    Failure,
+    Timeout,
+    Interrupted,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct SpoolArgs {
+    /// Where to keep data points that couldn't be sent
+    ///
+    /// Defaults to `perfit/spool` in the user's state directory.
+    #[arg(long, env = "PERFIT_SPOOL_DIR")]
+    pub spool_dir: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct SendArgs {
+    /// Retry sending a data point this many times, waiting twice as long
+    /// before each retry
+    #[arg(long, default_value = "4", env = "PERFIT_RETRIES")]
+    pub retries: u32,
+
+    /// Drop data points that couldn't be sent, instead of keeping them for
+    /// `perfit flush`
+    #[arg(long, env = "PERFIT_NO_SPOOL")]
+    pub no_spool: bool,
+
+    #[command(flatten)]
+    pub spool_args: SpoolArgs,
+}
+
+/// How to turn durations of repeated runs into a single data point
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Aggregate {
+    Median,
+    Min,
+    Mean,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct RepeatArgs {
+    /// Run the command this many times, and report the aggregate of the
+    /// durations
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    pub repeat: u32,
+
+    /// Run the command this many times first, without measuring
+    #[arg(long, default_value = "0")]
+    pub warmup: u32,
+
+    #[arg(long, value_enum, default_value = "median")]
+    pub aggregate: Aggregate,
+
+    /// Also report the duration of every run to this metric
+    #[arg(long, allow_hyphen_values = true)]
+    pub samples_metric: Option<String>,
+
+    /// Also report the standard deviation of the durations to this metric
+    #[arg(long, allow_hyphen_values = true)]
+    pub stddev_metric: Option<String>,
+}
+
+/// Output format of a benchmark tool
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum IngestFormat {
+    /// `target/criterion` directory of Criterion.rs
+    Criterion,
+    /// `hyperfine --export-json` file
+    Hyperfine,
+    /// Output of `cargo bench` with the built-in (libtest) harness
+    LibtestBench,
+    /// JUnit XML report; test case durations
+    Junit,
+}
+
+fn parse_phase_metric(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((phase, metric)) if !phase.is_empty() && !metric.is_empty() => {
+            Ok((phase.to_owned(), metric.to_owned()))
+        }
+        _ => Err("expected `<PHASE>=<METRIC>`".to_owned()),
+    }
 }
 
 #[derive(Subcommand, Clone, Debug)]
@@ -47,7 +164,15 @@ pub enum Command {
         #[command(flatten)]
         data_point_args: DataPointArgs,
 
+        #[command(flatten)]
+        send_args: SendArgs,
+
+        #[command(flatten)]
+        repeat_args: RepeatArgs,
+
         /// Send the data point even if the `cmd` failed
+        ///
+        /// With `--repeat`, also keep running the remaining repetitions.
         #[arg(long)]
         send_on_failure: bool,
 
@@ -55,10 +180,31 @@ pub enum Command {
         #[arg(long)]
         fail_on_send_failure: bool,
 
+        /// Stop the command (and every process it started) if it runs longer
+        /// than this, like `90s` or `30m`
+        ///
+        /// The run is still reported, with the `timeout` outcome.
+        /// It gets SIGTERM first, and SIGKILL if it's still running 5 seconds
+        /// later.
+        #[arg(long, value_parser = humantime_serde::re::humantime::parse_duration)]
+        timeout: Option<Duration>,
+
+        /// Report the duration of a phase marked with `perfit mark <PHASE>`
+        /// to a metric
+        ///
+        /// Can be used multiple times. Phases without one are not reported.
+        #[arg(long = "phase-metric", value_name = "PHASE=METRIC", value_parser = parse_phase_metric)]
+        phase_metrics: Vec<(String, String)>,
+
         #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
         cmd: Vec<ffi::OsString>,
     },
 
+    /// Mark the start of a phase of a command run by `perfit run`
+    ///
+    /// The phase lasts until the next mark, or the end of the command.
+    Mark { phase: String },
+
     /// Report a data point
     Post {
         #[command(flatten)]
@@ -70,9 +216,85 @@ pub enum Command {
         #[command(flatten)]
         data_point_args: DataPointArgs,
 
+        #[command(flatten)]
+        send_args: SendArgs,
+
         data_point: f32,
     },
 
+    /// Report results of benchmarks, from the output of a benchmark tool
+    ///
+    /// Every benchmark is sent to the metric it maps to in the `--mapping`
+    /// file, in a separate request. If some fail, the others are still
+    /// recorded; run it again with the same `--idempotency-key` to send only
+    /// the missing ones.
+    Ingest {
+        #[command(flatten)]
+        server_args: ServerArgs,
+
+        #[command(flatten)]
+        data_point_args: DataPointArgs,
+
+        #[command(flatten)]
+        send_args: SendArgs,
+
+        #[arg(long, value_enum)]
+        format: IngestFormat,
+
+        /// TOML file mapping benchmark names to metrics (ids or aliases)
+        #[arg(long, default_value = "perfit-benchmarks.toml", env = "PERFIT_MAPPING")]
+        mapping: PathBuf,
+
+        /// Create metrics for benchmarks missing from `--mapping`, and add
+        /// them to it
+        ///
+        /// Requires an admin access token.
+        #[arg(long)]
+        create_metrics: bool,
+
+        /// Output of the benchmark tool, as expected by `--format`
+        path: PathBuf,
+    },
+
+    /// Send data points that couldn't be sent before
+    ///
+    /// Only the ones that were meant for `--server` are sent; they keep their
+    /// original time.
+    Flush {
+        #[command(flatten)]
+        server_args: ServerArgs,
+
+        #[command(flatten)]
+        spool_args: SpoolArgs,
+    },
+
+    /// Mark an event (e.g. "upgraded compiler") on the charts of the account
+    Annotate {
+        #[command(flatten)]
+        server_args: ServerArgs,
+
+        /// Only mark charts of a given metric, instead of all metrics
+        #[arg(long, allow_hyphen_values = true)]
+        metric: Option<String>,
+
+        /// Link with more details
+        #[arg(long)]
+        url: Option<Url>,
+
+        /// Time of the event (RFC3339), defaults to now
+        #[arg(long)]
+        ts: Option<String>,
+
+        label: String,
+    },
+
+    /// Show the configuration in effect, after merging the configuration
+    /// files, env variables and flags
+    Config {
+        #[command(flatten)]
+        server_args: ServerArgs,
+    },
+
     #[command(subcommand)]
     Account(AccountCommand),
 
@@ -83,12 +305,48 @@ pub enum Command {
     Token(TokenCommand),
 }
 
+#[derive(Args, Clone, Debug)]
+pub struct RetentionArgs {
+    /// Keep all data points for this many days
+    #[arg(long, required_unless_present = "clear")]
+    pub raw_days: Option<u32>,
+
+    /// What to do with older data points
+    #[arg(long, default_value = "delete", value_parser = ["delete", "daily-aggregate"])]
+    pub then: String,
+
+    /// Remove the retention policy instead
+    #[arg(long, conflicts_with = "raw_days")]
+    pub clear: bool,
+}
+
 #[derive(Subcommand, Clone, Debug)]
 pub enum AccountCommand {
     New {
         #[command(flatten)]
         server_args: ServerArgs,
     },
+
+    /// Set the default data retention policy of all metrics of the account
+    Retention {
+        #[command(flatten)]
+        server_args: ServerArgs,
+
+        #[command(flatten)]
+        retention_args: RetentionArgs,
+    },
+
+    /// Export metrics of the account for Prometheus and Grafana
+    ///
+    /// This makes the list of metrics of the account public.
+    Export {
+        #[command(flatten)]
+        server_args: ServerArgs,
+
+        /// Stop exporting instead
+        #[arg(long)]
+        disable: bool,
+    },
 }
 
 #[derive(Subcommand, Clone, Debug)]
@@ -105,6 +363,41 @@ pub enum MetricCommand {
         #[command(flatten)]
         metric_args: MetricArgs,
     },
+
+    /// Show a sparkline and statistics of the latest (successful) data points
+    /// of a metric
+    Show {
+        #[command(flatten)]
+        server_args: ServerArgs,
+
+        #[command(flatten)]
+        metric_args: MetricArgs,
+
+        /// Number of the latest data points to show
+        #[arg(long, default_value = "60", value_parser = clap::value_parser!(u32).range(1..=1000))]
+        last: u32,
+
+        /// Keep checking for new data points, and show them as they come
+        #[arg(long)]
+        follow: bool,
+
+        /// How often to check for new data points with `--follow`, like `5s`
+        #[arg(long, default_value = "5s", value_parser = humantime_serde::re::humantime::parse_duration)]
+        interval: Duration,
+    },
+
+    /// Set the data retention policy of a metric, overriding the account
+    /// default
+    Retention {
+        #[command(flatten)]
+        server_args: ServerArgs,
+
+        #[command(flatten)]
+        metric_args: MetricArgs,
+
+        #[command(flatten)]
+        retention_args: RetentionArgs,
+    },
 }
 
 #[derive(Subcommand, Clone, Debug)]
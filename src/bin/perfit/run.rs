@@ -0,0 +1,269 @@
+//! Running the command of `perfit run`
+//!
+//! With `--timeout`, the command runs in its own process group, so that
+//! everything it starts can be stopped together. Otherwise it stays in the
+//! group of perfit, so it can still use the terminal. SIGINT and SIGTERM
+//! perfit gets are forwarded to the command.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+#[cfg(unix)]
+use std::os::unix::process::{CommandExt as _, ExitStatusExt as _};
+use std::process::ExitStatus;
+use std::time::{Duration, SystemTime};
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use futures::FutureExt as _;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::{marks, LOG_PERFIT};
+
+/// How long the command has to exit after SIGTERM on `--timeout`, before
+/// getting SIGKILL
+const TIMEOUT_KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Why the command was stopped before it finished by itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interruption {
+    Timeout,
+    /// perfit got (and forwarded) a signal
+    Signal(i32),
+}
+
+impl Interruption {
+    /// For logging
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Interruption::Timeout => "timeout",
+            Interruption::Signal(_) => "interrupted",
+        }
+    }
+}
+
+/// A single run of the command
+#[derive(Debug)]
+pub struct Run {
+    pub duration: Duration,
+    pub phases: BTreeMap<String, Duration>,
+    pub exit_status: ExitStatus,
+    pub interruption: Option<Interruption>,
+}
+
+/// Code to exit with after the command, with the shell convention of
+/// 128+signal for killed ones
+pub fn exit_code(exit_status: ExitStatus) -> i32 {
+    let code = exit_status.code();
+    #[cfg(unix)]
+    let code = code.or_else(|| exit_status.signal().map(|signal| 128 + signal));
+    code.unwrap_or(255)
+}
+
+/// Exit status to report for a `signal` perfit got while no command was
+/// running
+pub fn signal_exit_status(signal: i32) -> ExitStatus {
+    #[cfg(unix)]
+    {
+        ExitStatus::from_raw(signal)
+    }
+    #[cfg(not(unix))]
+    {
+        unreachable!("Signals are only handled on unix, got {signal}")
+    }
+}
+
+/// SIGINT and SIGTERM perfit gets
+///
+/// Created once for all the runs of the command, so that the signals
+/// received between runs aren't lost.
+pub struct Signals {
+    #[cfg(unix)]
+    sigint: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    sigterm: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    pub fn new() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(Self {
+                sigint: signal(SignalKind::interrupt())?,
+                sigterm: signal(SignalKind::terminate())?,
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            Ok(Self {})
+        }
+    }
+
+    async fn recv(&mut self) -> i32 {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                Some(()) = self.sigint.recv() => libc::SIGINT,
+                Some(()) = self.sigterm.recv() => libc::SIGTERM,
+                else => std::future::pending().await,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            std::future::pending().await
+        }
+    }
+
+    /// Signal received since the last run, if any
+    pub fn pending(&mut self) -> Option<i32> {
+        self.recv().now_or_never()
+    }
+}
+
+/// The running command, and how to signal it
+struct Child {
+    child: tokio::process::Child,
+    /// Set if the command runs in its own process group
+    #[cfg(unix)]
+    own_group: bool,
+}
+
+impl Child {
+    fn spawn(command: std::process::Command, own_group: bool) -> Result<Self> {
+        #[cfg(unix)]
+        let command = {
+            let mut command = command;
+            if own_group {
+                command.process_group(0);
+            }
+            command
+        };
+        #[cfg(not(unix))]
+        let _ = own_group;
+        Ok(Self {
+            child: tokio::process::Command::from(command).spawn()?,
+            #[cfg(unix)]
+            own_group,
+        })
+    }
+
+    /// Forward a `signal` perfit got
+    fn forward(&mut self, signal: i32) {
+        // SIGINT from the terminal already went to the whole foreground
+        // process group, including the command
+        #[cfg(unix)]
+        if !self.own_group && signal == libc::SIGINT {
+            return;
+        }
+        self.signal(signal);
+    }
+
+    /// Send `signal` to the command, and everything it started if it has its
+    /// own process group
+    #[cfg(unix)]
+    fn signal(&mut self, signal: i32) {
+        let Some(pid) = self.child.id() else {
+            // Already waited for
+            return;
+        };
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return;
+        };
+        // SAFETY: no memory is involved
+        let res = unsafe {
+            if self.own_group {
+                libc::killpg(pid, signal)
+            } else {
+                libc::kill(pid, signal)
+            }
+        };
+        if res != 0 {
+            // Most likely all of them are already gone
+            let err = std::io::Error::last_os_error();
+            warn!(target: LOG_PERFIT, %err, signal, "Failed to signal the command");
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn signal(&mut self, _signal: i32) {
+        self.kill();
+    }
+
+    /// Stop the command on `--timeout`
+    fn terminate(&mut self) {
+        #[cfg(unix)]
+        self.signal(libc::SIGTERM);
+        #[cfg(not(unix))]
+        self.kill();
+    }
+
+    fn kill(&mut self) {
+        #[cfg(unix)]
+        self.signal(libc::SIGKILL);
+        #[cfg(not(unix))]
+        if let Err(err) = self.child.start_kill() {
+            warn!(target: LOG_PERFIT, %err, "Failed to kill the command");
+        }
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+pub async fn run_and_time(
+    cmd: &[OsString],
+    timeout: Option<Duration>,
+    signals: &mut Signals,
+) -> Result<Run> {
+    if cmd.is_empty() {
+        bail!("Empty command");
+    }
+
+    // Note: showing only num_args, in case there was something sensitive there
+    info!(target: LOG_PERFIT, cmd = %&cmd[0].to_string_lossy(), num_args = %cmd.len() - 1, "Running command");
+    let marks = marks::Marks::new()?;
+    let start = std::time::Instant::now();
+
+    let mut command = std::process::Command::new(&cmd[0]);
+    command.args(&cmd[1..]).env(marks::MARK_ENV, marks.path());
+    let mut child = Child::spawn(command, timeout.is_some())?;
+
+    let mut timeout_deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut kill_deadline = None;
+    let mut interruption = None;
+    let exit_status = loop {
+        tokio::select! {
+            res = child.child.wait() => break res?,
+            signal = signals.recv() => {
+                interruption.get_or_insert(Interruption::Signal(signal));
+                child.forward(signal);
+            }
+            _ = sleep_until(timeout_deadline) => {
+                warn!(target: LOG_PERFIT, timeout_secs = timeout.unwrap_or_default().as_secs(), "Command timed out, stopping it");
+                interruption.get_or_insert(Interruption::Timeout);
+                child.terminate();
+                timeout_deadline = None;
+                kill_deadline = Some(Instant::now() + TIMEOUT_KILL_GRACE);
+            }
+            _ = sleep_until(kill_deadline) => {
+                warn!(target: LOG_PERFIT, "Command didn't stop, killing it");
+                child.kill();
+                kill_deadline = None;
+            }
+        }
+    };
+    let end = SystemTime::now();
+    let duration = start.elapsed();
+
+    Ok(Run {
+        duration,
+        phases: marks.phases(end)?,
+        exit_status,
+        interruption,
+    })
+}
@@ -0,0 +1,66 @@
+//! Terminal view of the latest data points of a metric, for
+//! `perfit metric show`
+
+use std::fmt::Write as _;
+
+use perfitd::stats;
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+/// A data point, as returned by `/m/<metric>/json`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct DataPoint {
+    /// Unix timestamp, in seconds
+    t: i64,
+    v: f32,
+}
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// A bar for every value, scaled between the smallest and the largest one
+pub fn sparkline(values: &[f32]) -> String {
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+
+    values
+        .iter()
+        .map(|&v| {
+            if max <= min {
+                // All the same, nothing to compare
+                return BARS[BARS.len() / 2];
+            }
+            let i = ((v - min) / (max - min) * (BARS.len() - 1) as f32).round() as usize;
+            BARS[i.min(BARS.len() - 1)]
+        })
+        .collect()
+}
+
+/// Sparkline of the `data_points` (oldest first), with their statistics
+pub fn render(metric: &str, data_points: &[DataPoint]) -> String {
+    const TIME_FORMAT: &[time::format_description::FormatItem<'static>] =
+        time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second] UTC");
+
+    let Some(latest) = data_points.last() else {
+        return format!("{metric}: no data points\n");
+    };
+    let values: Vec<_> = data_points.iter().map(|d| d.v).collect();
+    let sorted = stats::sorted(values.iter().map(|&v| f64::from(v)));
+    let latest_time = OffsetDateTime::from_unix_timestamp(latest.t)
+        .ok()
+        .and_then(|t| t.format(&TIME_FORMAT).ok())
+        .unwrap_or_default();
+
+    let mut out = String::new();
+    // Writing to a `String` can't fail
+    let _ = writeln!(out, "{metric}: {} data points", values.len());
+    let _ = writeln!(out, "{}", sparkline(&values));
+    let _ = writeln!(
+        out,
+        "min {}  median {}  max {}  latest {} ({latest_time})",
+        sorted[0] as f32,
+        stats::median(&sorted).unwrap_or_default() as f32,
+        sorted[sorted.len() - 1] as f32,
+        latest.v,
+    );
+    out
+}
@@ -0,0 +1,99 @@
+//! Data points that couldn't be sent, kept on disk until `perfit flush`
+//!
+//! Every data point is a separate JSON file, so storing and removing them
+//! doesn't need any locking. Each keeps its idempotency key, so sending one
+//! again is harmless. The ones the server rejected are moved to the
+//! `rejected` subdirectory, to be looked at and removed by hand.
+
+use std::fs;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{format_err, WrapErr as _};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::opts::{Outcome, SpoolArgs};
+
+/// A data point, as sent to the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct DataPoint {
+    /// Url of the server it is meant for
+    pub server: String,
+    pub metric: String,
+    pub value: f32,
+    pub metadata: Option<String>,
+    #[serde(default)]
+    pub outcome: Outcome,
+    /// When it was first sent, to record it at the right time even if it
+    /// gets sent much later
+    #[serde(with = "time::serde::rfc3339")]
+    pub ts: OffsetDateTime,
+    /// Lets the server recognize the same data point sent more than once
+    pub idempotency_key: String,
+}
+
+impl SpoolArgs {
+    pub fn dir(&self) -> Result<PathBuf> {
+        if let Some(dir) = &self.spool_dir {
+            return Ok(dir.clone());
+        }
+        Ok(dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .ok_or_else(|| format_err!("Can't find a directory to keep unsent data points in"))?
+            .join("perfit")
+            .join("spool"))
+    }
+}
+
+/// Keep a data point, returning the path of its file
+pub fn store(dir: &Path, data_point: &DataPoint) -> Result<PathBuf> {
+    fs::create_dir_all(dir).wrap_err_with(|| format!("Creating {}", dir.display()))?;
+
+    // Written under a temporary name first, so `list` never sees a partial file
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut tmp, data_point)?;
+    tmp.flush()?;
+    tmp.as_file().sync_all()?;
+
+    let path = dir.join(format!("{}.json", Uuid::new_v4()));
+    tmp.persist(&path)?;
+    Ok(path)
+}
+
+/// Move a kept data point the server rejected out of the way, returning its
+/// new path
+pub fn reject(dir: &Path, path: &Path) -> Result<PathBuf> {
+    let rejected_dir = dir.join("rejected");
+    fs::create_dir_all(&rejected_dir)
+        .wrap_err_with(|| format!("Creating {}", rejected_dir.display()))?;
+
+    let rejected_path = rejected_dir.join(
+        path.file_name()
+            .ok_or_else(|| format_err!("Not a file: {}", path.display()))?,
+    );
+    fs::rename(path, &rejected_path)?;
+    Ok(rejected_path)
+}
+
+/// All the kept data points, oldest first
+pub fn list(dir: &Path) -> Result<Vec<(PathBuf, DataPoint)>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut data_points = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let data_point = serde_json::from_slice(&fs::read(&path)?)
+                .wrap_err_with(|| format!("Reading {}", path.display()))?;
+            data_points.push((path, data_point));
+        }
+    }
+    data_points.sort_by_key(|(_, data_point): &(PathBuf, DataPoint)| data_point.ts);
+    Ok(data_points)
+}
@@ -1,53 +1,46 @@
 use std::borrow::Cow;
+use std::fmt;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use bincode::{Decode, Encode};
 use color_eyre::eyre::bail;
 use color_eyre::Result;
-use redb_bincode::{ReadTransaction, TableDefinition, WriteTransaction};
+use prometheus::HistogramVec;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info, instrument};
 use uuid::Uuid;
 
-use crate::models::access_token::AccessToken;
+use self::executor::Executor;
+pub use self::executor::IngestBatching;
+pub use self::mem_storage::MemStorage;
+pub use self::redb_storage::RedbStorage;
+pub use self::storage::{DataPointAppend, Scan, Storage};
 use crate::models::ts::Ts;
-use crate::models::{AccessTokenType, AccountId, MetricId, MetricInternalId};
+use crate::models::{AccessTokenType, AccountId, AnnotationId, MetricId, MetricInternalId};
 use crate::routes::error::UserRequestError;
 
-pub const TABLE_DB_VER: TableDefinition<'_, (), u64> = TableDefinition::new("db-ver");
+mod executor;
+mod mem_storage;
+mod migrations;
+pub mod redb_storage;
+mod storage;
 
-pub const TABLE_ACCOUNTS: TableDefinition<'_, AccountId, AccountRecord> =
-    TableDefinition::new("accounts");
-
-pub const TABLE_ACCESS_TOKENS: TableDefinition<'_, AccessToken, AccessTokenRecord> =
-    TableDefinition::new("access_tokens");
-
-pub const TABLE_ACCESS_TOKENS_REV: TableDefinition<'_, (AccountId, AccessToken), ()> =
-    TableDefinition::new("access_tokens_rev");
-
-pub const TABLE_METRICS: TableDefinition<'_, MetricId, MetricRecord> =
-    TableDefinition::new("metrics");
-
-pub const TABLE_METRICS_REV: TableDefinition<'_, MetricInternalId, MetricId> =
-    TableDefinition::new("metrics_rev");
-
-pub const TABLE_DATA_POINTS: TableDefinition<'_, DataPoint, DataPointRecord> =
-    TableDefinition::new("data_points");
-
-#[derive(Encode, Decode, Clone, Copy)]
+#[derive(
+    Debug, Encode, Decode, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
 pub struct DataPoint {
     pub metric_internal_id: MetricInternalId,
     pub ts: Ts,
     pub idx: u64,
 }
 
-#[derive(Debug, Encode, Decode, Clone, Copy)]
+#[derive(Debug, Encode, Decode, Clone, Copy, Serialize, Deserialize)]
 pub struct AccountRecord {
     pub created: Ts,
 }
 
-#[derive(Debug, Encode, Decode, Clone, Copy)]
+#[derive(Debug, Encode, Decode, Clone, Copy, Serialize, Deserialize)]
 pub struct AccessTokenRecord {
     pub created: Ts,
     pub account_id: AccountId,
@@ -77,7 +70,43 @@ impl AccessTokenRecord {
         Err(UserRequestError::Unauthorized.into())
     }
 
-    pub fn ensure_can_create_accounts(self) -> Result<()> {
+    /// Any token of a regular account, to read what only the account can see
+    pub fn ensure_can_list_annotations(&self) -> Result<()> {
+        if self.account_id == ROOT_ACCOUNT_ID {
+            return Err(UserRequestError::RootAccountCantBeUsed.into());
+        }
+        Ok(())
+    }
+
+    /// Admin token of a regular account, for changing the account's settings
+    pub fn ensure_account_admin(&self) -> Result<()> {
+        if self.account_id == ROOT_ACCOUNT_ID {
+            return Err(UserRequestError::RootAccountCantBeUsed.into());
+        }
+
+        if matches!(self.r#type, AccessTokenType::Admin) {
+            return Ok(());
+        }
+
+        Err(UserRequestError::Unauthorized.into())
+    }
+
+    /// Posting data points is open to anyone over HTTP, but the line protocol
+    /// identifies the account with an access token instead
+    pub fn ensure_can_post(&self, metric_account_id: AccountId) -> Result<()> {
+        if self.account_id == ROOT_ACCOUNT_ID {
+            return Err(UserRequestError::RootAccountCantBeUsed.into());
+        }
+
+        if self.account_id == metric_account_id {
+            return Ok(());
+        }
+
+        Err(UserRequestError::Unauthorized.into())
+    }
+
+    /// Root token, for server-wide operations
+    pub fn ensure_root(self) -> Result<()> {
         if matches!(self.r#type, AccessTokenType::Root) {
             return Ok(());
         }
@@ -85,7 +114,14 @@ impl AccessTokenRecord {
     }
 }
 
-#[derive(Debug, Encode, Decode, Clone, Copy)]
+/// Presence means the latest values of the metrics of the account are
+/// exported for Prometheus and Grafana
+#[derive(Debug, Encode, Decode, Clone, Copy, Serialize, Deserialize)]
+pub struct AccountExportRecord {
+    pub created: Ts,
+}
+
+#[derive(Debug, Encode, Decode, Clone, Copy, Serialize, Deserialize)]
 pub struct MetricRecord {
     pub created: Ts,
     pub account_id: AccountId,
@@ -101,6 +137,12 @@ impl DataPointValue {
     }
 }
 
+impl From<f32> for DataPointValue {
+    fn from(value: f32) -> Self {
+        Self(value)
+    }
+}
+
 /// Metadata attached to a [`DataPoint`]
 #[derive(Encode, Decode, Serialize, Debug, Clone, Default)]
 pub struct DataPointMetadata(String);
@@ -118,6 +160,35 @@ impl DataPointMetadata {
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
+
+    /// Value of a field, for metadata in the `key=value,...` format (like the
+    /// one `perfit` sends)
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .split(',')
+            .find_map(|field| field.strip_prefix(key)?.strip_prefix('='))
+    }
+
+    /// Web page of the commit, from the `repo` and `commit` fields
+    pub fn commit_url(&self) -> Option<String> {
+        let repo = web_url(self.get("repo")?)?;
+        Some(format!(
+            "{}/commit/{}",
+            repo.trim_end_matches('/'),
+            self.get("commit")?
+        ))
+    }
+
+    /// Web page of the CI job, from the `job` field
+    pub fn job_url(&self) -> Option<&str> {
+        web_url(self.get("job")?)
+    }
+}
+
+/// `url`, if it's a web one, so nothing else (like `javascript:`) gets linked
+fn web_url(url: &str) -> Option<&str> {
+    let parsed = url::Url::parse(url).ok()?;
+    matches!(parsed.scheme(), "http" | "https").then_some(url)
 }
 
 impl FromStr for DataPointMetadata {
@@ -142,97 +213,287 @@ impl<'de> Deserialize<'de> for DataPointMetadata {
     }
 }
 
+/// How the run measured by a [`DataPoint`] ended
+///
+/// Only successful data points are included in aggregates (badges, exported
+/// latest values, daily aggregates, ...) by default.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DataPointOutcome {
+    #[default]
+    Success,
+    Failure,
+    Timeout,
+    Interrupted,
+}
+
+impl DataPointOutcome {
+    pub fn is_success(&self) -> bool {
+        *self == Self::Success
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure => "failure",
+            Self::Timeout => "timeout",
+            Self::Interrupted => "interrupted",
+        }
+    }
+}
+
 #[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
 pub struct DataPointRecord {
     pub value: DataPointValue,
     pub metadata: DataPointMetadata,
+    #[serde(default, skip_serializing_if = "DataPointOutcome::is_success")]
+    pub outcome: DataPointOutcome,
 }
 
-#[derive(Debug)]
-pub struct Database(redb_bincode::Database);
+/// Client-chosen key identifying the same data point posted more than once
+/// (e.g. when retrying after a timeout)
+#[derive(Encode, Decode, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IdempotencyKey(String);
 
-impl Database {
-    const DB_VER: u64 = 0;
+impl IdempotencyKey {
+    pub const MAX_LEN: usize = 128;
 
-    pub async fn init(self) -> Result<Self> {
-        self.write_with(|dbtx| {
-            dbtx.open_table(&TABLE_ACCOUNTS)?;
-            dbtx.open_table(&TABLE_ACCESS_TOKENS)?;
-            dbtx.open_table(&TABLE_ACCESS_TOKENS_REV)?;
-            dbtx.open_table(&TABLE_METRICS)?;
-            dbtx.open_table(&TABLE_METRICS_REV)?;
-            dbtx.open_table(&TABLE_DATA_POINTS)?;
+    pub fn try_new<'a>(s: impl Into<Cow<'a, str>>) -> Result<Self> {
+        let s = s.into();
+        if s.is_empty() || Self::MAX_LEN < s.len() {
+            return Err(UserRequestError::InvalidIdempotencyKey.into());
+        }
+        Ok(Self(s.into_owned()))
+    }
+}
 
-            Self::handle_db_ver_migrations(dbtx)?;
+impl<'de> Deserialize<'de> for IdempotencyKey {
+    fn deserialize<D>(deserializer: D) -> std::prelude::v1::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Self::try_new(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
 
-            Ok(())
-        })
-        .await?;
+/// Data point posted with an [`IdempotencyKey`]
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct IdempotencyRecord {
+    pub created: Ts,
+    pub data_point: DataPoint,
+}
 
-        Ok(self)
+/// An event marker (e.g. "upgraded compiler") on the timeline of an account
+#[derive(
+    Debug, Encode, Decode, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct Annotation {
+    pub account_id: AccountId,
+    pub ts: Ts,
+    pub annotation_id: AnnotationId,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+pub struct AnnotationRecord {
+    pub label: AnnotationLabel,
+    pub url: Option<AnnotationUrl>,
+    /// Metric the annotation applies to, or all metrics of the account if
+    /// `None`
+    pub metric_id: Option<MetricId>,
+}
+
+impl AnnotationRecord {
+    pub fn applies_to(&self, metric_id: MetricId) -> bool {
+        self.metric_id.is_none_or(|m| m == metric_id)
     }
-    fn handle_db_ver_migrations(dbtx: &WriteTransaction) -> Result<(), color_eyre::eyre::Error> {
-        let mut table_db_ver = dbtx.open_table(&TABLE_DB_VER)?;
+}
 
-        let Some(cur_db_ver) = table_db_ver.first()?.map(|g| g.1.value()) else {
-            info!("Initializing empty database");
-            table_db_ver.insert(&(), &Self::DB_VER)?;
+#[derive(Encode, Decode, Serialize, Debug, Clone)]
+pub struct AnnotationLabel(String);
 
-            return Ok(());
-        };
+impl AnnotationLabel {
+    pub const MAX_LEN: usize = 128;
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
 
-        debug!(db_ver = cur_db_ver, "Checking db version");
-        if Self::DB_VER < cur_db_ver {
-            bail!(
-                "Db version {cur_db_ver} higher than code version {}",
-                Self::DB_VER
-            );
+impl<'de> Deserialize<'de> for AnnotationLabel {
+    fn deserialize<D>(deserializer: D) -> std::prelude::v1::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+
+        if s.is_empty() {
+            return Err(serde::de::Error::custom("Label empty"));
         }
+        if Self::MAX_LEN < s.len() {
+            return Err(serde::de::Error::custom("Label too long"));
+        }
+        Ok(Self(s))
+    }
+}
 
-        // migration code will go here
+/// Link attached to an [`AnnotationRecord`]
+#[derive(Debug, Clone)]
+pub struct AnnotationUrl(url::Url);
 
-        Ok(())
+impl AnnotationUrl {
+    pub const MAX_LEN: usize = 512;
+}
+
+impl fmt::Display for AnnotationUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
     }
 }
 
-impl From<redb_bincode::Database> for Database {
-    fn from(db: redb_bincode::Database) -> Self {
-        Self(db)
+impl FromStr for AnnotationUrl {
+    type Err = color_eyre::eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if Self::MAX_LEN < s.len() {
+            bail!("Url too long");
+        }
+        let url = url::Url::parse(s)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("Url must be http(s)");
+        }
+        Ok(Self(url))
     }
 }
 
-impl Database {
-    pub async fn write_with<T>(
+impl Serialize for AnnotationUrl {
+    fn serialize<S>(&self, serializer: S) -> std::prelude::v1::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for AnnotationUrl {
+    fn deserialize<D>(deserializer: D) -> std::prelude::v1::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Encode for AnnotationUrl {
+    fn encode<E: bincode::enc::Encoder>(
         &self,
-        f: impl FnOnce(&'_ WriteTransaction) -> Result<T>,
-    ) -> Result<T> {
-        tokio::task::block_in_place(|| {
-            let mut dbtx = self.0.begin_write()?;
+        encoder: &mut E,
+    ) -> std::prelude::v1::Result<(), bincode::error::EncodeError> {
+        Encode::encode(self.0.as_str(), encoder)
+    }
+}
+
+impl Decode for AnnotationUrl {
+    fn decode<D: bincode::de::Decoder>(
+        decoder: &mut D,
+    ) -> std::prelude::v1::Result<Self, bincode::error::DecodeError> {
+        let s: String = Decode::decode(decoder)?;
+        url::Url::parse(&s)
+            .map(Self)
+            .map_err(|e| bincode::error::DecodeError::OtherString(e.to_string()))
+    }
+}
+
+bincode::impl_borrow_decode!(AnnotationUrl);
+
+/// How long to keep data points of a metric
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetentionPolicy {
+    /// Keep all data points for this many days
+    pub raw_days: u32,
+    /// What to do with data points older than `raw_days`
+    pub then: RetentionAction,
+}
 
-            let res = f(&mut dbtx)?;
+impl RetentionPolicy {
+    /// Data points before the returned time are subject to [`Self::then`]
+    ///
+    /// Always at a UTC day boundary, so whole days are processed at once.
+    pub fn cutoff(self, now: Ts) -> Ts {
+        now.saturating_sub_days(self.raw_days).day_start()
+    }
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetentionAction {
+    /// Delete the data points
+    Delete,
+    /// Replace all data points of each day with a single one, with the
+    /// median value of the successful ones
+    DailyAggregate,
+}
 
-            dbtx.commit()?;
+/// Handle to the [`Storage`] backend, for use from async code
+#[derive(Debug, Clone)]
+pub struct Database(Executor);
 
-            Ok(res)
-        })
+impl Database {
+    /// Open (or create) a redb database at `path`
+    pub async fn open(
+        path: impl Into<PathBuf>,
+        ingest_batching: Option<IngestBatching>,
+    ) -> Result<Self> {
+        Self::new(Arc::new(RedbStorage::open(path).await?), ingest_batching)
+    }
+
+    /// Non-persistent database, mostly for tests
+    pub fn new_in_memory(ingest_batching: Option<IngestBatching>) -> Result<Self> {
+        Self::new(Arc::new(MemStorage::default()), ingest_batching)
     }
 
-    pub async fn read_with<T>(
+    fn new(storage: Arc<dyn Storage>, ingest_batching: Option<IngestBatching>) -> Result<Self> {
+        Ok(Self(Executor::new(storage, ingest_batching)?))
+    }
+
+    /// Run `f` on the writer thread
+    ///
+    /// All the writes are run one after another, so nothing else can change
+    /// the data while `f` runs, even between separate [`Storage`] calls (each
+    /// its own transaction). That makes it safe to write based on what `f`
+    /// read before.
+    pub async fn write_with<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn Storage) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        self.0.write(f).await
+    }
+
+    /// Run `f` on one of the reader threads
+    pub async fn read_with<T: Send + 'static>(
         &self,
-        f: impl FnOnce(&'_ ReadTransaction) -> Result<T>,
+        f: impl FnOnce(&dyn Storage) -> Result<T> + Send + 'static,
     ) -> Result<T> {
-        tokio::task::block_in_place(|| {
-            let mut dbtx = self.0.begin_read()?;
+        self.0.read(f).await
+    }
+
+    /// Append a data point to a metric
+    ///
+    /// Appends waiting for the writer thread get committed together (see
+    /// [`IngestBatching`]), and this returns only after the commit.
+    pub async fn data_point_append(&self, append: DataPointAppend) -> Result<DataPoint> {
+        self.0.append(append).await
+    }
 
-            f(&mut dbtx)
-        })
+    /// Durations of the transactions run so far, by kind
+    pub fn txn_durations(&self) -> HistogramVec {
+        self.0.txn_durations()
     }
 
-    #[instrument(skip_all)]
-    pub async fn open(path: impl Into<PathBuf>) -> Result<Database> {
-        let path = path.into();
-        let create =
-            tokio::task::spawn_blocking(move || redb_bincode::Database::create(path)).await??;
-        Self::from(create).init().await
+    /// See [`Storage::backup_to`]
+    pub async fn backup_to(&self, path: PathBuf) -> Result<()> {
+        self.read_with(move |db| db.backup_to(&path)).await
     }
 }
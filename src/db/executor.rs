@@ -0,0 +1,293 @@
+//! Dedicated threads running [`Storage`] operations
+//!
+//! Storage operations are blocking, and running them on Tokio worker threads
+//! (even with `block_in_place`) stalls other requests for the whole
+//! transaction. Instead, all the writes go through a single writer thread,
+//! which commits data points queued up in the meantime together (or waits for
+//! more of them, with [`IngestBatching`]), and reads are spread over a pool of
+//! reader threads.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::eyre::format_err;
+use color_eyre::Result;
+use prometheus::{Histogram, HistogramOpts, HistogramVec};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+use super::{DataPoint, DataPointAppend, Storage};
+
+/// Max number of writes waiting for the writer thread, before callers have to
+/// wait to even queue theirs
+const WRITE_QUEUE_LEN: usize = 1024;
+const READ_QUEUE_LEN: usize = 1024;
+/// Max number of queued up data points committed together, without
+/// [`IngestBatching`]
+const MAX_WRITE_GROUP: usize = 256;
+
+/// Commit posted data points in batches, instead of as soon as possible
+///
+/// Every commit of redb is durable, so it's expensive, and many points
+/// committed at once are much cheaper than separately. Points are still
+/// acknowledged only after their batch is committed.
+#[derive(Debug, Clone, Copy)]
+pub struct IngestBatching {
+    /// Max time the first point of a batch waits for the commit
+    pub max_delay: Duration,
+    /// Commit sooner, once the batch has that many points
+    pub max_points: usize,
+}
+
+type Job = Box<dyn FnOnce(&dyn Storage) + Send>;
+
+struct Append {
+    append: DataPointAppend,
+    reply: oneshot::Sender<Result<DataPoint>>,
+}
+
+enum WriteJob {
+    /// Data point append, that can be committed together with other ones
+    Append(Append),
+    Other(Job),
+}
+
+#[derive(Debug, Clone)]
+pub struct Executor {
+    writer: mpsc::Sender<WriteJob>,
+    reader: mpsc::Sender<Job>,
+    txn_durations: HistogramVec,
+}
+
+impl Executor {
+    /// Start the threads; they exit when the last clone of the executor is
+    /// dropped
+    pub fn new(storage: Arc<dyn Storage>, ingest_batching: Option<IngestBatching>) -> Result<Self> {
+        let (writer, writer_rx) = mpsc::channel(WRITE_QUEUE_LEN);
+        let (reader, reader_rx) = mpsc::channel(READ_QUEUE_LEN);
+        let txn_durations = HistogramVec::new(
+            HistogramOpts::new(
+                "perfitd_db_txn_duration_seconds",
+                "Duration of database transactions",
+            ),
+            &["kind"],
+        )?;
+
+        thread::Builder::new().name("db-writer".into()).spawn({
+            let storage = storage.clone();
+            let durations = WriterTxnDurations {
+                write: txn_durations.with_label_values(&["write"]),
+                append: txn_durations.with_label_values(&["append"]),
+            };
+            move || run_writer(&*storage, writer_rx, ingest_batching, &durations)
+        })?;
+
+        let reader_rx = Arc::new(Mutex::new(reader_rx));
+        let num_readers = thread::available_parallelism().map_or(4, usize::from);
+        for i in 0..num_readers {
+            thread::Builder::new()
+                .name(format!("db-reader-{i}"))
+                .spawn({
+                    let storage = storage.clone();
+                    let reader_rx = reader_rx.clone();
+                    let duration = txn_durations.with_label_values(&["read"]);
+                    move || run_reader(&*storage, &reader_rx, &duration)
+                })?;
+        }
+
+        Ok(Self {
+            writer,
+            reader,
+            txn_durations,
+        })
+    }
+
+    pub fn txn_durations(&self) -> HistogramVec {
+        self.txn_durations.clone()
+    }
+
+    pub async fn write<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn Storage) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.writer
+            .send(WriteJob::Other(Box::new(move |db| {
+                let _ = reply.send(f(db));
+            })))
+            .await
+            .map_err(|_| executor_gone())?;
+        reply_rx.await.map_err(|_| executor_gone())?
+    }
+
+    pub async fn read<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn Storage) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.reader
+            .send(Box::new(move |db| {
+                let _ = reply.send(f(db));
+            }))
+            .await
+            .map_err(|_| executor_gone())?;
+        reply_rx.await.map_err(|_| executor_gone())?
+    }
+
+    /// See [`Storage::data_points_append`]
+    pub async fn append(&self, append: DataPointAppend) -> Result<DataPoint> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.writer
+            .send(WriteJob::Append(Append { append, reply }))
+            .await
+            .map_err(|_| executor_gone())?;
+        reply_rx.await.map_err(|_| executor_gone())?
+    }
+}
+
+fn executor_gone() -> color_eyre::Report {
+    format_err!("Database executor is gone")
+}
+
+/// Run `job`, making sure a panic in it doesn't take down the whole thread
+///
+/// The caller will get an error, as the reply channel gets dropped.
+fn run_job(storage: &dyn Storage, job: Job, duration: &Histogram) {
+    let _timer = duration.start_timer();
+    if panic::catch_unwind(AssertUnwindSafe(|| job(storage))).is_err() {
+        warn!("Database job panicked");
+    }
+}
+
+struct WriterTxnDurations {
+    write: Histogram,
+    append: Histogram,
+}
+
+fn run_writer(
+    storage: &dyn Storage,
+    mut rx: mpsc::Receiver<WriteJob>,
+    ingest_batching: Option<IngestBatching>,
+    durations: &WriterTxnDurations,
+) {
+    // Only to wait for the next job with a timeout
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .expect("Failed to build writer thread runtime");
+
+    let mut appends = vec![];
+    // When the current batch must be committed, if there's one
+    let mut deadline = None;
+
+    loop {
+        let job = match deadline {
+            None => rt.block_on(rx.recv()),
+            Some(at) => match rt.block_on(async { tokio::time::timeout_at(at, rx.recv()).await }) {
+                Ok(job) => job,
+                Err(_elapsed) => {
+                    commit_appends(storage, &mut appends, &durations.append);
+                    deadline = None;
+                    continue;
+                }
+            },
+        };
+        let Some(job) = job else {
+            break;
+        };
+
+        match job {
+            WriteJob::Append(append) => appends.push(append),
+            WriteJob::Other(job) => {
+                // Keep the order of writes
+                commit_appends(storage, &mut appends, &durations.append);
+                run_job(storage, job, &durations.write);
+            }
+        }
+
+        let commit_now = match ingest_batching {
+            // Group together whatever got queued up while the previous
+            // commit was running
+            None => rx.is_empty() || MAX_WRITE_GROUP <= appends.len(),
+            Some(ingest_batching) => ingest_batching.max_points <= appends.len(),
+        };
+        if commit_now {
+            commit_appends(storage, &mut appends, &durations.append);
+        }
+
+        deadline = match ingest_batching {
+            _ if appends.is_empty() => None,
+            Some(ingest_batching) => Some(
+                deadline.unwrap_or_else(|| tokio::time::Instant::now() + ingest_batching.max_delay),
+            ),
+            // The queue looked non-empty, but if the next job doesn't actually
+            // show up right away, commit what we have
+            None => Some(tokio::time::Instant::now()),
+        };
+    }
+
+    commit_appends(storage, &mut appends, &durations.append);
+}
+
+fn commit_appends(storage: &dyn Storage, appends: &mut Vec<Append>, duration: &Histogram) {
+    if appends.is_empty() {
+        return;
+    }
+    let _timer = duration.start_timer();
+
+    if appends.len() == 1 {
+        commit_append(storage, appends.pop().expect("Not empty"));
+        return;
+    }
+
+    let records = appends
+        .iter()
+        .map(|Append { append, .. }| append.clone())
+        .collect::<Vec<_>>();
+
+    match panic::catch_unwind(AssertUnwindSafe(|| storage.data_points_append(&records))) {
+        Ok(Ok(data_points)) => {
+            for (Append { reply, .. }, data_point) in appends.drain(..).zip(data_points) {
+                let _ = reply.send(Ok(data_point));
+            }
+            return;
+        }
+        Ok(Err(err)) => debug!(err = %err, "Group commit failed, committing appends one by one"),
+        Err(_) => warn!("Database job panicked, committing appends one by one"),
+    }
+
+    // One bad append must not fail the others, and each caller should get its
+    // own error as it is (e.g. a `UserRequestError`)
+    for append in appends.drain(..) {
+        commit_append(storage, append);
+    }
+}
+
+/// Commit a single append, in its own transaction
+fn commit_append(storage: &dyn Storage, Append { append, reply }: Append) {
+    match panic::catch_unwind(AssertUnwindSafe(|| {
+        storage.data_points_append(std::slice::from_ref(&append))
+    })) {
+        Ok(res) => {
+            let _ = reply.send(
+                res.map(|mut data_points| data_points.pop().expect("One data point per append")),
+            );
+        }
+        Err(_) => {
+            warn!("Database job panicked");
+        }
+    }
+}
+
+fn run_reader(storage: &dyn Storage, rx: &Mutex<mpsc::Receiver<Job>>, duration: &Histogram) {
+    loop {
+        // Only the idle thread waiting for the next job holds the lock
+        let job = rx.lock().expect("locking failed").blocking_recv();
+        let Some(job) = job else {
+            break;
+        };
+        run_job(storage, job, duration);
+    }
+}
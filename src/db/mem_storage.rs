@@ -0,0 +1,331 @@
+//! [`Storage`] kept in memory only
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+
+use super::storage::{Counts, DataPointAppend, Scan, Storage};
+use super::{
+    AccessTokenRecord, AccountExportRecord, AccountRecord, Annotation, AnnotationRecord, DataPoint,
+    DataPointRecord, IdempotencyKey, IdempotencyRecord, MetricRecord, RetentionPolicy,
+};
+use crate::models::access_token::AccessToken;
+use crate::models::ts::Ts;
+use crate::models::{AccountId, AnnotationId, MetricId, MetricInternalId};
+use crate::retention::ensure_within_retention;
+
+#[derive(Debug, Default)]
+struct Tables {
+    accounts: BTreeMap<AccountId, AccountRecord>,
+    access_tokens: BTreeMap<AccessToken, AccessTokenRecord>,
+    metrics: BTreeMap<MetricId, MetricRecord>,
+    next_metric_internal_id: MetricInternalId,
+    data_points: BTreeMap<DataPoint, DataPointRecord>,
+    annotations: BTreeMap<Annotation, AnnotationRecord>,
+    account_retention: BTreeMap<AccountId, RetentionPolicy>,
+    metric_retention: BTreeMap<MetricId, RetentionPolicy>,
+    account_exports: BTreeMap<AccountId, AccountExportRecord>,
+    idempotency_keys: BTreeMap<(MetricInternalId, IdempotencyKey), IdempotencyRecord>,
+    idempotency_keys_by_created: BTreeSet<(Ts, MetricInternalId, IdempotencyKey)>,
+}
+
+/// [`Storage`] in plain in-memory maps, lost on restart
+///
+/// Every operation holds a single lock, which makes them trivially atomic.
+#[derive(Debug, Default)]
+pub struct MemStorage(Mutex<Tables>);
+
+impl MemStorage {
+    fn lock(&self) -> MutexGuard<'_, Tables> {
+        self.0.lock().expect("locking failed")
+    }
+}
+
+impl Storage for MemStorage {
+    fn account_new(&self, account_id: AccountId, record: AccountRecord) -> Result<()> {
+        self.lock().accounts.insert(account_id, record);
+        Ok(())
+    }
+
+    fn access_token_get(&self, access_token: AccessToken) -> Result<Option<AccessTokenRecord>> {
+        Ok(self.lock().access_tokens.get(&access_token).copied())
+    }
+
+    fn access_token_new(&self, access_token: AccessToken, record: AccessTokenRecord) -> Result<()> {
+        self.lock().access_tokens.insert(access_token, record);
+        Ok(())
+    }
+
+    fn access_token_remove(&self, access_token: AccessToken) -> Result<()> {
+        self.lock().access_tokens.remove(&access_token);
+        Ok(())
+    }
+
+    fn account_access_tokens(&self, account_id: AccountId) -> Result<Vec<AccessToken>> {
+        Ok(self
+            .lock()
+            .access_tokens
+            .iter()
+            .filter(|(_, record)| record.account_id == account_id)
+            .map(|(access_token, _)| *access_token)
+            .collect())
+    }
+
+    fn metric_new(&self, metric_id: MetricId, account_id: AccountId) -> Result<MetricRecord> {
+        let mut tables = self.lock();
+        let record = MetricRecord {
+            created: Ts::now(),
+            account_id,
+            internal_id: tables.next_metric_internal_id,
+        };
+        tables.next_metric_internal_id = record.internal_id.next();
+        tables.metrics.insert(metric_id, record);
+        Ok(record)
+    }
+
+    fn metric_get(&self, metric_id: MetricId) -> Result<Option<MetricRecord>> {
+        Ok(self.lock().metrics.get(&metric_id).copied())
+    }
+
+    fn metrics(&self) -> Result<Vec<(MetricId, MetricRecord)>> {
+        Ok(self.lock().metrics.iter().map(|(k, v)| (*k, *v)).collect())
+    }
+
+    fn account_metrics(&self, account_id: AccountId) -> Result<Vec<(MetricId, MetricRecord)>> {
+        Ok(self
+            .lock()
+            .metrics
+            .iter()
+            .filter(|(_, record)| record.account_id == account_id)
+            .map(|(k, v)| (*k, *v))
+            .collect())
+    }
+
+    fn counts(&self) -> Result<Counts> {
+        let tables = self.lock();
+        Ok(Counts {
+            accounts: tables.accounts.len() as u64,
+            metrics: tables.metrics.len() as u64,
+            data_points: tables.data_points.len() as u64,
+        })
+    }
+
+    fn data_points_append(&self, appends: &[DataPointAppend]) -> Result<Vec<DataPoint>> {
+        let mut tables = self.lock();
+        let mut data_points = Vec::with_capacity(appends.len());
+        for DataPointAppend {
+            metric_id,
+            account_id,
+            metric_internal_id,
+            ts,
+            record,
+            idempotency_key,
+        } in appends
+        {
+            let (metric_internal_id, ts) = (*metric_internal_id, *ts);
+            let idempotency_key = idempotency_key
+                .as_ref()
+                .map(|key| (metric_internal_id, key.clone()));
+            if let Some(existing) = idempotency_key
+                .as_ref()
+                .and_then(|key| tables.idempotency_keys.get(key))
+            {
+                data_points.push(existing.data_point);
+                continue;
+            }
+            ensure_within_retention(ts, || {
+                Ok(tables
+                    .metric_retention
+                    .get(metric_id)
+                    .or_else(|| tables.account_retention.get(account_id))
+                    .copied())
+            })?;
+
+            let idx = tables
+                .data_points
+                .range(
+                    DataPoint {
+                        metric_internal_id,
+                        ts,
+                        idx: 0,
+                    }..=DataPoint {
+                        metric_internal_id,
+                        ts,
+                        idx: u64::MAX,
+                    },
+                )
+                .next_back()
+                .map(|(k, _v)| k.idx + 1)
+                .unwrap_or_default();
+
+            let data_point = DataPoint {
+                metric_internal_id,
+                ts,
+                idx,
+            };
+            tables.data_points.insert(data_point, record.clone());
+            if let Some((metric_internal_id, key)) = idempotency_key {
+                let created = Ts::now();
+                tables.idempotency_keys_by_created.insert((
+                    created,
+                    metric_internal_id,
+                    key.clone(),
+                ));
+                tables.idempotency_keys.insert(
+                    (metric_internal_id, key),
+                    IdempotencyRecord {
+                        created,
+                        data_point,
+                    },
+                );
+            }
+            data_points.push(data_point);
+        }
+        Ok(data_points)
+    }
+
+    fn data_points_scan(
+        &self,
+        range: ops::Range<DataPoint>,
+        rev: bool,
+        f: &mut dyn FnMut(DataPoint, DataPointRecord) -> Scan,
+    ) -> Result<()> {
+        let tables = self.lock();
+        let entries = tables.data_points.range(range);
+        let entries: Box<dyn Iterator<Item = _>> = if rev {
+            Box::new(entries.rev())
+        } else {
+            Box::new(entries)
+        };
+        for (k, v) in entries {
+            if f(*k, v.clone()) == Scan::Stop {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn data_points_replace(
+        &self,
+        remove: &[DataPoint],
+        insert: &[(DataPoint, DataPointRecord)],
+    ) -> Result<()> {
+        let mut tables = self.lock();
+        for k in remove {
+            tables.data_points.remove(k);
+        }
+        for (k, v) in insert {
+            tables.data_points.insert(*k, v.clone());
+        }
+        Ok(())
+    }
+
+    fn annotation_new(&self, annotation: Annotation, record: AnnotationRecord) -> Result<()> {
+        self.lock().annotations.insert(annotation, record);
+        Ok(())
+    }
+
+    fn annotation_get(&self, annotation_id: AnnotationId) -> Result<Option<Annotation>> {
+        Ok(self
+            .lock()
+            .annotations
+            .keys()
+            .find(|annotation| annotation.annotation_id == annotation_id)
+            .copied())
+    }
+
+    fn annotation_remove(&self, annotation: Annotation) -> Result<()> {
+        self.lock().annotations.remove(&annotation);
+        Ok(())
+    }
+
+    fn annotations_scan(
+        &self,
+        range: ops::RangeInclusive<Annotation>,
+        f: &mut dyn FnMut(Annotation, AnnotationRecord) -> Scan,
+    ) -> Result<()> {
+        for (k, v) in self.lock().annotations.range(range) {
+            if f(*k, v.clone()) == Scan::Stop {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn account_retention_get(&self, account_id: AccountId) -> Result<Option<RetentionPolicy>> {
+        Ok(self.lock().account_retention.get(&account_id).copied())
+    }
+
+    fn account_retention_set(
+        &self,
+        account_id: AccountId,
+        policy: Option<RetentionPolicy>,
+    ) -> Result<()> {
+        let mut tables = self.lock();
+        match policy {
+            Some(policy) => tables.account_retention.insert(account_id, policy),
+            None => tables.account_retention.remove(&account_id),
+        };
+        Ok(())
+    }
+
+    fn metric_retention_get(&self, metric_id: MetricId) -> Result<Option<RetentionPolicy>> {
+        Ok(self.lock().metric_retention.get(&metric_id).copied())
+    }
+
+    fn metric_retention_set(
+        &self,
+        metric_id: MetricId,
+        policy: Option<RetentionPolicy>,
+    ) -> Result<()> {
+        let mut tables = self.lock();
+        match policy {
+            Some(policy) => tables.metric_retention.insert(metric_id, policy),
+            None => tables.metric_retention.remove(&metric_id),
+        };
+        Ok(())
+    }
+
+    fn account_export_get(&self, account_id: AccountId) -> Result<Option<AccountExportRecord>> {
+        Ok(self.lock().account_exports.get(&account_id).copied())
+    }
+
+    fn account_export_set(
+        &self,
+        account_id: AccountId,
+        record: Option<AccountExportRecord>,
+    ) -> Result<()> {
+        let mut tables = self.lock();
+        match record {
+            Some(record) => tables.account_exports.insert(account_id, record),
+            None => tables.account_exports.remove(&account_id),
+        };
+        Ok(())
+    }
+
+    fn idempotency_keys_prune(&self, before: Ts, limit: usize) -> Result<usize> {
+        let mut tables = self.lock();
+        let expired: Vec<_> = tables
+            .idempotency_keys_by_created
+            .iter()
+            .take_while(|(created, _, _)| *created < before)
+            .take(limit)
+            .cloned()
+            .collect();
+        for (created, metric_internal_id, key) in expired.iter().cloned() {
+            tables
+                .idempotency_keys_by_created
+                .remove(&(created, metric_internal_id, key.clone()));
+            tables.idempotency_keys.remove(&(metric_internal_id, key));
+        }
+        Ok(expired.len())
+    }
+
+    fn backup_to(&self, _path: &Path) -> Result<()> {
+        bail!("Backups are not supported by the in-memory storage")
+    }
+}
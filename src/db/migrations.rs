@@ -0,0 +1,102 @@
+//! Database schema migrations
+
+use std::ops::Bound;
+
+use bincode::{Decode, Encode};
+use color_eyre::Result;
+use redb_bincode::{TableDefinition, WriteTransaction};
+
+use super::redb_storage::{TABLE_DATA_POINTS, TABLE_METRICS, TABLE_METRICS_BY_ACCOUNT};
+use super::{DataPoint, DataPointMetadata, DataPointOutcome, DataPointRecord, DataPointValue};
+
+/// A single step migrating the database from one version to the next
+pub struct Migration {
+    /// Short description, for logging
+    pub name: &'static str,
+    /// Apply the changes, returning the number of records changed
+    pub apply: fn(&WriteTransaction) -> Result<u64>,
+}
+
+/// All migrations, in order
+///
+/// Migration at index `i` migrates the database from version `i` to `i + 1`,
+/// so the current version of the database is the number of migrations.
+/// Once released, migrations must never be changed or removed, only appended
+/// to (unreleased ones can still change), and a fixture database of each
+/// released version should be added as `tests/fixtures/v{version}.redb.gz`.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "data-point-outcome",
+        apply: data_point_outcome,
+    },
+    Migration {
+        name: "metrics-by-account",
+        apply: metrics_by_account,
+    },
+];
+
+/// [`DataPointRecord`] before [`DataPointOutcome`] was added
+#[derive(Encode, Decode, Debug, Clone)]
+struct DataPointRecordV0 {
+    value: DataPointValue,
+    metadata: DataPointMetadata,
+}
+
+const TABLE_DATA_POINTS_V0: TableDefinition<'_, DataPoint, DataPointRecordV0> =
+    TableDefinition::new("data_points");
+
+/// Mark all existing data points as successful
+fn data_point_outcome(dbtx: &WriteTransaction) -> Result<u64> {
+    /// Records rewritten at once, to bound memory use
+    const CHUNK_LEN: usize = 10_000;
+
+    let mut changed = 0;
+    let mut last = None;
+    loop {
+        // The same table can't be opened twice at once, so records are read
+        // in chunks and only then written back in the new format
+        let chunk = {
+            let table = dbtx.open_table(&TABLE_DATA_POINTS_V0)?;
+            let start = last.as_ref().map_or(Bound::Unbounded, Bound::Excluded);
+            table
+                .range::<DataPoint>((start, Bound::Unbounded))?
+                .take(CHUNK_LEN)
+                .map(|entry| entry.map(|(k, v)| (k.value(), v.value())))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let Some((last_in_chunk, _)) = chunk.last() else {
+            break;
+        };
+        last = Some(*last_in_chunk);
+
+        let mut table = dbtx.open_table(&TABLE_DATA_POINTS)?;
+        for (k, DataPointRecordV0 { value, metadata }) in chunk {
+            table.insert(
+                &k,
+                &DataPointRecord {
+                    value,
+                    metadata,
+                    outcome: DataPointOutcome::Success,
+                },
+            )?;
+            changed += 1;
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Index existing metrics by their account
+fn metrics_by_account(dbtx: &WriteTransaction) -> Result<u64> {
+    let table_metrics = dbtx.open_table(&TABLE_METRICS)?;
+    let mut table_metrics_by_account = dbtx.open_table(&TABLE_METRICS_BY_ACCOUNT)?;
+
+    let mut changed = 0;
+    for entry in table_metrics.range(..)? {
+        let (metric_id, record) = entry?;
+        table_metrics_by_account.insert(&(record.value().account_id, metric_id.value()), &())?;
+        changed += 1;
+    }
+
+    Ok(changed)
+}
@@ -0,0 +1,783 @@
+//! [`Storage`] implemented on top of redb
+
+use std::ops;
+use std::path::{Path, PathBuf};
+
+use bincode::{Decode, Encode};
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use redb::{ReadableTable as _, ReadableTableMetadata as _, TableHandle as _};
+use redb_bincode::{ReadTransaction, StorageError, TableDefinition, WriteTransaction};
+use tracing::{debug, info, instrument};
+
+use super::migrations::MIGRATIONS;
+use super::storage::{Counts, DataPointAppend, Scan, Storage};
+use super::{
+    AccessTokenRecord, AccountExportRecord, AccountRecord, Annotation, AnnotationRecord, DataPoint,
+    DataPointRecord, IdempotencyKey, IdempotencyRecord, MetricRecord, RetentionPolicy,
+};
+use crate::backup::with_suffix;
+use crate::models::access_token::AccessToken;
+use crate::models::ts::Ts;
+use crate::models::{AccountId, AnnotationId, MetricId, MetricInternalId};
+use crate::retention::ensure_within_retention;
+
+pub const TABLE_DB_VER: TableDefinition<'_, (), u64> = TableDefinition::new("db-ver");
+
+pub const TABLE_ACCOUNTS: TableDefinition<'_, AccountId, AccountRecord> =
+    TableDefinition::new("accounts");
+
+pub const TABLE_ACCESS_TOKENS: TableDefinition<'_, AccessToken, AccessTokenRecord> =
+    TableDefinition::new("access_tokens");
+
+pub const TABLE_ACCESS_TOKENS_REV: TableDefinition<'_, (AccountId, AccessToken), ()> =
+    TableDefinition::new("access_tokens_rev");
+
+pub const TABLE_METRICS: TableDefinition<'_, MetricId, MetricRecord> =
+    TableDefinition::new("metrics");
+
+pub const TABLE_METRICS_REV: TableDefinition<'_, MetricInternalId, MetricId> =
+    TableDefinition::new("metrics_rev");
+
+/// [`TABLE_METRICS`] by the account they belong to
+pub const TABLE_METRICS_BY_ACCOUNT: TableDefinition<'_, (AccountId, MetricId), ()> =
+    TableDefinition::new("metrics_by_account");
+
+pub const TABLE_DATA_POINTS: TableDefinition<'_, DataPoint, DataPointRecord> =
+    TableDefinition::new("data_points");
+
+pub const TABLE_ANNOTATIONS: TableDefinition<'_, Annotation, AnnotationRecord> =
+    TableDefinition::new("annotations");
+
+pub const TABLE_ANNOTATIONS_REV: TableDefinition<'_, AnnotationId, Annotation> =
+    TableDefinition::new("annotations_rev");
+
+/// Default retention policy of all the metrics of an account
+pub const TABLE_ACCOUNT_RETENTION: TableDefinition<'_, AccountId, RetentionPolicy> =
+    TableDefinition::new("account_retention");
+
+/// Retention policy of a metric, overriding the account default
+pub const TABLE_METRIC_RETENTION: TableDefinition<'_, MetricId, RetentionPolicy> =
+    TableDefinition::new("metric_retention");
+
+/// Accounts that export their metrics for Prometheus and Grafana
+pub const TABLE_ACCOUNT_EXPORTS: TableDefinition<'_, AccountId, AccountExportRecord> =
+    TableDefinition::new("account_exports");
+
+/// Data points posted with an idempotency key, kept for a while to recognize
+/// the same post repeated
+pub const TABLE_IDEMPOTENCY_KEYS: TableDefinition<
+    '_,
+    (MetricInternalId, IdempotencyKey),
+    IdempotencyRecord,
+> = TableDefinition::new("idempotency_keys");
+
+/// [`TABLE_IDEMPOTENCY_KEYS`] by the time they were created, to find the
+/// expired ones without scanning all of them
+pub const TABLE_IDEMPOTENCY_KEYS_BY_CREATED: TableDefinition<
+    '_,
+    (Ts, MetricInternalId, IdempotencyKey),
+    (),
+> = TableDefinition::new("idempotency_keys_by_created");
+
+/// [`Storage`] in a redb database file
+#[derive(Debug)]
+pub struct RedbStorage(redb_bincode::Database);
+
+impl RedbStorage {
+    const DB_VER: u64 = MIGRATIONS.len() as u64;
+
+    pub async fn init(self) -> Result<Self> {
+        self.write_with(|dbtx| Self::init_tables(dbtx, false))
+            .await?;
+
+        Ok(self)
+    }
+
+    /// Create missing tables and migrate the database to the current version
+    ///
+    /// With `dry_run` only the logging changes; the caller is responsible for
+    /// not committing `dbtx`.
+    fn init_tables(dbtx: &WriteTransaction, dry_run: bool) -> Result<()> {
+        dbtx.open_table(&TABLE_ACCOUNTS)?;
+        dbtx.open_table(&TABLE_ACCESS_TOKENS)?;
+        dbtx.open_table(&TABLE_ACCESS_TOKENS_REV)?;
+        dbtx.open_table(&TABLE_METRICS)?;
+        dbtx.open_table(&TABLE_METRICS_REV)?;
+        dbtx.open_table(&TABLE_METRICS_BY_ACCOUNT)?;
+        dbtx.open_table(&TABLE_DATA_POINTS)?;
+        dbtx.open_table(&TABLE_ANNOTATIONS)?;
+        dbtx.open_table(&TABLE_ANNOTATIONS_REV)?;
+        dbtx.open_table(&TABLE_ACCOUNT_RETENTION)?;
+        dbtx.open_table(&TABLE_METRIC_RETENTION)?;
+        dbtx.open_table(&TABLE_ACCOUNT_EXPORTS)?;
+        dbtx.open_table(&TABLE_IDEMPOTENCY_KEYS)?;
+        dbtx.open_table(&TABLE_IDEMPOTENCY_KEYS_BY_CREATED)?;
+
+        Self::handle_db_ver_migrations(dbtx, dry_run)?;
+
+        Ok(())
+    }
+
+    fn handle_db_ver_migrations(
+        dbtx: &WriteTransaction,
+        dry_run: bool,
+    ) -> Result<(), color_eyre::eyre::Error> {
+        let mut table_db_ver = dbtx.open_table(&TABLE_DB_VER)?;
+
+        let Some(cur_db_ver) = table_db_ver.first()?.map(|g| g.1.value()) else {
+            info!("Initializing empty database");
+            table_db_ver.insert(&(), &Self::DB_VER)?;
+
+            return Ok(());
+        };
+
+        debug!(db_ver = cur_db_ver, "Checking db version");
+        if Self::DB_VER < cur_db_ver {
+            bail!(
+                "Db version {cur_db_ver} higher than code version {}",
+                Self::DB_VER
+            );
+        }
+
+        for (from_ver, migration) in MIGRATIONS.iter().enumerate().skip(cur_db_ver as usize) {
+            let changed = (migration.apply)(dbtx)?;
+            // Same fields either way, to compare a dry run with the real one
+            let verb = if dry_run { "Would apply" } else { "Applied" };
+            info!(
+                from_ver,
+                to_ver = from_ver + 1,
+                name = migration.name,
+                changed,
+                "{verb} migration {}: {changed} records",
+                migration.name
+            );
+        }
+        table_db_ver.insert(&(), &Self::DB_VER)?;
+
+        Ok(())
+    }
+
+    /// Version of the database, if it was initialized
+    fn read_db_ver(dbtx: &ReadTransaction) -> Result<Option<u64>> {
+        match dbtx.open_table(&TABLE_DB_VER) {
+            Ok(table) => Ok(table.first()?.map(|g| g.1.value())),
+            Err(redb::TableError::TableDoesNotExist(_)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Backup the database at `path` if any migrations are about to be applied
+    /// to it
+    async fn backup_before_migrations(&self, path: &Path) -> Result<()> {
+        let Some(cur_db_ver) = self.read_with(Self::read_db_ver).await? else {
+            return Ok(());
+        };
+        if MIGRATIONS
+            .get(cur_db_ver as usize..)
+            .is_none_or(|pending| pending.is_empty())
+        {
+            return Ok(());
+        }
+
+        let backup_path = with_suffix(path, &format!(".pre-migration-v{cur_db_ver}"));
+        if tokio::fs::try_exists(&backup_path).await? {
+            info!(path = %backup_path.display(), "Pre-migration backup already exists");
+            return Ok(());
+        }
+
+        info!(path = %backup_path.display(), "Backing up database before migrations");
+        let tmp_path = with_suffix(&backup_path, ".tmp");
+        tokio::task::block_in_place(|| Storage::backup_to(self, &tmp_path))?;
+        tokio::fs::rename(&tmp_path, &backup_path).await?;
+
+        Ok(())
+    }
+
+    /// Run the migrations of the database at `path` without committing them,
+    /// to see what they would do
+    #[instrument]
+    pub async fn migrate_dry_run(path: &Path) -> Result<()> {
+        let db = Self::open_existing(path).await?;
+
+        let cur_db_ver = db.read_with(Self::read_db_ver).await?;
+        info!(
+            db_ver = ?cur_db_ver,
+            code_db_ver = Self::DB_VER,
+            "Starting migrations dry run"
+        );
+
+        tokio::task::block_in_place(|| {
+            let dbtx = db.0.begin_write()?;
+            Self::init_tables(&dbtx, true)?;
+            // Dropping without committing aborts the transaction
+            drop(dbtx);
+            Ok::<_, color_eyre::eyre::Error>(())
+        })?;
+
+        info!("Dry run complete, no changes were made");
+        Ok(())
+    }
+}
+
+/// Untyped view of any of the tables, for copying records around as they are
+type RawTableDefinition<'a> = redb::TableDefinition<'a, &'static [u8], &'static [u8]>;
+
+impl RedbStorage {
+    /// Check that the database file at `path` is not corrupted and can be
+    /// used by this version of the code
+    ///
+    /// Every record of every known table is decoded, to detect any
+    /// incompatibilities early.
+    #[instrument]
+    pub fn verify(path: &Path) -> Result<()> {
+        fn verify_table<K, V>(
+            tx: &ReadTransaction,
+            table_def: &TableDefinition<'_, K, V>,
+        ) -> Result<()>
+        where
+            K: Encode + Decode,
+            V: Encode + Decode,
+        {
+            let table = match tx.open_table(table_def) {
+                Ok(table) => table,
+                // Tables are created on first use, it's fine if some are missing
+                Err(redb::TableError::TableDoesNotExist(_)) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
+
+            for entry in table.range::<K>(..)? {
+                let (k, v) = entry?;
+                k.value_try()?;
+                v.value_try()?;
+            }
+            Ok(())
+        }
+
+        let mut db = redb::Database::open(path)?;
+        if !db.check_integrity()? {
+            bail!("Database file was corrupted");
+        }
+        let db = redb_bincode::Database::from(db);
+        let tx = db.begin_read()?;
+
+        let Some(db_ver) = tx
+            .open_table(&TABLE_DB_VER)?
+            .first()?
+            .map(|g| g.1.value_try())
+        else {
+            bail!("Database version missing");
+        };
+        let db_ver = db_ver?;
+        if Self::DB_VER < db_ver {
+            bail!(
+                "Db version {db_ver} higher than code version {}",
+                Self::DB_VER
+            );
+        }
+
+        verify_table(&tx, &TABLE_ACCOUNTS)?;
+        verify_table(&tx, &TABLE_ACCESS_TOKENS)?;
+        verify_table(&tx, &TABLE_ACCESS_TOKENS_REV)?;
+        verify_table(&tx, &TABLE_METRICS)?;
+        verify_table(&tx, &TABLE_METRICS_REV)?;
+        verify_table(&tx, &TABLE_METRICS_BY_ACCOUNT)?;
+        verify_table(&tx, &TABLE_DATA_POINTS)?;
+        verify_table(&tx, &TABLE_ANNOTATIONS)?;
+        verify_table(&tx, &TABLE_ANNOTATIONS_REV)?;
+        verify_table(&tx, &TABLE_ACCOUNT_RETENTION)?;
+        verify_table(&tx, &TABLE_METRIC_RETENTION)?;
+        verify_table(&tx, &TABLE_ACCOUNT_EXPORTS)?;
+        verify_table(&tx, &TABLE_IDEMPOTENCY_KEYS)?;
+        verify_table(&tx, &TABLE_IDEMPOTENCY_KEYS_BY_CREATED)?;
+
+        Ok(())
+    }
+}
+
+impl From<redb_bincode::Database> for RedbStorage {
+    fn from(db: redb_bincode::Database) -> Self {
+        Self(db)
+    }
+}
+
+impl RedbStorage {
+    fn write<T>(&self, f: impl FnOnce(&'_ WriteTransaction) -> Result<T>) -> Result<T> {
+        let mut dbtx = self.0.begin_write()?;
+
+        let res = f(&mut dbtx)?;
+
+        dbtx.commit()?;
+
+        Ok(res)
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&'_ ReadTransaction) -> Result<T>) -> Result<T> {
+        let mut dbtx = self.0.begin_read()?;
+
+        f(&mut dbtx)
+    }
+
+    pub async fn write_with<T>(
+        &self,
+        f: impl FnOnce(&'_ WriteTransaction) -> Result<T>,
+    ) -> Result<T> {
+        tokio::task::block_in_place(|| self.write(f))
+    }
+
+    pub async fn read_with<T>(
+        &self,
+        f: impl FnOnce(&'_ ReadTransaction) -> Result<T>,
+    ) -> Result<T> {
+        tokio::task::block_in_place(|| self.read(f))
+    }
+
+    /// Open the existing database at `path` as it is, without initializing or
+    /// migrating it
+    pub async fn open_existing(path: &Path) -> Result<Self> {
+        if !tokio::fs::try_exists(path).await? {
+            bail!("Database {} does not exist", path.display());
+        }
+        let path = path.to_owned();
+        Ok(Self::from(
+            tokio::task::spawn_blocking(move || redb_bincode::Database::open(path)).await??,
+        ))
+    }
+
+    /// Fail unless the database was initialized and migrated to the version of
+    /// this code
+    pub async fn ensure_current_version(&self) -> Result<()> {
+        let db_ver = self.read_with(Self::read_db_ver).await?;
+        if db_ver != Some(Self::DB_VER) {
+            bail!(
+                "Db version {db_ver:?} doesn't match code version {}; start perfitd on it first to migrate it",
+                Self::DB_VER
+            );
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let create = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || redb_bincode::Database::create(path)
+        })
+        .await??;
+        let db = Self::from(create);
+        db.backup_before_migrations(&path).await?;
+        db.init().await
+    }
+}
+
+/// Feed decoded `entries` to `f` until it stops the scan
+fn scan<K, V>(
+    entries: impl Iterator<Item = Result<(K, V), StorageError>>,
+    f: &mut dyn FnMut(K, V) -> Scan,
+) -> Result<()> {
+    for entry in entries {
+        let (k, v) = entry?;
+        if f(k, v) == Scan::Stop {
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl Storage for RedbStorage {
+    fn account_new(&self, account_id: AccountId, record: AccountRecord) -> Result<()> {
+        self.write(|tx| {
+            tx.open_table(&TABLE_ACCOUNTS)?
+                .insert(&account_id, &record)?;
+            Ok(())
+        })
+    }
+
+    fn access_token_get(&self, access_token: AccessToken) -> Result<Option<AccessTokenRecord>> {
+        self.read(|tx| {
+            Ok(tx
+                .open_table(&TABLE_ACCESS_TOKENS)?
+                .get(&access_token)?
+                .map(|r| r.value()))
+        })
+    }
+
+    fn access_token_new(&self, access_token: AccessToken, record: AccessTokenRecord) -> Result<()> {
+        self.write(|tx| {
+            tx.open_table(&TABLE_ACCESS_TOKENS)?
+                .insert(&access_token, &record)?;
+            tx.open_table(&TABLE_ACCESS_TOKENS_REV)?
+                .insert(&(record.account_id, access_token), &())?;
+            Ok(())
+        })
+    }
+
+    fn access_token_remove(&self, access_token: AccessToken) -> Result<()> {
+        self.write(|tx| {
+            if let Some(record) = tx.open_table(&TABLE_ACCESS_TOKENS)?.remove(&access_token)? {
+                tx.open_table(&TABLE_ACCESS_TOKENS_REV)?
+                    .remove(&(record.value().account_id, access_token))?;
+            }
+            Ok(())
+        })
+    }
+
+    fn account_access_tokens(&self, account_id: AccountId) -> Result<Vec<AccessToken>> {
+        self.read(|tx| {
+            tx.open_table(&TABLE_ACCESS_TOKENS_REV)?
+                .range(&(account_id, AccessToken::ZERO)..=&(account_id, AccessToken::LAST))?
+                .map(|entry| Ok(entry?.0.value().1))
+                .collect()
+        })
+    }
+
+    fn metric_new(&self, metric_id: MetricId, account_id: AccountId) -> Result<MetricRecord> {
+        self.write(|tx| {
+            let mut table_metric_rev = tx.open_table(&TABLE_METRICS_REV)?;
+            let new_internal_id = table_metric_rev
+                .last()?
+                .map(|(k, _v)| k.value().next())
+                .unwrap_or_default();
+
+            let record = MetricRecord {
+                created: Ts::now(),
+                account_id,
+                internal_id: new_internal_id,
+            };
+            tx.open_table(&TABLE_METRICS)?.insert(&metric_id, &record)?;
+            table_metric_rev.insert(&new_internal_id, &metric_id)?;
+            tx.open_table(&TABLE_METRICS_BY_ACCOUNT)?
+                .insert(&(account_id, metric_id), &())?;
+
+            Ok(record)
+        })
+    }
+
+    fn metric_get(&self, metric_id: MetricId) -> Result<Option<MetricRecord>> {
+        self.read(|tx| {
+            Ok(tx
+                .open_table(&TABLE_METRICS)?
+                .get(&metric_id)?
+                .map(|r| r.value()))
+        })
+    }
+
+    fn metrics(&self) -> Result<Vec<(MetricId, MetricRecord)>> {
+        self.read(|tx| {
+            tx.open_table(&TABLE_METRICS)?
+                .range(..)?
+                .map(|entry| {
+                    let (k, v) = entry?;
+                    Ok((k.value(), v.value()))
+                })
+                .collect()
+        })
+    }
+
+    fn account_metrics(&self, account_id: AccountId) -> Result<Vec<(MetricId, MetricRecord)>> {
+        self.read(|tx| {
+            let table_metrics = tx.open_table(&TABLE_METRICS)?;
+            tx.open_table(&TABLE_METRICS_BY_ACCOUNT)?
+                .range(&(account_id, MetricId::ZERO)..=&(account_id, MetricId::LAST))?
+                .map(|entry| {
+                    let metric_id = entry?.0.value().1;
+                    let record = table_metrics
+                        .get(&metric_id)?
+                        .ok_or_else(|| color_eyre::eyre::format_err!("Missing metric {metric_id}"))?
+                        .value();
+                    Ok((metric_id, record))
+                })
+                .collect()
+        })
+    }
+
+    fn counts(&self) -> Result<Counts> {
+        self.read(|tx| {
+            Ok(Counts {
+                accounts: tx.open_table(&TABLE_ACCOUNTS)?.as_raw().len()?,
+                metrics: tx.open_table(&TABLE_METRICS)?.as_raw().len()?,
+                data_points: tx.open_table(&TABLE_DATA_POINTS)?.as_raw().len()?,
+            })
+        })
+    }
+
+    fn data_points_append(&self, appends: &[DataPointAppend]) -> Result<Vec<DataPoint>> {
+        self.write(|tx| {
+            let mut data_points_table = tx.open_table(&TABLE_DATA_POINTS)?;
+            let mut idempotency_keys_table = tx.open_table(&TABLE_IDEMPOTENCY_KEYS)?;
+            let mut idempotency_keys_by_created_table =
+                tx.open_table(&TABLE_IDEMPOTENCY_KEYS_BY_CREATED)?;
+            let metric_retention_table = tx.open_table(&TABLE_METRIC_RETENTION)?;
+            let account_retention_table = tx.open_table(&TABLE_ACCOUNT_RETENTION)?;
+
+            let mut data_points = Vec::with_capacity(appends.len());
+            for DataPointAppend {
+                metric_id,
+                account_id,
+                metric_internal_id,
+                ts,
+                record,
+                idempotency_key,
+            } in appends
+            {
+                let (metric_internal_id, ts) = (*metric_internal_id, *ts);
+                let idempotency_key = idempotency_key
+                    .as_ref()
+                    .map(|key| (metric_internal_id, key.clone()));
+                if let Some(key) = &idempotency_key {
+                    if let Some(existing) = idempotency_keys_table.get(key)? {
+                        data_points.push(existing.value().data_point);
+                        continue;
+                    }
+                }
+                ensure_within_retention(ts, || {
+                    Ok(match metric_retention_table.get(metric_id)? {
+                        Some(policy) => Some(policy.value()),
+                        None => account_retention_table.get(account_id)?.map(|r| r.value()),
+                    })
+                })?;
+
+                let idx = data_points_table
+                    .range(
+                        &DataPoint {
+                            metric_internal_id,
+                            ts,
+                            idx: 0,
+                        }..=&DataPoint {
+                            metric_internal_id,
+                            ts,
+                            idx: u64::MAX,
+                        },
+                    )?
+                    .next_back()
+                    .transpose()?
+                    .map(|(k, _v)| k.value().idx + 1)
+                    .unwrap_or_default();
+
+                let data_point = DataPoint {
+                    metric_internal_id,
+                    ts,
+                    idx,
+                };
+                data_points_table.insert(&data_point, record)?;
+                if let Some(key) = &idempotency_key {
+                    let created = Ts::now();
+                    idempotency_keys_table.insert(
+                        key,
+                        &IdempotencyRecord {
+                            created,
+                            data_point,
+                        },
+                    )?;
+                    idempotency_keys_by_created_table
+                        .insert(&(created, key.0, key.1.clone()), &())?;
+                }
+                data_points.push(data_point);
+            }
+
+            Ok(data_points)
+        })
+    }
+
+    fn data_points_scan(
+        &self,
+        range: ops::Range<DataPoint>,
+        rev: bool,
+        f: &mut dyn FnMut(DataPoint, DataPointRecord) -> Scan,
+    ) -> Result<()> {
+        self.read(|tx| {
+            let table = tx.open_table(&TABLE_DATA_POINTS)?;
+            let entries = table
+                .range(&range.start..&range.end)?
+                .map(|entry| entry.map(|(k, v)| (k.value(), v.value())));
+            if rev {
+                scan(entries.rev(), f)
+            } else {
+                scan(entries, f)
+            }
+        })
+    }
+
+    fn data_points_replace(
+        &self,
+        remove: &[DataPoint],
+        insert: &[(DataPoint, DataPointRecord)],
+    ) -> Result<()> {
+        self.write(|tx| {
+            let mut table = tx.open_table(&TABLE_DATA_POINTS)?;
+            for k in remove {
+                table.remove(k)?;
+            }
+            for (k, v) in insert {
+                table.insert(k, v)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn annotation_new(&self, annotation: Annotation, record: AnnotationRecord) -> Result<()> {
+        self.write(|tx| {
+            tx.open_table(&TABLE_ANNOTATIONS)?
+                .insert(&annotation, &record)?;
+            tx.open_table(&TABLE_ANNOTATIONS_REV)?
+                .insert(&annotation.annotation_id, &annotation)?;
+            Ok(())
+        })
+    }
+
+    fn annotation_get(&self, annotation_id: AnnotationId) -> Result<Option<Annotation>> {
+        self.read(|tx| {
+            Ok(tx
+                .open_table(&TABLE_ANNOTATIONS_REV)?
+                .get(&annotation_id)?
+                .map(|r| r.value()))
+        })
+    }
+
+    fn annotation_remove(&self, annotation: Annotation) -> Result<()> {
+        self.write(|tx| {
+            tx.open_table(&TABLE_ANNOTATIONS)?.remove(&annotation)?;
+            tx.open_table(&TABLE_ANNOTATIONS_REV)?
+                .remove(&annotation.annotation_id)?;
+            Ok(())
+        })
+    }
+
+    fn annotations_scan(
+        &self,
+        range: ops::RangeInclusive<Annotation>,
+        f: &mut dyn FnMut(Annotation, AnnotationRecord) -> Scan,
+    ) -> Result<()> {
+        self.read(|tx| {
+            scan(
+                tx.open_table(&TABLE_ANNOTATIONS)?
+                    .range(range.start()..=range.end())?
+                    .map(|entry| entry.map(|(k, v)| (k.value(), v.value()))),
+                f,
+            )
+        })
+    }
+
+    fn account_retention_get(&self, account_id: AccountId) -> Result<Option<RetentionPolicy>> {
+        self.read(|tx| {
+            Ok(tx
+                .open_table(&TABLE_ACCOUNT_RETENTION)?
+                .get(&account_id)?
+                .map(|r| r.value()))
+        })
+    }
+
+    fn account_retention_set(
+        &self,
+        account_id: AccountId,
+        policy: Option<RetentionPolicy>,
+    ) -> Result<()> {
+        self.write(|tx| {
+            let mut table = tx.open_table(&TABLE_ACCOUNT_RETENTION)?;
+            match policy {
+                Some(policy) => table.insert(&account_id, &policy)?,
+                None => table.remove(&account_id)?,
+            };
+            Ok(())
+        })
+    }
+
+    fn metric_retention_get(&self, metric_id: MetricId) -> Result<Option<RetentionPolicy>> {
+        self.read(|tx| {
+            Ok(tx
+                .open_table(&TABLE_METRIC_RETENTION)?
+                .get(&metric_id)?
+                .map(|r| r.value()))
+        })
+    }
+
+    fn metric_retention_set(
+        &self,
+        metric_id: MetricId,
+        policy: Option<RetentionPolicy>,
+    ) -> Result<()> {
+        self.write(|tx| {
+            let mut table = tx.open_table(&TABLE_METRIC_RETENTION)?;
+            match policy {
+                Some(policy) => table.insert(&metric_id, &policy)?,
+                None => table.remove(&metric_id)?,
+            };
+            Ok(())
+        })
+    }
+
+    fn account_export_get(&self, account_id: AccountId) -> Result<Option<AccountExportRecord>> {
+        self.read(|tx| {
+            Ok(tx
+                .open_table(&TABLE_ACCOUNT_EXPORTS)?
+                .get(&account_id)?
+                .map(|r| r.value()))
+        })
+    }
+
+    fn account_export_set(
+        &self,
+        account_id: AccountId,
+        record: Option<AccountExportRecord>,
+    ) -> Result<()> {
+        self.write(|tx| {
+            let mut table = tx.open_table(&TABLE_ACCOUNT_EXPORTS)?;
+            match record {
+                Some(record) => table.insert(&account_id, &record)?,
+                None => table.remove(&account_id)?,
+            };
+            Ok(())
+        })
+    }
+
+    fn idempotency_keys_prune(&self, before: Ts, limit: usize) -> Result<usize> {
+        self.write(|tx| {
+            let mut table = tx.open_table(&TABLE_IDEMPOTENCY_KEYS)?;
+            let mut by_created_table = tx.open_table(&TABLE_IDEMPOTENCY_KEYS_BY_CREATED)?;
+            // Oldest first, so only the expired ones are visited
+            let expired = by_created_table
+                .range::<(Ts, MetricInternalId, IdempotencyKey)>(..)?
+                .map(|entry| entry.map(|(k, _)| k.value()))
+                .take_while(|entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |(created, _, _)| *created < before)
+                })
+                .take(limit)
+                .collect::<Result<Vec<_>, _>>()?;
+            for key in &expired {
+                by_created_table.remove(key)?;
+                let (_, metric_internal_id, idempotency_key) = key;
+                table.remove(&(*metric_internal_id, idempotency_key.clone()))?;
+            }
+            Ok(expired.len())
+        })
+    }
+
+    /// Only a read transaction is held, so the database can be used as usual
+    /// in the meantime.
+    #[instrument(skip(self))]
+    fn backup_to(&self, path: &Path) -> Result<()> {
+        self.read(|tx| {
+            let src = tx.as_raw();
+            let dst = redb::Database::create(path)?;
+            let dst_tx = dst.begin_write()?;
+
+            for table in src.list_tables()? {
+                let def = RawTableDefinition::new(table.name());
+                let src_table = src.open_table(def)?;
+                let mut dst_table = dst_tx.open_table(def)?;
+
+                for entry in src_table.iter()? {
+                    let (k, v) = entry?;
+                    dst_table.insert(k.value(), v.value())?;
+                }
+                debug!(table = table.name(), len = src_table.len()?, "Table copied");
+            }
+
+            dst_tx.commit()?;
+            Ok(())
+        })
+    }
+}
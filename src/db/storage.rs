@@ -0,0 +1,131 @@
+//! Storage backend interface
+
+use std::fmt;
+use std::ops;
+use std::path::Path;
+
+use color_eyre::Result;
+
+use super::{
+    AccessTokenRecord, AccountExportRecord, AccountRecord, Annotation, AnnotationRecord, DataPoint,
+    DataPointRecord, IdempotencyKey, MetricRecord, RetentionPolicy,
+};
+use crate::models::access_token::AccessToken;
+use crate::models::ts::Ts;
+use crate::models::{AccountId, AnnotationId, MetricId, MetricInternalId};
+
+/// Whether a scan callback wants more records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scan {
+    Continue,
+    Stop,
+}
+
+/// Number of records of the main kinds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counts {
+    pub accounts: u64,
+    pub metrics: u64,
+    pub data_points: u64,
+}
+
+/// A data point to append, see [`Storage::data_points_append`]
+#[derive(Debug, Clone)]
+pub struct DataPointAppend {
+    /// Metric (and its account) to look up the retention policy of
+    pub metric_id: MetricId,
+    pub account_id: AccountId,
+    pub metric_internal_id: MetricInternalId,
+    pub ts: Ts,
+    pub record: DataPointRecord,
+    pub idempotency_key: Option<IdempotencyKey>,
+}
+
+/// Domain operations on the persisted data
+///
+/// Every method is a single, atomic transaction. Methods are blocking, and
+/// [`super::Database`] takes care of calling them from async code.
+pub trait Storage: fmt::Debug + Send + Sync {
+    fn account_new(&self, account_id: AccountId, record: AccountRecord) -> Result<()>;
+
+    fn access_token_get(&self, access_token: AccessToken) -> Result<Option<AccessTokenRecord>>;
+    fn access_token_new(&self, access_token: AccessToken, record: AccessTokenRecord) -> Result<()>;
+    fn access_token_remove(&self, access_token: AccessToken) -> Result<()>;
+    fn account_access_tokens(&self, account_id: AccountId) -> Result<Vec<AccessToken>>;
+
+    /// Create a new metric, with the next free internal id
+    fn metric_new(&self, metric_id: MetricId, account_id: AccountId) -> Result<MetricRecord>;
+    fn metric_get(&self, metric_id: MetricId) -> Result<Option<MetricRecord>>;
+    fn metrics(&self) -> Result<Vec<(MetricId, MetricRecord)>>;
+    /// Metrics of an account, without going through all the others
+    fn account_metrics(&self, account_id: AccountId) -> Result<Vec<(MetricId, MetricRecord)>>;
+    /// Cheap to call, without going through all the records
+    fn counts(&self) -> Result<Counts>;
+
+    /// Append data points, each after any existing ones of its metric in the
+    /// same second
+    ///
+    /// All of them are written in a single transaction, to amortize the cost
+    /// of a commit over many concurrent posts. An append with an idempotency
+    /// key already used for its metric is skipped, and the data point
+    /// appended back then is returned instead. Fails if any of them is past
+    /// the retention cutoff of its metric (see
+    /// [`crate::retention::ensure_within_retention`]).
+    fn data_points_append(&self, appends: &[DataPointAppend]) -> Result<Vec<DataPoint>>;
+    /// Call `f` with data points in `range`, in order (or reverse order if
+    /// `rev`), until it returns [`Scan::Stop`]
+    fn data_points_scan(
+        &self,
+        range: ops::Range<DataPoint>,
+        rev: bool,
+        f: &mut dyn FnMut(DataPoint, DataPointRecord) -> Scan,
+    ) -> Result<()>;
+    /// Remove data points with `remove` keys, then insert `insert` ones
+    fn data_points_replace(
+        &self,
+        remove: &[DataPoint],
+        insert: &[(DataPoint, DataPointRecord)],
+    ) -> Result<()>;
+
+    fn annotation_new(&self, annotation: Annotation, record: AnnotationRecord) -> Result<()>;
+    fn annotation_get(&self, annotation_id: AnnotationId) -> Result<Option<Annotation>>;
+    fn annotation_remove(&self, annotation: Annotation) -> Result<()>;
+    /// Call `f` with annotations in `range`, in order, until it returns
+    /// [`Scan::Stop`]
+    fn annotations_scan(
+        &self,
+        range: ops::RangeInclusive<Annotation>,
+        f: &mut dyn FnMut(Annotation, AnnotationRecord) -> Scan,
+    ) -> Result<()>;
+
+    fn account_retention_get(&self, account_id: AccountId) -> Result<Option<RetentionPolicy>>;
+    /// Set, or remove with `None`, the default retention policy of an account
+    fn account_retention_set(
+        &self,
+        account_id: AccountId,
+        policy: Option<RetentionPolicy>,
+    ) -> Result<()>;
+    fn metric_retention_get(&self, metric_id: MetricId) -> Result<Option<RetentionPolicy>>;
+    /// Set, or remove with `None`, the retention policy of a metric
+    fn metric_retention_set(
+        &self,
+        metric_id: MetricId,
+        policy: Option<RetentionPolicy>,
+    ) -> Result<()>;
+
+    fn account_export_get(&self, account_id: AccountId) -> Result<Option<AccountExportRecord>>;
+    /// Enable, or disable with `None`, exporting metrics of an account
+    fn account_export_set(
+        &self,
+        account_id: AccountId,
+        record: Option<AccountExportRecord>,
+    ) -> Result<()>;
+
+    /// Forget up to `limit` idempotency keys created before `before`,
+    /// returning how many were removed
+    fn idempotency_keys_prune(&self, before: Ts, limit: usize) -> Result<usize>;
+
+    /// Write a consistent snapshot of all the data to a new redb database file
+    /// at `path`
+    fn backup_to(&self, path: &Path) -> Result<()>;
+}
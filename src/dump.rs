@@ -0,0 +1,250 @@
+//! Portable, logical dump of the database
+//!
+//! The format is line-delimited JSON: a [`DumpHeader`] followed by one
+//! [`DumpEntry`] per line. It depends only on the serde representation of the
+//! records, not on redb or bincode, so it can be used to move data between
+//! incompatible versions of the database. Reverse-lookup tables are not
+//! dumped, and are rebuilt on load instead. Short-lived idempotency keys are
+//! not dumped at all.
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use color_eyre::eyre::{bail, Context as _};
+use color_eyre::Result;
+use redb_bincode::{ReadTransaction, TableDefinition};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::backup::with_suffix;
+use crate::db::redb_storage::{
+    TABLE_ACCESS_TOKENS, TABLE_ACCESS_TOKENS_REV, TABLE_ACCOUNTS, TABLE_ACCOUNT_EXPORTS,
+    TABLE_ACCOUNT_RETENTION, TABLE_ANNOTATIONS, TABLE_ANNOTATIONS_REV, TABLE_DATA_POINTS,
+    TABLE_METRICS, TABLE_METRICS_BY_ACCOUNT, TABLE_METRICS_REV, TABLE_METRIC_RETENTION,
+};
+use crate::db::{
+    AccessTokenRecord, AccountExportRecord, AccountRecord, Annotation, AnnotationRecord, DataPoint,
+    DataPointRecord, MetricRecord, RedbStorage, RetentionPolicy,
+};
+use crate::models::access_token::AccessToken;
+use crate::models::{AccountId, MetricId};
+
+const DUMP_FORMAT: &str = "perfitd-dump";
+const DUMP_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DumpHeader {
+    format: String,
+    version: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "table", rename_all = "snake_case")]
+enum DumpEntry {
+    Accounts {
+        key: AccountId,
+        value: AccountRecord,
+    },
+    AccessTokens {
+        key: AccessToken,
+        value: AccessTokenRecord,
+    },
+    Metrics {
+        key: MetricId,
+        value: MetricRecord,
+    },
+    DataPoints {
+        key: DataPoint,
+        value: DataPointRecord,
+    },
+    Annotations {
+        key: Annotation,
+        value: AnnotationRecord,
+    },
+    AccountRetention {
+        key: AccountId,
+        value: RetentionPolicy,
+    },
+    MetricRetention {
+        key: MetricId,
+        value: RetentionPolicy,
+    },
+    AccountExports {
+        key: AccountId,
+        value: AccountExportRecord,
+    },
+}
+
+/// Write all the records of the database at `db_path` to `out`
+///
+/// The database is only read, so it must exist and already be migrated to the
+/// current version.
+pub async fn dump(db_path: &Path, out: impl Write) -> Result<()> {
+    fn dump_table<K, V>(
+        tx: &ReadTransaction,
+        table_def: &TableDefinition<'_, K, V>,
+        out: &mut impl Write,
+        to_entry: impl Fn(K, V) -> DumpEntry,
+    ) -> Result<u64>
+    where
+        K: bincode::Encode + bincode::Decode,
+        V: bincode::Encode + bincode::Decode,
+    {
+        let mut len = 0;
+        for entry in tx.open_table(table_def)?.range::<K>(..)? {
+            let (k, v) = entry?;
+            serde_json::to_writer(&mut *out, &to_entry(k.value(), v.value()))?;
+            out.write_all(b"\n")?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    let db = RedbStorage::open_existing(db_path).await?;
+    db.ensure_current_version().await?;
+    let mut out = io::BufWriter::new(out);
+
+    db.read_with(|tx| {
+        serde_json::to_writer(
+            &mut out,
+            &DumpHeader {
+                format: DUMP_FORMAT.into(),
+                version: DUMP_VERSION,
+            },
+        )?;
+        out.write_all(b"\n")?;
+
+        let mut len = 0;
+        len += dump_table(tx, &TABLE_ACCOUNTS, &mut out, |key, value| {
+            DumpEntry::Accounts { key, value }
+        })?;
+        len += dump_table(tx, &TABLE_ACCESS_TOKENS, &mut out, |key, value| {
+            DumpEntry::AccessTokens { key, value }
+        })?;
+        len += dump_table(tx, &TABLE_METRICS, &mut out, |key, value| {
+            DumpEntry::Metrics { key, value }
+        })?;
+        len += dump_table(tx, &TABLE_DATA_POINTS, &mut out, |key, value| {
+            DumpEntry::DataPoints { key, value }
+        })?;
+        len += dump_table(tx, &TABLE_ANNOTATIONS, &mut out, |key, value| {
+            DumpEntry::Annotations { key, value }
+        })?;
+        len += dump_table(tx, &TABLE_ACCOUNT_RETENTION, &mut out, |key, value| {
+            DumpEntry::AccountRetention { key, value }
+        })?;
+        len += dump_table(tx, &TABLE_METRIC_RETENTION, &mut out, |key, value| {
+            DumpEntry::MetricRetention { key, value }
+        })?;
+        len += dump_table(tx, &TABLE_ACCOUNT_EXPORTS, &mut out, |key, value| {
+            DumpEntry::AccountExports { key, value }
+        })?;
+
+        out.flush()?;
+        info!(len, "Dump complete");
+        Ok(())
+    })
+    .await
+}
+
+/// Create a new database at `db_path` with records from a dump
+pub async fn load(db_path: &Path, input: impl BufRead) -> Result<()> {
+    if tokio::fs::try_exists(db_path).await? {
+        bail!("Database {} already exists", db_path.display());
+    }
+
+    let mut lines = input.lines();
+
+    let header: DumpHeader = serde_json::from_str(
+        &lines
+            .next()
+            .transpose()?
+            .ok_or_else(|| color_eyre::eyre::format_err!("Empty dump"))?,
+    )
+    .wrap_err("Invalid dump header")?;
+    if header.format != DUMP_FORMAT {
+        bail!("Not a perfitd dump");
+    }
+    if DUMP_VERSION < header.version {
+        bail!(
+            "Dump version {} higher than code version {DUMP_VERSION}",
+            header.version
+        );
+    }
+
+    // Load into a temporary file first, so a failed load doesn't leave a
+    // partial database behind
+    let tmp_path = with_suffix(db_path, ".load.tmp");
+    if tokio::fs::try_exists(&tmp_path).await? {
+        tokio::fs::remove_file(&tmp_path).await?;
+    }
+    let db = RedbStorage::open(&tmp_path).await?;
+
+    let res = db
+        .write_with(|tx| {
+            let mut table_accounts = tx.open_table(&TABLE_ACCOUNTS)?;
+            let mut table_access_tokens = tx.open_table(&TABLE_ACCESS_TOKENS)?;
+            let mut table_access_tokens_rev = tx.open_table(&TABLE_ACCESS_TOKENS_REV)?;
+            let mut table_metrics = tx.open_table(&TABLE_METRICS)?;
+            let mut table_metrics_rev = tx.open_table(&TABLE_METRICS_REV)?;
+            let mut table_metrics_by_account = tx.open_table(&TABLE_METRICS_BY_ACCOUNT)?;
+            let mut table_data_points = tx.open_table(&TABLE_DATA_POINTS)?;
+            let mut table_annotations = tx.open_table(&TABLE_ANNOTATIONS)?;
+            let mut table_annotations_rev = tx.open_table(&TABLE_ANNOTATIONS_REV)?;
+            let mut table_account_retention = tx.open_table(&TABLE_ACCOUNT_RETENTION)?;
+            let mut table_metric_retention = tx.open_table(&TABLE_METRIC_RETENTION)?;
+            let mut table_account_exports = tx.open_table(&TABLE_ACCOUNT_EXPORTS)?;
+
+            let mut len = 0;
+            for (line_i, line) in lines.enumerate() {
+                let line = line?;
+                let entry: DumpEntry = serde_json::from_str(&line)
+                    // +2: lines are 1-indexed and after the header
+                    .wrap_err_with(|| format!("Invalid entry at line {}", line_i + 2))?;
+
+                match entry {
+                    DumpEntry::Accounts { key, value } => {
+                        table_accounts.insert(&key, &value)?;
+                    }
+                    DumpEntry::AccessTokens { key, value } => {
+                        table_access_tokens.insert(&key, &value)?;
+                        table_access_tokens_rev.insert(&(value.account_id, key), &())?;
+                    }
+                    DumpEntry::Metrics { key, value } => {
+                        table_metrics.insert(&key, &value)?;
+                        table_metrics_rev.insert(&value.internal_id, &key)?;
+                        table_metrics_by_account.insert(&(value.account_id, key), &())?;
+                    }
+                    DumpEntry::DataPoints { key, value } => {
+                        table_data_points.insert(&key, &value)?;
+                    }
+                    DumpEntry::Annotations { key, value } => {
+                        table_annotations.insert(&key, &value)?;
+                        table_annotations_rev.insert(&key.annotation_id, &key)?;
+                    }
+                    DumpEntry::AccountRetention { key, value } => {
+                        table_account_retention.insert(&key, &value)?;
+                    }
+                    DumpEntry::MetricRetention { key, value } => {
+                        table_metric_retention.insert(&key, &value)?;
+                    }
+                    DumpEntry::AccountExports { key, value } => {
+                        table_account_exports.insert(&key, &value)?;
+                    }
+                }
+                len += 1;
+            }
+
+            info!(len, "Load complete");
+            Ok(())
+        })
+        .await;
+    drop(db);
+
+    if res.is_ok() {
+        tokio::fs::rename(&tmp_path, db_path).await?;
+    } else {
+        tokio::fs::remove_file(&tmp_path).await?;
+    }
+    res
+}
@@ -3,11 +3,13 @@ use maud::{html, Markup, DOCTYPE};
 
 use crate::models::MetricId;
 use crate::routes::error::{RequestResult, UserRequestError};
-use crate::routes::metric::MetricOpts;
+use crate::routes::metric::{ChartStyle, ChartTheme, MetricOpts, YScale, YZoom};
 use crate::routes::render_svg;
 use crate::state::SharedAppState;
 
 const LABEL_CLASS: &str = "block mb-2 text-sm font-medium text-gray-900 dark:text-white";
+/// Options of the badge itself, see [`crate::badge::BadgeOpts`]
+const BADGE_OPTS_HINT: &str = "Add label, unit, warn, crit, trailing, delta-warn, delta-crit, higher-is-better or sparkline=true to the query to customize the badge";
 const TEXT_INPUT_CLASS: &str = "shadow-sm bg-gray-50 border border-gray-300 text-gray-900 sm:text-sm rounded-lg focus:ring-primary-500 focus:border-primary-500 block w-full p-2.5 dark:bg-gray-700 dark:border-gray-600 dark:placeholder-gray-400 dark:text-white dark:focus:ring-primary-500 dark:focus:border-primary-500";
 
 pub fn page(title: &str, content: Markup) -> Markup {
@@ -64,6 +66,30 @@ pub fn page(title: &str, content: Markup) -> Markup {
     }
 }
 
+fn select_field<T>(id: &str, label: &str, options: &[(T, &str)], selected: T) -> Markup
+where
+    T: PartialEq + Copy,
+{
+    html! {
+        div class="col-span-6 sm:col-span-3" {
+            label
+                for=(id)
+                class=(LABEL_CLASS)
+                { (label) }
+
+            select
+                id=(id)
+                class=(TEXT_INPUT_CLASS)
+                name=(id)
+            {
+                @for &(value, name) in options {
+                    option value=(name) selected[value == selected] { (name) }
+                }
+            }
+        }
+    }
+}
+
 pub async fn render_chart_form(
     state: &SharedAppState,
     metric_id: MetricId,
@@ -114,7 +140,7 @@ pub async fn render_chart_form(
                 form
                     hx-get=(state.html_chart_url(metric_id))
                     hx-push-url="true"
-                    hx-trigger="change from:(form input) delay:0.5s, keyup delay:0.5s"
+                    hx-trigger="change from:(form input) delay:0.5s, change from:(form select) delay:0.5s, keyup delay:0.5s"
                     hx-target="find #svg-img"
                     hx-swap="outerHTML"
                     hx-select="#svg-img"
@@ -136,6 +162,12 @@ pub async fn render_chart_form(
                                     href=(format!("{}?{}", state.svg_chart_url(metric_id), params)) {
                                     "Export..."
                                 }
+                                a
+                                    class="hover:text-blue-600 p-2"
+                                    title=(BADGE_OPTS_HINT)
+                                    href=(format!("{}?{}", state.badge_url(metric_id), params)) {
+                                    "Badge..."
+                                }
                             }
                         }
 
@@ -244,6 +276,52 @@ pub async fn render_chart_form(
                                 type="text"
                                 value=(input_end_fixed_value);
                         }
+                        div class="col-span-6 sm:col-span-3" {
+                            label
+                                for="tz"
+                                class=(LABEL_CLASS)
+                                { "Time zone (UTC offset)" }
+
+                            input
+                                id="tz"
+                                class=(TEXT_INPUT_CLASS)
+                                name="tz"
+                                type="text"
+                                placeholder="+00:00"
+                                value=(opts.tz.map(|tz| tz.to_string()).unwrap_or_default());
+                        }
+                        (select_field("theme", "Theme", ChartTheme::ALL, opts.theme))
+                        (select_field("style", "Style", ChartStyle::ALL, opts.style))
+                        (select_field("y-scale", "Y Scale", YScale::ALL, opts.y_scale))
+                        (select_field("y-zoom", "Y Zoom (auto: p1-p99)", YZoom::ALL, opts.y_zoom))
+                        div class="col-span-6 sm:col-span-3" {
+                            label
+                                for="width"
+                                class=(LABEL_CLASS)
+                                { "Width" }
+
+                            input
+                                id="width"
+                                class=(TEXT_INPUT_CLASS)
+                                name="width"
+                                type="number"
+                                placeholder=(MetricOpts::DEFAULT_WIDTH)
+                                value=(opts.width.map(|w| w.to_string()).unwrap_or_default());
+                        }
+                        div class="col-span-6 sm:col-span-3" {
+                            label
+                                for="height"
+                                class=(LABEL_CLASS)
+                                { "Height" }
+
+                            input
+                                id="height"
+                                class=(TEXT_INPUT_CLASS)
+                                name="height"
+                                type="number"
+                                placeholder=(MetricOpts::DEFAULT_HEIGHT)
+                                value=(opts.height.map(|h| h.to_string()).unwrap_or_default());
+                        }
                     }
                 }
             },
@@ -1,25 +1,34 @@
 mod asset_cache;
-mod db;
+pub mod backup;
+mod badge;
+pub mod db;
+pub mod dump;
 mod fragment;
+mod line_protocol;
 pub mod models;
 pub mod opts;
+mod retention;
 mod routes;
+mod server_metrics;
 mod state;
+pub mod stats;
 
 use std::env;
 use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
 use std::os::fd::FromRawFd as _;
 use std::str::FromStr as _;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use axum::extract::{Request, State};
+use axum::extract::{MatchedPath, Request, State};
 use axum::http::header::{ACCEPT, CONTENT_TYPE};
 use axum::http::{HeaderName, Method};
 use axum::middleware::{self, Next};
 use axum::response::Response;
 use axum::Router;
+use color_eyre::eyre::format_err;
 use color_eyre::Result;
 use db::Database;
 use state::SharedAppState;
@@ -35,6 +44,9 @@ use tower_http::CompressionLevel;
 use tracing::{debug, info, warn};
 
 use crate::asset_cache::AssetCache;
+use crate::line_protocol::LineProtocolListener;
+use crate::retention::{run_retention_task, RetentionState};
+use crate::server_metrics::ServerMetrics;
 use crate::state::AppState;
 
 #[derive(Clone)]
@@ -64,9 +76,15 @@ impl KeyExtractor for OurKeyExtractor {
     }
 }
 
+/// Report what database migrations would do, without applying them
+pub async fn migrate_dry_run(db_path: &std::path::Path) -> Result<()> {
+    db::RedbStorage::migrate_dry_run(db_path).await
+}
+
 pub struct Server {
     opts: opts::Opts,
     listener: TcpListener,
+    line_protocol: Option<LineProtocolListener>,
     state: Arc<AppState>,
 }
 
@@ -75,20 +93,34 @@ impl Server {
         let (assets, listener, db) = tokio::try_join!(
             AssetCache::load_files(&opts.assets_dir),
             Self::get_listener(&opts),
-            Database::open(&opts.db),
+            async {
+                if opts.in_memory {
+                    Database::new_in_memory(opts.ingest_batching())
+                } else {
+                    Database::open(&opts.db, opts.ingest_batching()).await
+                }
+            },
         )?;
+        let server_metrics = ServerMetrics::new(&db, (!opts.in_memory).then(|| opts.db.clone()))?;
         let state = Arc::new(AppState {
             db,
             assets,
             req_counter: AtomicU64::default(),
+            retention: RetentionState::default(),
+            server_metrics,
         });
 
         if let Some(access_token) = opts.root_access_token {
             state.init_root_account(&access_token).await?;
         }
         info!("Listening on {}", listener.local_addr()?);
+        let line_protocol = match &opts.line_protocol_listen {
+            Some(addr) => Some(LineProtocolListener::bind(addr).await?),
+            None => None,
+        };
         Ok(Self {
             listener,
+            line_protocol,
             opts,
             state,
         })
@@ -151,11 +183,21 @@ impl Server {
         std::thread::spawn(move || loop {
             std::thread::sleep(interval);
             governor_limiter.retain_recent();
-            if 0 < governor_limiter.len() {
+            if !governor_limiter.is_empty() {
                 debug!("Rate limiting storage size: {}", governor_limiter.len());
             }
         });
 
+        tokio::spawn(run_retention_task(
+            self.state.clone(),
+            Duration::from_secs(self.opts.retention_interval_secs),
+            Duration::from_secs(self.opts.idempotency_key_ttl_secs),
+        ));
+
+        if let Some(line_protocol) = self.line_protocol {
+            tokio::spawn(line_protocol.run(self.state.clone(), line_protocol_quota(&self.opts)?));
+        }
+
         let router = Router::new()
             .merge(routes::route_handler(self.state.clone()))
             .nest("/assets", routes::static_file_handler(self.state.clone()));
@@ -175,6 +217,10 @@ impl Server {
                     state.clone(),
                     update_req_count,
                 ))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    observe_request,
+                ))
                 .into_make_service_with_connect_info::<SocketAddr>(),
         )
         .with_graceful_shutdown(async move {
@@ -192,6 +238,14 @@ impl Server {
         Ok(self.listener.local_addr()?)
     }
 
+    /// Address of the line protocol listener, if enabled
+    pub fn line_protocol_addr(&self) -> Result<Option<SocketAddr>> {
+        self.line_protocol
+            .as_ref()
+            .map(LineProtocolListener::addr)
+            .transpose()
+    }
+
     pub async fn wait_idle(state: Arc<AppState>) {
         debug!("Will shutdown on idle");
         let mut prev = state.req_counter.load(Ordering::Relaxed);
@@ -214,6 +268,17 @@ impl Server {
     }
 }
 
+/// Lines of the line protocol a peer can send, same as http requests
+fn line_protocol_quota(opts: &opts::Opts) -> Result<governor::Quota> {
+    let burst = NonZeroU32::new(opts.rate_limit_burst)
+        .ok_or_else(|| format_err!("Rate limit burst size can't be 0"))?;
+    Ok(
+        governor::Quota::with_period(Duration::from_millis(opts.rate_limit_replenish_millis))
+            .ok_or_else(|| format_err!("Rate limit replenish interval can't be 0"))?
+            .allow_burst(burst),
+    )
+}
+
 fn compression_layer() -> CompressionLayer<SizeAbove> {
     CompressionLayer::new()
         .quality(CompressionLevel::Precise(4))
@@ -242,6 +307,23 @@ async fn update_req_count(state: State<SharedAppState>, request: Request, next:
     next.run(request).await
 }
 
+async fn observe_request(state: State<SharedAppState>, request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".into());
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    state
+        .server_metrics
+        .observe_request(&method, &route, response.status(), start.elapsed());
+    response
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
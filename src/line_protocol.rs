@@ -0,0 +1,428 @@
+//! Ingesting data points in the InfluxDB line protocol, over UDP and TCP
+//!
+//! Many existing tools (Telegraf, most metrics libraries) can emit it, so
+//! they can feed perfit without going through `perfit post`. Every line is
+//! one data point:
+//!
+//! ```text
+//! <metric-id>,token=<post-access-token>[,<tag>=<value>...] value=<number> [<timestamp>]
+//! ```
+//!
+//! The measurement must be the id of the metric. Metric aliases (and names
+//! in general) only exist in the `perfit` configuration, so they can't be used
+//! here. The remaining tags become the metadata of the data point (`tag=value`,
+//! separated with commas). The timestamp is in nanoseconds, and like with HTTP
+//! posts, the time of the data point is when perfitd received it if there's
+//! none.
+//!
+//! Nothing is sent back, so rejected and malformed lines are only counted in
+//! the server metrics.
+//!
+//! Lines are rate limited per peer ip, like http requests, and only a bounded
+//! number of datagrams and connections are handled at once. Idle connections
+//! get closed, so they can't hold on to them.
+//!
+//! The source address of UDP datagrams isn't verified, so a sender spoofing
+//! many addresses gets around the per-peer limit, and only the bound on
+//! datagrams in flight applies. Each line still needs a valid access token to
+//! be stored, but only expose the UDP listener to trusted networks.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr as _;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{bail, format_err};
+use color_eyre::Result;
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use time::OffsetDateTime;
+use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+use crate::db::{DataPointMetadata, DataPointRecord};
+use crate::models::access_token::AccessToken;
+use crate::models::ts::Ts;
+use crate::models::MetricId;
+use crate::routes::error::UserRequestError;
+use crate::routes::metric::data_point_post;
+use crate::state::SharedAppState;
+
+/// Max size of a UDP datagram
+const MAX_DATAGRAM_LEN: usize = 65536;
+
+/// Max length of a line received over TCP, longer ones close the connection
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Max number of datagrams handled at once, more are dropped
+const MAX_DATAGRAMS_IN_FLIGHT: usize = 1024;
+
+/// Max number of TCP connections handled at once, more wait to be accepted
+const MAX_CONNECTIONS: usize = 1024;
+
+/// Max time to wait for the next line of a TCP connection, before closing it
+const LINE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait after failing to receive or accept, which is usually
+/// something like running out of file descriptors
+const RECEIVE_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// How often to forget peers that stopped sending
+const RATE_LIMIT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What happened to a single line, as counted in the server metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineOutcome {
+    Accepted,
+    /// Could not be parsed, or doesn't fit perfit's data model
+    Malformed,
+    /// Parsed fine, but refused: wrong token, unknown metric, etc.
+    Rejected,
+    /// Dropped without looking at it, as the peer sent too many, or too many
+    /// datagrams were waiting to be handled
+    Limited,
+}
+
+impl LineOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineOutcome::Accepted => "accepted",
+            LineOutcome::Malformed => "malformed",
+            LineOutcome::Rejected => "rejected",
+            LineOutcome::Limited => "limited",
+        }
+    }
+}
+
+/// A line, already mapped to perfit terms
+#[derive(Debug)]
+struct DataPointLine {
+    metric_id: MetricId,
+    access_token: AccessToken,
+    record: DataPointRecord,
+    ts: Option<OffsetDateTime>,
+}
+
+/// Split `s` on `sep`, except where escaped with a backslash or (if
+/// `quotes`) inside a double-quoted string
+fn split_unescaped(s: &str, sep: char, quotes: bool) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut escaped = false;
+    let mut in_quotes = false;
+
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if quotes && c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == sep && !in_quotes {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Remove backslashes escaping the special characters of the line protocol
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if matches!(next, ',' | ' ' | '=' | '"' | '\\') {
+                    out.push(next);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn split_key_value(s: &str) -> Result<(String, &str)> {
+    match split_unescaped(s, '=', false).as_slice() {
+        [key, value] if !key.is_empty() => Ok((unescape(key), value)),
+        _ => bail!("Invalid key-value pair: {s}"),
+    }
+}
+
+/// Parse a numeric field value, as perfit stores only numbers
+fn parse_field_value(s: &str) -> Result<f32> {
+    let s = s
+        .strip_suffix('i')
+        .or_else(|| s.strip_suffix('u'))
+        .unwrap_or(s);
+    let value = f64::from_str(s).map_err(|_| format_err!("Not a numeric value: {s}"))?;
+    if !value.is_finite() {
+        bail!("Not a finite value: {s}");
+    }
+    Ok(value as f32)
+}
+
+fn parse_line(line: &str) -> Result<DataPointLine> {
+    let sections = split_unescaped(line, ' ', true);
+    let (series, fields) = match sections.as_slice() {
+        [series, fields] | [series, fields, _] => (series, fields),
+        _ => bail!("Expected measurement, fields and an optional timestamp"),
+    };
+    let ts = sections
+        .get(2)
+        .map(|timestamp| {
+            i128::from_str(timestamp)
+                .ok()
+                .and_then(|nanos| OffsetDateTime::from_unix_timestamp_nanos(nanos).ok())
+                .ok_or_else(|| format_err!("Invalid timestamp: {timestamp}"))
+        })
+        .transpose()?;
+
+    let mut series = split_unescaped(series, ',', false).into_iter();
+    let metric_id = unescape(series.next().unwrap_or_default());
+    let metric_id = MetricId::from_str(&metric_id)
+        .map_err(|_| format_err!("Invalid metric id (aliases can't be used): {metric_id}"))?;
+
+    let mut access_token = None;
+    let mut metadata = vec![];
+    for tag in series {
+        let (key, value) = split_key_value(tag)?;
+        let value = unescape(value);
+        if key == "token" {
+            access_token = Some(
+                AccessToken::from_str(&value).map_err(|_| format_err!("Invalid access token"))?,
+            );
+        } else {
+            metadata.push(format!("{key}={value}"));
+        }
+    }
+    let access_token = access_token.ok_or_else(|| format_err!("Missing `token` tag"))?;
+
+    let mut value = None;
+    for field in split_unescaped(fields, ',', true) {
+        let (key, field_value) = split_key_value(field)?;
+        if key == "value" {
+            value = Some(parse_field_value(field_value)?);
+        }
+    }
+    let value = value.ok_or_else(|| format_err!("Missing `value` field"))?;
+
+    Ok(DataPointLine {
+        metric_id,
+        access_token,
+        record: DataPointRecord {
+            value: value.into(),
+            metadata: DataPointMetadata::try_new(metadata.join(","))?,
+            outcome: Default::default(),
+        },
+        ts,
+    })
+}
+
+async fn handle_line(state: &SharedAppState, line: &str) -> LineOutcome {
+    let DataPointLine {
+        metric_id,
+        access_token,
+        record,
+        ts,
+    } = match parse_line(line) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            debug!(%err, "Malformed line");
+            return LineOutcome::Malformed;
+        }
+    };
+
+    let res = async {
+        state
+            .db
+            .read_with(move |db| {
+                let token = db
+                    .access_token_get(access_token)?
+                    .ok_or(UserRequestError::InvalidAuthorizationToken)?;
+                let metric = db
+                    .metric_get(metric_id)?
+                    .ok_or(UserRequestError::MetricNotFound(metric_id))?;
+                token.ensure_can_post(metric.account_id)
+            })
+            .await?;
+        let ts = match ts {
+            Some(ts) => Ts::from_client(ts).ok_or(UserRequestError::InvalidTimestamp)?,
+            None => Ts::now(),
+        };
+        data_point_post(state, metric_id, ts, record, None).await
+    }
+    .await;
+
+    match res {
+        Ok(_) => LineOutcome::Accepted,
+        Err(err) => {
+            debug!(%err, "Rejected line");
+            LineOutcome::Rejected
+        }
+    }
+}
+
+async fn handle_lines<'a>(
+    state: &SharedAppState,
+    peer_limiter: &PeerRateLimiter,
+    peer: IpAddr,
+    lines: impl Iterator<Item = &'a str>,
+) {
+    for line in lines {
+        let line = line.trim();
+        // Blank lines and comments
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let outcome = if peer_limiter.check_key(&peer).is_ok() {
+            handle_line(state, line).await
+        } else {
+            LineOutcome::Limited
+        };
+        state.server_metrics.observe_line_protocol_line(outcome);
+    }
+}
+
+type PeerRateLimiter = DefaultKeyedRateLimiter<IpAddr>;
+
+/// Sockets receiving the line protocol, bound to the same address
+pub struct LineProtocolListener {
+    tcp: TcpListener,
+    udp: UdpSocket,
+}
+
+impl LineProtocolListener {
+    pub async fn bind(addr: &str) -> Result<Self> {
+        let tcp = TcpListener::bind(addr).await?;
+        // With port `0`, use the same port the TCP listener got
+        let udp = UdpSocket::bind(tcp.local_addr()?).await?;
+        info!("Line protocol listening on {}", tcp.local_addr()?);
+        Ok(Self { tcp, udp })
+    }
+
+    pub fn addr(&self) -> Result<SocketAddr> {
+        Ok(self.tcp.local_addr()?)
+    }
+
+    /// Handle incoming lines, with `quota` of lines per peer ip
+    pub async fn run(self, state: SharedAppState, quota: Quota) {
+        let peer_limiter = Arc::new(RateLimiter::keyed(quota));
+        tokio::spawn({
+            let peer_limiter = Arc::downgrade(&peer_limiter);
+            async move {
+                let mut interval = tokio::time::interval(RATE_LIMIT_CLEANUP_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let Some(peer_limiter) = peer_limiter.upgrade() else {
+                        return;
+                    };
+                    peer_limiter.retain_recent();
+                }
+            }
+        });
+        tokio::join!(
+            run_udp(self.udp, state.clone(), peer_limiter.clone()),
+            run_tcp(self.tcp, state, peer_limiter)
+        );
+    }
+}
+
+async fn run_udp(socket: UdpSocket, state: SharedAppState, peer_limiter: Arc<PeerRateLimiter>) {
+    let in_flight = Arc::new(Semaphore::new(MAX_DATAGRAMS_IN_FLIGHT));
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                warn!(%err, "Failed to receive line protocol datagram");
+                tokio::time::sleep(RECEIVE_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let datagram = String::from_utf8_lossy(&buf[..len]).into_owned();
+        let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+            debug!(%peer, "Too many line protocol datagrams in flight, dropping");
+            for _ in datagram.lines().filter(|line| !line.trim().is_empty()) {
+                state
+                    .server_metrics
+                    .observe_line_protocol_line(LineOutcome::Limited);
+            }
+            continue;
+        };
+        let state = state.clone();
+        let peer_limiter = peer_limiter.clone();
+        tokio::spawn(async move {
+            handle_lines(&state, &peer_limiter, peer.ip(), datagram.lines()).await;
+            drop(permit);
+        });
+    }
+}
+
+async fn run_tcp(listener: TcpListener, state: SharedAppState, peer_limiter: Arc<PeerRateLimiter>) {
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let permit = connections
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Never closed");
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(%err, "Failed to accept line protocol connection");
+                tokio::time::sleep(RECEIVE_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let state = state.clone();
+        let peer_limiter = peer_limiter.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_tcp_connection(stream, peer.ip(), &state, &peer_limiter).await
+            {
+                warn!(%err, %peer, "Line protocol connection failed");
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn handle_tcp_connection(
+    stream: TcpStream,
+    peer: IpAddr,
+    state: &SharedAppState,
+    peer_limiter: &PeerRateLimiter,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        // One more than allowed, to tell a line of max length from a longer one
+        let mut limited = (&mut reader).take(MAX_LINE_LEN as u64 + 1);
+        let len = tokio::time::timeout(LINE_TIMEOUT, limited.read_until(b'\n', &mut line))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No line received in time"))??;
+        if len == 0 {
+            return Ok(());
+        }
+        if MAX_LINE_LEN < line.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Line longer than {MAX_LINE_LEN} bytes"),
+            ));
+        }
+        handle_lines(
+            state,
+            peer_limiter,
+            peer,
+            std::iter::once(String::from_utf8_lossy(&line).as_ref()),
+        )
+        .await;
+    }
+}
@@ -1,5 +1,6 @@
 use clap::Parser;
 use color_eyre::Result;
+use perfitd::opts::Command;
 use tracing::info;
 
 #[tokio::main]
@@ -8,7 +9,25 @@ async fn main() -> Result<()> {
     info!(version = env!("CARGO_PKG_VERSION"), "Starting perfitd");
 
     let opts = perfitd::opts::Opts::parse();
-    perfitd::Server::init(opts).await?.run().await
+    match opts.cmd {
+        None if opts.migrate_dry_run => perfitd::migrate_dry_run(&opts.db).await,
+        None => perfitd::Server::init(opts).await?.run().await,
+        Some(Command::Backup { ref path }) => perfitd::backup::backup(&opts.db, path).await,
+        Some(Command::Restore { ref path }) => perfitd::backup::restore(&opts.db, path).await,
+        Some(Command::Dump {
+            path: Some(ref path),
+        }) => perfitd::dump::dump(&opts.db, std::fs::File::create_new(path)?).await,
+        Some(Command::Dump { path: None }) => {
+            perfitd::dump::dump(&opts.db, std::io::stdout()).await
+        }
+        Some(Command::Load { ref path }) => {
+            perfitd::dump::load(
+                &opts.db,
+                std::io::BufReader::new(std::fs::File::open(path)?),
+            )
+            .await
+        }
+    }
 }
 
 fn install_tracing() {
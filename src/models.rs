@@ -12,7 +12,7 @@ pub mod ts;
 
 macro_rules! define_uuidv4_newtype {
     ($name:ident) => {
-        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
         pub struct $name(Uuid);
 
         impl $name {
@@ -45,6 +45,17 @@ macro_rules! define_uuidv4_newtype {
             }
         }
 
+        impl FromStr for $name {
+            type Err = color_eyre::eyre::Report;
+
+            fn from_str(s: &str) -> Result<Self> {
+                use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+                use base64::prelude::*;
+                let bytes = URL_SAFE_NO_PAD.decode(s)?;
+                Ok(Self(Uuid::from_slice(&bytes)?))
+            }
+        }
+
         impl bincode::Encode for $name {
             fn encode<E: bincode::enc::Encoder>(
                 &self,
@@ -115,7 +126,20 @@ macro_rules! define_uuidv4_newtype {
 
 define_uuidv4_newtype!(MetricId);
 
-#[derive(Debug, Encode, Decode, Default, Clone, Copy)]
+#[derive(
+    Debug,
+    Encode,
+    Decode,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
 pub struct MetricInternalId(u64);
 
 impl MetricInternalId {
@@ -126,6 +150,8 @@ impl MetricInternalId {
 
 define_uuidv4_newtype!(AccountId);
 
+define_uuidv4_newtype!(AnnotationId);
+
 #[derive(Debug, Encode, Decode, Clone, Copy, Deserialize, PartialEq, Eq, Serialize)]
 pub enum AccessTokenType {
     Root,
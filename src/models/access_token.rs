@@ -9,7 +9,7 @@ use rand::RngCore as _;
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Encode, Decode, Debug, Clone, Copy)]
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct AccessToken([u8; 32]);
 
 impl AccessToken {
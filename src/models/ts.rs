@@ -1,16 +1,33 @@
+use std::fmt;
 use std::ops::{self, Add};
+use std::str::FromStr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bincode::{Decode, Encode};
+use color_eyre::eyre::bail;
 use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use time::{OffsetDateTime, UtcOffset};
 
-#[derive(Encode, Decode, Serialize, Deserialize, Debug, Copy, Clone, Default)]
+#[derive(
+    Encode,
+    Decode,
+    Serialize,
+    Deserialize,
+    Debug,
+    Copy,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
 pub struct Ts(u64);
 
 impl From<time::OffsetDateTime> for Ts {
+    /// Times before the epoch become [`Ts::ZERO`]
     fn from(value: time::OffsetDateTime) -> Self {
-        Ts(value.unix_timestamp() as u64)
+        Ts(u64::try_from(value.unix_timestamp()).unwrap_or_default())
     }
 }
 
@@ -39,6 +56,12 @@ impl ops::Sub for Ts {
 
 impl Ts {
     pub const ZERO: Self = Ts(0);
+    pub const MAX: Self = Ts(u64::MAX);
+    const DAY_SECS: u64 = 24 * 60 * 60;
+    /// How far in the future a time sent by a client can be, to tolerate
+    /// clocks that are a bit off
+    const MAX_CLOCK_SKEW_SECS: u64 = 10 * 60;
+
     pub fn now() -> Self {
         Self(
             std::time::SystemTime::now()
@@ -57,43 +80,230 @@ impl Ts {
             .expect("can't fail")
     }
 
-    pub fn inc(self) -> Ts {
-        Self(self.0 + 1)
+    /// Convert a time sent by a client
+    ///
+    /// Returns `None` if it's before the epoch, or in the future.
+    pub fn from_client(value: time::OffsetDateTime) -> Option<Ts> {
+        let secs = u64::try_from(value.unix_timestamp()).ok()?;
+        (secs <= Self::now().0 + Self::MAX_CLOCK_SKEW_SECS).then_some(Self(secs))
+    }
+
+    /// Start of the UTC day
+    pub fn day_start(self) -> Ts {
+        Self(self.0 - self.0 % Self::DAY_SECS)
+    }
+
+    /// Start of the next UTC day
+    pub fn next_day_start(self) -> Ts {
+        Self(self.day_start().0 + Self::DAY_SECS)
+    }
+
+    pub fn saturating_sub_days(self, days: u32) -> Ts {
+        Self(self.0.saturating_sub(u64::from(days) * Self::DAY_SECS))
+    }
+
+    pub fn saturating_sub_secs(self, secs: u64) -> Ts {
+        Self(self.0.saturating_sub(secs))
     }
 }
 
-pub trait DateTimeExt {
-    fn round_down_to_hour(self) -> Self;
-    fn round_up_to_hour(self) -> Self;
-    fn round_up_exclusive_to_hour(self) -> Self;
+/// Step between two consecutive time axis ticks
+///
+/// Steps are calendar-aware: days start at midnight, weeks on Monday, months
+/// on the first day of the month.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeStep {
+    Minutes(u8),
+    Hours(u8),
+    Days(u8),
+    Weeks(u8),
+    Months(u8),
+}
+
+impl TimeStep {
+    const CANDIDATES: &'static [Self] = &[
+        Self::Minutes(1),
+        Self::Minutes(2),
+        Self::Minutes(5),
+        Self::Minutes(10),
+        Self::Minutes(15),
+        Self::Minutes(30),
+        Self::Hours(1),
+        Self::Hours(2),
+        Self::Hours(3),
+        Self::Hours(6),
+        Self::Hours(12),
+        Self::Days(1),
+        Self::Days(2),
+        Self::Weeks(1),
+        Self::Weeks(2),
+        Self::Months(1),
+        Self::Months(2),
+        Self::Months(3),
+        Self::Months(6),
+        Self::Months(12),
+    ];
+
+    /// Approximate length of the step (months are 30 days)
+    pub fn approx_secs(self) -> u64 {
+        const MINUTE: u64 = 60;
+        const HOUR: u64 = 60 * MINUTE;
+        const DAY: u64 = 24 * HOUR;
+
+        match self {
+            TimeStep::Minutes(n) => u64::from(n) * MINUTE,
+            TimeStep::Hours(n) => u64::from(n) * HOUR,
+            TimeStep::Days(n) => u64::from(n) * DAY,
+            TimeStep::Weeks(n) => u64::from(n) * 7 * DAY,
+            TimeStep::Months(n) => u64::from(n) * 30 * DAY,
+        }
+    }
+
+    /// Smallest step that splits `range_secs` into at most `max_ticks`
+    pub fn for_range(range_secs: u64, max_ticks: u64) -> Self {
+        let max_ticks = max_ticks.max(1);
+        Self::CANDIDATES
+            .iter()
+            .copied()
+            .find(|step| range_secs / step.approx_secs() < max_ticks)
+            .unwrap_or_else(|| {
+                let years = range_secs / Self::Months(12).approx_secs() / max_ticks + 1;
+                Self::Months(u8::try_from(years * 12).unwrap_or(u8::MAX / 12 * 12))
+            })
+    }
+
+    pub fn round_down(self, dt: OffsetDateTime) -> OffsetDateTime {
+        let (h, m, _s) = dt.to_hms();
+        let date = dt.date();
+        let midnight = time::Time::MIDNIGHT;
 
-    fn our_fmt(self) -> String;
+        match self {
+            TimeStep::Minutes(n) => {
+                dt.replace_time(time::Time::from_hms(h, m - m % n, 0).expect("Can't fail"))
+            }
+            TimeStep::Hours(n) => {
+                dt.replace_time(time::Time::from_hms(h - h % n, 0, 0).expect("Can't fail"))
+            }
+            TimeStep::Days(n) => {
+                let day = date.to_julian_day();
+                let date = time::Date::from_julian_day(day - day.rem_euclid(i32::from(n)))
+                    .expect("Can't fail");
+                dt.replace_date_time(date.with_time(midnight))
+            }
+            TimeStep::Weeks(n) => {
+                // Julian day 0 was a Monday
+                let day = date.to_julian_day();
+                let date = time::Date::from_julian_day(day - day.rem_euclid(7 * i32::from(n)))
+                    .expect("Can't fail");
+                dt.replace_date_time(date.with_time(midnight))
+            }
+            TimeStep::Months(n) => {
+                let month0 = u8::from(date.month()) - 1;
+                let month =
+                    time::Month::try_from(month0 - month0 % n.min(12) + 1).expect("Can't fail");
+                let mut year = date.year();
+                if 12 < n {
+                    let n_years = i32::from(n / 12);
+                    year -= year.rem_euclid(n_years);
+                }
+                let date = time::Date::from_calendar_date(year, month, 1).expect("Can't fail");
+                dt.replace_date_time(date.with_time(midnight))
+            }
+        }
+    }
+
+    /// Next tick after `dt`, which should already be rounded with
+    /// [`Self::round_down`]
+    pub fn next(self, dt: OffsetDateTime) -> OffsetDateTime {
+        match self {
+            TimeStep::Months(n) => {
+                let month0 = u32::from(u8::from(dt.month())) - 1 + u32::from(n);
+                let year = dt.year() + (month0 / 12) as i32;
+                let month = time::Month::try_from((month0 % 12) as u8 + 1).expect("Can't fail");
+                let date = time::Date::from_calendar_date(year, month, 1).expect("Can't fail");
+                dt.replace_date(date)
+            }
+            _ => dt.add(Duration::from_secs(self.approx_secs())),
+        }
+    }
+
+    /// Format a tick label, with precision appropriate for the step
+    pub fn format(self, dt: OffsetDateTime) -> String {
+        use time::format_description::FormatItem;
+        use time::macros::format_description;
+
+        const MINUTES: &[FormatItem<'static>] =
+            format_description!("[month]-[day] [hour]:[minute]");
+        const DAYS: &[FormatItem<'static>] = format_description!("[year]-[month]-[day]");
+        const MONTHS: &[FormatItem<'static>] = format_description!("[year]-[month]");
+        const YEARS: &[FormatItem<'static>] = format_description!("[year]");
+
+        let format = match self {
+            TimeStep::Minutes(_) | TimeStep::Hours(_) => MINUTES,
+            TimeStep::Days(_) | TimeStep::Weeks(_) => DAYS,
+            TimeStep::Months(n) if n % 12 == 0 => YEARS,
+            TimeStep::Months(_) => MONTHS,
+        };
+
+        dt.format(format).expect("Can't fail")
+    }
 }
 
-impl DateTimeExt for time::OffsetDateTime {
-    fn our_fmt(self) -> String {
-        format!(
-            "{}T{:02}:{:02}:{:02}Z",
-            self.date(),
-            self.hour(),
-            self.minute(),
-            self.second()
-        )
+/// Fixed UTC offset to display times in
+///
+/// Parsed from `[UTC][+|-]HH[:MM]`, e.g. `+02:00`, `UTC-5` or `Z`. A missing
+/// sign means a positive offset, so that `+` lost to url-decoding as a space
+/// is handled correctly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TzOffset(pub UtcOffset);
+
+impl fmt::Display for TzOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (h, m, _s) = self.0.as_hms();
+        let sign = if self.0.is_negative() { '-' } else { '+' };
+        write!(f, "{sign}{:02}:{:02}", h.abs(), m.abs())
     }
-    fn round_down_to_hour(self) -> Self {
-        let (h, _m, _s) = self.to_hms();
+}
 
-        time::OffsetDateTime::new_utc(
-            self.date(),
-            time::Time::from_hms(h, 0, 0).expect("Can't fail"),
-        )
+impl FromStr for TzOffset {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix("UTC").unwrap_or(s);
+        if s.is_empty() || s == "Z" {
+            return Ok(Self(UtcOffset::UTC));
+        }
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (h, m) = s.split_once(':').unwrap_or((s, "0"));
+        let (h, m): (i8, i8) = (h.parse()?, m.parse()?);
+        if 59 < m {
+            bail!("Invalid minutes: {m}");
+        }
+        let (h, m) = if negative { (-h, -m) } else { (h, m) };
+
+        Ok(Self(UtcOffset::from_hms(h, m, 0)?))
     }
+}
 
-    fn round_up_to_hour(self) -> Self {
-        self.add(Duration::from_secs(60 * 60 - 1))
-            .round_down_to_hour()
+impl Serialize for TzOffset {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
     }
-    fn round_up_exclusive_to_hour(self) -> Self {
-        self.add(Duration::from_secs(60 * 60)).round_down_to_hour()
+}
+
+impl<'de> Deserialize<'de> for TzOffset {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
     }
 }
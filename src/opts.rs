@@ -1,11 +1,13 @@
 use std::ffi::OsString;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use axum::http::HeaderValue;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use color_eyre::Result;
 use tracing::instrument;
 
+use crate::db::IngestBatching;
 use crate::models::access_token::AccessToken;
 
 fn default_perfit_assets_dir() -> OsString {
@@ -17,6 +19,9 @@ fn default_perfit_assets_dir() -> OsString {
 #[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Opts {
+    #[command(subcommand)]
+    pub cmd: Option<Command>,
+
     /// Listen address
     #[arg(long, short, default_value = "[::1]:5050", env = "PERFITD_LISTEN")]
     pub listen: String,
@@ -33,6 +38,17 @@ pub struct Opts {
     #[arg(long, default_value = "perfitd.redb", env = "PERFITD_DB_PATH")]
     pub db: PathBuf,
 
+    /// Keep all the data in memory only, instead of the database file
+    ///
+    /// Everything is lost on exit, so mostly useful for testing.
+    #[arg(long, env = "PERFITD_IN_MEMORY")]
+    pub in_memory: bool,
+
+    /// Report what database migrations would do, without applying them, and
+    /// exit
+    #[arg(long)]
+    pub migrate_dry_run: bool,
+
     /// Cors origin settings
     #[arg(long, env = "PERFITD_CORS_ORIGIN")]
     pub cors_origin: Option<String>,
@@ -63,11 +79,75 @@ pub struct Opts {
     /// Rate limit burst size
     #[arg(long, default_value = "60", env = "PERFITD_RATE_LIMIT_BURST")]
     pub rate_limit_burst: u32,
+
+    /// Enforce data retention policies every N seconds
+    #[arg(
+        long,
+        default_value = "3600",
+        env = "PERFITD_RETENTION_INTERVAL_SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub retention_interval_secs: u64,
+
+    /// Remember idempotency keys of posted data points for at least N
+    /// seconds
+    #[arg(
+        long,
+        default_value = "86400",
+        env = "PERFITD_IDEMPOTENCY_KEY_TTL_SECS"
+    )]
+    pub idempotency_key_ttl_secs: u64,
+
+    /// Commit posted data points in batches, every N milliseconds, instead of
+    /// as soon as possible
+    ///
+    /// Posts are acknowledged only after their batch is committed, so this
+    /// trades latency for throughput without risking acknowledged data.
+    #[arg(long, env = "PERFITD_INGEST_BATCH_MILLIS")]
+    pub ingest_batch_millis: Option<u64>,
+
+    /// With `--ingest-batch-millis`, commit a batch early once it has that many
+    /// data points
+    #[arg(long, default_value = "1000", env = "PERFITD_INGEST_BATCH_POINTS")]
+    pub ingest_batch_points: usize,
+
+    /// Also accept data points in the InfluxDB line protocol, over both UDP
+    /// and TCP on this address
+    #[arg(long, env = "PERFITD_LINE_PROTOCOL_LISTEN")]
+    pub line_protocol_listen: Option<String>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Write a consistent snapshot of the database to a new file
+    ///
+    /// Requires exclusive access to the database; use the `/backup` endpoint
+    /// to backup a running instance.
+    Backup { path: PathBuf },
+
+    /// Verify a backup and replace the database with it
+    ///
+    /// perfitd must not be running.
+    Restore { path: PathBuf },
+
+    /// Write all the data as a portable, line-delimited JSON dump
+    ///
+    /// Requires exclusive access to the database.
+    Dump {
+        /// Output file, stdout if not set
+        path: Option<PathBuf>,
+    },
+
+    /// Create a new database from a dump
+    Load { path: PathBuf },
 }
 
 impl Default for Opts {
     fn default() -> Self {
         Self {
+            cmd: None,
+            migrate_dry_run: false,
+            in_memory: false,
             listen: "[::1]:3000".into(),
             db: "db.redb".into(),
             cors_origin: None,
@@ -78,11 +158,23 @@ impl Default for Opts {
             reuseport: false,
             shutdown_on_idle: Default::default(),
             rate_limit_peer_ip: false,
+            retention_interval_secs: 3600,
+            idempotency_key_ttl_secs: 86400,
+            ingest_batch_millis: None,
+            ingest_batch_points: 1000,
+            line_protocol_listen: None,
         }
     }
 }
 
 impl Opts {
+    pub fn ingest_batching(&self) -> Option<IngestBatching> {
+        self.ingest_batch_millis.map(|millis| IngestBatching {
+            max_delay: Duration::from_millis(millis),
+            max_points: self.ingest_batch_points,
+        })
+    }
+
     #[instrument]
     pub fn cors_origin(&self) -> Result<HeaderValue> {
         Ok(self
@@ -0,0 +1,508 @@
+//! Enforcing data retention policies
+//!
+//! A background task periodically goes over all metrics with a retention
+//! policy (their own, or the default of their account) and deletes or
+//! downsamples data points older than the policy allows. All the work is done
+//! in write transactions of at most [`BATCH_SIZE`] data points, so ingestion
+//! is never blocked for long.
+//!
+//! Aggregated days can't be aggregated again with data points that arrived
+//! later, so data points can't be posted with a time before the cutoff of the
+//! policy (see [`ensure_within_retention`]).
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use color_eyre::Result;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::db::{
+    DataPoint, DataPointRecord, MetricRecord, RetentionAction, RetentionPolicy, Scan, Storage,
+};
+use crate::models::ts::Ts;
+use crate::models::{MetricId, MetricInternalId};
+use crate::routes::error::UserRequestError;
+use crate::state::SharedAppState;
+use crate::stats;
+
+/// Max number of data points touched in a single write transaction
+const BATCH_SIZE: usize = 1000;
+
+/// Cumulative statistics of retention enforcement since the start
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetentionStats {
+    /// Number of completed enforcement runs
+    pub runs: u64,
+    /// Start time of the last completed run
+    pub last_run: Option<Ts>,
+    /// Data points deleted
+    pub deleted: u64,
+    /// Days of data points replaced with their daily aggregate
+    pub aggregated_days: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct RetentionState {
+    stats: Mutex<RetentionStats>,
+    aggregation_progress: Mutex<AggregationProgress>,
+}
+
+/// How far daily aggregation got for each metric, so the next run doesn't
+/// have to go over all the already aggregated days again
+///
+/// It's only valid as long as no data points can be posted before it, so it's
+/// forgotten whenever retention policies change. It's not persisted, so the
+/// first run after a restart starts from the beginning.
+#[derive(Debug, Default)]
+struct AggregationProgress {
+    /// Bumped whenever the progress is forgotten, so runs that started before
+    /// don't record theirs
+    generation: u64,
+    aggregated_until: BTreeMap<MetricInternalId, Ts>,
+}
+
+impl RetentionState {
+    pub fn stats(&self) -> RetentionStats {
+        self.stats.lock().expect("locking failed").clone()
+    }
+
+    /// Forget how far daily aggregation got, as data points might now be
+    /// posted in days considered done
+    pub fn policies_changed(&self) {
+        let mut progress = self.aggregation_progress.lock().expect("locking failed");
+        progress.generation += 1;
+        progress.aggregated_until.clear();
+    }
+
+    /// Generation of the progress, and where to start aggregating a metric
+    fn aggregation_start(&self, metric_internal_id: MetricInternalId) -> (u64, Ts) {
+        let progress = self.aggregation_progress.lock().expect("locking failed");
+        (
+            progress.generation,
+            progress
+                .aggregated_until
+                .get(&metric_internal_id)
+                .copied()
+                .unwrap_or(Ts::ZERO),
+        )
+    }
+
+    fn aggregated_until_set(&self, generation: u64, metric_internal_id: MetricInternalId, ts: Ts) {
+        let mut progress = self.aggregation_progress.lock().expect("locking failed");
+        if progress.generation == generation {
+            progress.aggregated_until.insert(metric_internal_id, ts);
+        }
+    }
+}
+
+/// Enforce retention policies every `interval`, forever
+///
+/// Idempotency keys older than `idempotency_key_ttl` are forgotten along the
+/// way.
+pub async fn run_retention_task(
+    state: SharedAppState,
+    interval: Duration,
+    idempotency_key_ttl: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        if let Err(err) = enforce_retention(&state).await {
+            warn!(%err, "Retention enforcement failed");
+        }
+        if let Err(err) = prune_idempotency_keys(&state, idempotency_key_ttl).await {
+            warn!(%err, "Pruning idempotency keys failed");
+        }
+    }
+}
+
+async fn prune_idempotency_keys(state: &SharedAppState, ttl: Duration) -> Result<()> {
+    let before = Ts::now().saturating_sub_secs(ttl.as_secs());
+    let mut total = 0;
+    loop {
+        let pruned = state
+            .db
+            .write_with(move |db| db.idempotency_keys_prune(before, BATCH_SIZE))
+            .await?;
+        total += pruned;
+        if pruned < BATCH_SIZE {
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+    if total != 0 {
+        debug!(total, "Pruned idempotency keys");
+    }
+    Ok(())
+}
+
+/// Policy of a metric: its own, or the default of its account
+fn metric_policy(
+    db: &dyn Storage,
+    metric_id: MetricId,
+    metric_record: &MetricRecord,
+) -> Result<Option<RetentionPolicy>> {
+    match db.metric_retention_get(metric_id)? {
+        Some(policy) => Ok(Some(policy)),
+        None => db.account_retention_get(metric_record.account_id),
+    }
+}
+
+/// Fail if a data point at `ts` would already be subject to the retention
+/// policy of the metric, as returned by `policy`
+///
+/// Called by [`Storage::data_points_append`] implementations, in the same
+/// transaction as the append, so the policy can't change in between.
+pub fn ensure_within_retention(
+    ts: Ts,
+    policy: impl FnOnce() -> Result<Option<RetentionPolicy>>,
+) -> Result<()> {
+    let now = Ts::now();
+    // Cutoffs are never past the start of the current day
+    if now.day_start() <= ts {
+        return Ok(());
+    }
+    if policy()?.is_some_and(|policy| ts < policy.cutoff(now)) {
+        return Err(UserRequestError::PastRetention.into());
+    }
+    Ok(())
+}
+
+async fn enforce_retention(state: &SharedAppState) -> Result<()> {
+    let now = Ts::now();
+
+    let policies = state
+        .db
+        .read_with(move |db| {
+            let mut policies = vec![];
+            for (metric_id, metric_record) in db.metrics()? {
+                if let Some(policy) = metric_policy(db, metric_id, &metric_record)? {
+                    policies.push((metric_id, metric_record.internal_id, policy));
+                }
+            }
+            Ok(policies)
+        })
+        .await?;
+
+    debug!(num = policies.len(), "Enforcing retention policies");
+    for (metric_id, metric_internal_id, policy) in policies {
+        enforce_metric_retention(state, metric_id, metric_internal_id, policy, now).await?;
+    }
+
+    let mut stats = state.retention.stats.lock().expect("locking failed");
+    stats.runs += 1;
+    stats.last_run = Some(now);
+    Ok(())
+}
+
+async fn enforce_metric_retention(
+    state: &SharedAppState,
+    metric_id: MetricId,
+    metric_internal_id: MetricInternalId,
+    policy: RetentionPolicy,
+    now: Ts,
+) -> Result<()> {
+    let cutoff = policy.cutoff(now);
+
+    let (deleted, aggregated_days) = match policy.then {
+        RetentionAction::Delete => (delete_before(state, metric_internal_id, cutoff).await?, 0),
+        RetentionAction::DailyAggregate => {
+            aggregate_before(state, metric_internal_id, cutoff).await?
+        }
+    };
+
+    if deleted != 0 || aggregated_days != 0 {
+        info!(%metric_id, deleted, aggregated_days, "Pruned old data points");
+        let mut stats = state.retention.stats.lock().expect("locking failed");
+        stats.deleted += deleted;
+        stats.aggregated_days += aggregated_days;
+    }
+    Ok(())
+}
+
+fn data_point_key(metric_internal_id: MetricInternalId, ts: Ts) -> DataPoint {
+    DataPoint {
+        metric_internal_id,
+        ts,
+        idx: 0,
+    }
+}
+
+fn same_day(a: &DataPoint, b: &DataPoint) -> bool {
+    a.ts.day_start() == b.ts.day_start()
+}
+
+/// Delete all data points before `cutoff`, returning how many were deleted
+async fn delete_before(
+    state: &SharedAppState,
+    metric_internal_id: MetricInternalId,
+    cutoff: Ts,
+) -> Result<u64> {
+    let mut total = 0;
+    loop {
+        let deleted = state
+            .db
+            .write_with(move |db| {
+                let mut keys = vec![];
+                db.data_points_scan(
+                    data_point_key(metric_internal_id, Ts::ZERO)
+                        ..data_point_key(metric_internal_id, cutoff),
+                    false,
+                    &mut |k, _| {
+                        keys.push(k);
+                        if keys.len() < BATCH_SIZE {
+                            Scan::Continue
+                        } else {
+                            Scan::Stop
+                        }
+                    },
+                )?;
+
+                db.data_points_replace(&keys, &[])?;
+                Ok(keys.len())
+            })
+            .await?;
+
+        total += deleted as u64;
+        if deleted < BATCH_SIZE {
+            return Ok(total);
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Replace data points of each day before `cutoff` with a single one at the
+/// start of the day, returning the number of points deleted and days
+/// aggregated
+///
+/// Days that already have a single data point are left alone, which makes
+/// this idempotent. Starts where the previous run got to, if it's known.
+async fn aggregate_before(
+    state: &SharedAppState,
+    metric_internal_id: MetricInternalId,
+    cutoff: Ts,
+) -> Result<(u64, u64)> {
+    let (generation, start) = state.retention.aggregation_start(metric_internal_id);
+    // The cutoff moves back when the policy gets longer
+    let mut start = start.min(cutoff);
+    let (mut deleted_total, mut aggregated_days_total) = (0, 0);
+
+    loop {
+        // The scan and the replace are separate transactions, but nothing can
+        // change in between, as all the writes are done one after another
+        let batch = state
+            .db
+            .write_with(move |db| aggregate_batch(db, metric_internal_id, start, cutoff))
+            .await?;
+        deleted_total += batch.deleted;
+        aggregated_days_total += batch.aggregated_days;
+
+        match batch.next {
+            AggregateNext::Done => break,
+            AggregateNext::Start(next_start) => start = next_start,
+            AggregateNext::OversizedDay(day_start) => {
+                deleted_total +=
+                    aggregate_oversized_day(state, metric_internal_id, day_start).await?;
+                aggregated_days_total += 1;
+                start = day_start.next_day_start();
+            }
+        }
+        state
+            .retention
+            .aggregated_until_set(generation, metric_internal_id, start);
+        tokio::task::yield_now().await;
+    }
+
+    state
+        .retention
+        .aggregated_until_set(generation, metric_internal_id, cutoff);
+    Ok((deleted_total, aggregated_days_total))
+}
+
+struct AggregateBatch {
+    deleted: u64,
+    aggregated_days: u64,
+    next: AggregateNext,
+}
+
+/// Where [`aggregate_batch`] stopped
+enum AggregateNext {
+    /// Reached the cutoff
+    Done,
+    /// Continue from this time
+    Start(Ts),
+    /// The day starting at this time has more data points than fit in a batch
+    OversizedDay(Ts),
+}
+
+/// Aggregate whole days from `start` in one transaction, looking at up to
+/// [`BATCH_SIZE`] data points
+fn aggregate_batch(
+    db: &dyn Storage,
+    metric_internal_id: MetricInternalId,
+    start: Ts,
+    cutoff: Ts,
+) -> Result<AggregateBatch> {
+    // One more than the batch size, to tell if the last day is complete
+    let mut points: Vec<(DataPoint, DataPointRecord)> = vec![];
+    db.data_points_scan(
+        data_point_key(metric_internal_id, start)..data_point_key(metric_internal_id, cutoff),
+        false,
+        &mut |k, v| {
+            points.push((k, v));
+            if points.len() <= BATCH_SIZE {
+                Scan::Continue
+            } else {
+                Scan::Stop
+            }
+        },
+    )?;
+
+    let next = match points.last() {
+        Some((last, _)) if BATCH_SIZE < points.len() => {
+            // Leave the last day, which might be incomplete, for the next batch
+            let last_day_start = last.ts.day_start();
+            points.truncate(points.partition_point(|(k, _)| k.ts < last_day_start));
+            if points.is_empty() {
+                AggregateNext::OversizedDay(last_day_start)
+            } else {
+                AggregateNext::Start(last_day_start)
+            }
+        }
+        _ => AggregateNext::Done,
+    };
+
+    let (mut remove, mut insert, mut aggregated_days) = (vec![], vec![], 0);
+    for day_points in points
+        .chunk_by(|(a, _), (b, _)| same_day(a, b))
+        .filter(|day_points| 1 < day_points.len())
+    {
+        remove.extend(day_points.iter().map(|(k, _)| *k));
+        // Failed runs are dropped along with the rest of the day
+        let median = stats::median(&stats::sorted(
+            day_points
+                .iter()
+                .filter(|(_, v)| v.outcome.is_success())
+                .map(|(_, v)| f64::from(v.value.as_f32())),
+        ));
+        if let Some(median) = median {
+            insert.push(aggregate_data_point(
+                metric_internal_id,
+                day_points[0].0.ts,
+                median,
+            ));
+        }
+        aggregated_days += 1;
+    }
+    db.data_points_replace(&remove, &insert)?;
+
+    Ok(AggregateBatch {
+        deleted: (remove.len() - insert.len()) as u64,
+        aggregated_days,
+        next,
+    })
+}
+
+/// Aggregate a day with more data points than fit in a single batch,
+/// returning the number of points deleted
+///
+/// The median is computed in read transactions first, then the data points
+/// are deleted in batches, with the aggregate inserted along with the last
+/// one. If that gets interrupted, the next run aggregates the points left.
+async fn aggregate_oversized_day(
+    state: &SharedAppState,
+    metric_internal_id: MetricInternalId,
+    day_start: Ts,
+) -> Result<u64> {
+    let day_range = data_point_key(metric_internal_id, day_start)
+        ..data_point_key(metric_internal_id, day_start.next_day_start());
+
+    let mut values = vec![];
+    let mut from = day_range.start;
+    loop {
+        let range = from..day_range.end;
+        let (batch_values, next) = state
+            .db
+            .read_with(move |db| {
+                let (mut values, mut scanned, mut next) = (vec![], 0, None);
+                db.data_points_scan(range, false, &mut |k, v| {
+                    if scanned == BATCH_SIZE {
+                        next = Some(k);
+                        return Scan::Stop;
+                    }
+                    scanned += 1;
+                    // Failed runs are dropped along with the rest of the day
+                    if v.outcome.is_success() {
+                        values.push(f64::from(v.value.as_f32()));
+                    }
+                    Scan::Continue
+                })?;
+                Ok((values, next))
+            })
+            .await?;
+        values.extend(batch_values);
+        match next {
+            Some(next) => from = next,
+            None => break,
+        }
+        tokio::task::yield_now().await;
+    }
+    let median = stats::median(&stats::sorted(values));
+
+    let mut deleted = 0;
+    loop {
+        let range = day_range.clone();
+        let (removed, done) = state
+            .db
+            .write_with(move |db| {
+                // One more than the batch size, to tell if it's the last batch
+                let mut keys = vec![];
+                db.data_points_scan(range, false, &mut |k, _| {
+                    keys.push(k);
+                    if keys.len() <= BATCH_SIZE {
+                        Scan::Continue
+                    } else {
+                        Scan::Stop
+                    }
+                })?;
+                let done = keys.len() <= BATCH_SIZE;
+                keys.truncate(BATCH_SIZE);
+
+                let insert = match median {
+                    Some(median) if done => {
+                        vec![aggregate_data_point(metric_internal_id, day_start, median)]
+                    }
+                    _ => vec![],
+                };
+                db.data_points_replace(&keys, &insert)?;
+                Ok((keys.len() - insert.len(), done))
+            })
+            .await?;
+        deleted += removed as u64;
+        if done {
+            return Ok(deleted);
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
+/// The single data point replacing all the data points of the day of `ts`
+fn aggregate_data_point(
+    metric_internal_id: MetricInternalId,
+    ts: Ts,
+    median: f64,
+) -> (DataPoint, DataPointRecord) {
+    (
+        data_point_key(metric_internal_id, ts.day_start()),
+        DataPointRecord {
+            value: (median as f32).into(),
+            metadata: Default::default(),
+            outcome: Default::default(),
+        },
+    )
+}
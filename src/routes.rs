@@ -1,10 +1,15 @@
 pub mod account;
+pub mod annotation;
 mod auth;
+mod backup;
 pub mod error;
+mod export;
 pub mod metric;
+mod retention;
+mod server_metrics;
 pub mod token;
 
-use std::ops;
+use std::{fmt, ops};
 
 use axum::body::Body;
 use axum::extract::{FromRequest, Path, Request, State};
@@ -12,21 +17,35 @@ use axum::http::header::{CONTENT_ENCODING, CONTENT_TYPE};
 use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::middleware::{self, Next};
 use axum::response::{Html, IntoResponse, Response};
-use axum::routing::{get, post, put};
+use axum::routing::{delete, get, post, put};
 use axum::Router;
+use poloto::ticks::tick_fmt::TickFmt;
+use poloto::ticks::TickDistribution;
 use reqwest::header::ACCEPT_ENCODING;
-use time::OffsetDateTime;
+use tagu::elem::Locked;
+use tagu::prelude::*;
+use time::{OffsetDateTime, UtcOffset};
 
 use self::account::account_new;
+use self::annotation::{
+    annotation_delete, annotation_list, annotation_new, get_metric_annotations,
+};
+use self::backup::backup_get;
 use self::error::{RequestError, RequestResult, UserErrorResponse, UserRequestError};
+use self::export::{
+    account_export_grafana_query, account_export_grafana_search, account_export_grafana_test,
+    account_export_prometheus, account_export_set,
+};
 use self::metric::{
-    get_metric, metric_find, metric_get, metric_get_default_type, metric_new, metric_post,
-    MetricOpts,
+    get_metric, metric_badge, metric_find, metric_get, metric_get_default_type, metric_new,
+    metric_post, ChartTheme, MetricOpts, YScale,
 };
+use self::retention::{account_retention_set, metric_retention_set, retention_stats_get};
+use self::server_metrics::server_metrics_get;
 use self::token::token_new;
-use crate::db::DataPointRecord;
+use crate::db::{AnnotationRecord, DataPointRecord};
 use crate::fragment::{self};
-use crate::models::ts::{DateTimeExt, Ts};
+use crate::models::ts::{TimeStep, Ts, TzOffset};
 use crate::models::MetricId;
 use crate::state::SharedAppState;
 
@@ -50,14 +69,25 @@ pub async fn render_svg(
     metric_id: MetricId,
     opts: &MetricOpts,
 ) -> color_eyre::Result<(String, ops::Range<OffsetDateTime>)> {
+    let measurements = get_metric(state, metric_id, opts).await?;
+    let annotations = match measurements.first() {
+        // Annotations past the last data point are still interesting, the
+        // rendering will clip them to the time axis bounds
+        Some((start_ts, _)) => {
+            get_metric_annotations(state, metric_id, *start_ts..=Ts::now()).await?
+        }
+        None => vec![],
+    };
     Ok(render_svg_from_measurements(
-        &get_metric(state, metric_id, opts).await?,
+        &measurements,
+        &annotations,
         opts,
     ))
 }
 
 fn render_svg_from_measurements(
     measurements: &[(Ts, DataPointRecord)],
+    annotations: &[(Ts, AnnotationRecord)],
     opts: &MetricOpts,
 ) -> (String, ops::Range<OffsetDateTime>) {
     use poloto::build;
@@ -81,66 +111,356 @@ fn render_svg_from_measurements(
         val
     }
 
-    let start_rel_ts = measurements.first().map(|m| m.0).unwrap_or_default();
-    let start_bound_datetime = start_rel_ts.to_datetime().round_down_to_hour();
-    let end_rel_ts = measurements.last().map(|m| m.0).unwrap_or_default();
-    let end_bound_datetime = end_rel_ts.to_datetime().round_up_exclusive_to_hour();
+    let dim = opts.dim();
+    let offset = opts.tz_offset();
+
+    let start_ts = measurements.first().map(|m| m.0).unwrap_or_default();
+    let end_ts = measurements.last().map(|m| m.0).unwrap_or_default();
 
-    let range = end_bound_datetime - start_bound_datetime;
-    let hours_as_secs = 60. * 60.;
+    // Leave some horizontal space for each tick label
+    let max_ticks = ((dim[0] - 300.) / 110.).max(2.) as u64;
+    let tick_step = TimeStep::for_range(end_ts - start_ts, max_ticks);
 
-    let tick_step_secs = (range.as_seconds_f64() / 1.5 / hours_as_secs).ceil() * hours_as_secs;
+    let start_bound_datetime = tick_step.round_down(start_ts.to_datetime().to_offset(offset));
+    let end_bound_datetime =
+        tick_step.next(tick_step.round_down(end_ts.to_datetime().to_offset(offset)));
 
-    let datapoints = measurements.iter().map(|(ts, m)| {
-        let y = m.value.as_f32() as f64;
+    // Failed runs are drawn with their own marker, and don't affect the zoom
+    let (successes, failures): (Vec<_>, Vec<_>) = measurements
+        .iter()
+        .partition(|(_, m)| m.outcome.is_success());
+
+    let (y_min, y_max) = opts.y_range(successes.iter().map(|(_, m)| m.value.as_f32() as f64));
+    let (y_min, y_max) = (
+        y_min.map(|v| opts.y_scale.apply(v)),
+        y_max.map(|v| opts.y_scale.apply(v)),
+    );
+
+    let to_xy = |(ts, m): &&(Ts, DataPointRecord)| {
+        let y = opts.y_scale.apply(m.value.as_f32() as f64);
         let x = ts.to_absolute_secs() as f64;
         (x, y)
+    };
+    let datapoints = successes.iter().map(to_xy);
+    let failed_datapoints = failures.iter().map(to_xy);
+    let has_outliers = datapoints
+        .clone()
+        .any(|(_, y)| nan_out_of_range(y, y_min, y_max).is_nan() && !y.is_nan());
+
+    let annotations = annotations.iter().filter(|(ts, _)| {
+        let dt = ts.to_datetime();
+        start_bound_datetime <= dt && dt <= end_bound_datetime
     });
+    // Failed runs are drawn over the chart (see `failed_markers`), the markers
+    // keep them within its bounds
+    let failed_datapoints = failed_datapoints
+        .zip(failures.iter())
+        .map(|((x, y), (_, m))| (x, saturate_out_of_range(y, y_min, y_max), m));
+
+    let xticks = TickDistribution::new(
+        std::iter::successors(Some(start_bound_datetime), |&dt| Some(tick_step.next(dt)))
+            .map(|dt| dt.unix_timestamp() as f64),
+    )
+    .with_tick_fmt(move |&v| {
+        tick_step.format(
+            OffsetDateTime::from_unix_timestamp(v as i64)
+                .expect("Can't fail")
+                .to_offset(offset),
+        )
+    })
+    .with_where_fmt(move || format!("UTC{}", TzOffset(offset)));
 
-    let xticks = poloto::ticks::TickDistribution::new(std::iter::successors(
-        Some(start_bound_datetime.unix_timestamp() as f64),
-        |w| Some(w + tick_step_secs),
-    ))
-    .with_tick_fmt(|&v| {
-        OffsetDateTime::from_unix_timestamp(v as i64)
-            .expect("Can't fail")
-            .our_fmt()
-            .to_string()
-    });
-
-    let frame = poloto::frame_build()
+    let frame = poloto::frame()
+        .with_viewbox(dim)
+        .build()
         .data(poloto::plots!(
             build::plot("").scatter(
                 datapoints
                     .clone()
-                    .map(|(x, y)| [x, saturate_out_of_range(y, opts.min, opts.max)])
+                    .filter(|_| opts.style.has_points())
+                    .map(|(x, y)| [x, nan_out_of_range(y, y_min, y_max)])
+            ),
+            build::plot("").line(
+                datapoints
+                    .clone()
+                    .filter(|_| opts.style.has_line())
+                    .map(|(x, y)| [x, nan_out_of_range(y, y_min, y_max)])
+            ),
+            // Outliers are drawn at the edge of the range
+            build::plot(if has_outliers { "outliers" } else { "" }).scatter(
+                datapoints
+                    .filter(|&(_, y)| nan_out_of_range(y, y_min, y_max).is_nan())
+                    .map(|(x, y)| [x, saturate_out_of_range(y, y_min, y_max)])
             ),
-            build::plot("")
-                .line(datapoints.map(|(x, y)| [x, nan_out_of_range(y, opts.min, opts.max)])),
             poloto::build::markers(
                 [
                     start_bound_datetime.unix_timestamp() as f64,
                     end_bound_datetime.unix_timestamp() as f64
                 ],
-                [opts.min, opts.max].into_iter().flatten()
+                [y_min, y_max]
+                    .into_iter()
+                    .flatten()
+                    .chain(failed_datapoints.clone().map(|(_, y, _)| y))
             )
         ))
         .map_xticks(|_| xticks)
-        .build_and_label((
-            opts.title.clone(),
-            opts.x_label.clone(),
-            opts.y_label.clone(),
-        ));
+        .map_yticks(|default| {
+            poloto::ticks::from_closure(move |data, canvas, req| {
+                let TickDistribution { iter, fmt, res } =
+                    poloto::ticks::gen_ticks(default, data, canvas, req);
+                TickDistribution::from_parts(
+                    iter,
+                    YTickFmt {
+                        inner: fmt,
+                        scale: opts.y_scale,
+                    },
+                    res,
+                )
+            })
+        })
+        .build();
+
+    let coords = ChartCoords {
+        boundx: [frame.boundx().min, frame.boundx().max],
+        boundy: [frame.boundy().min, frame.boundy().max],
+        dim,
+    };
+    let annotations = annotation_lines(annotations, coords);
+    let failed = failed_markers(failed_datapoints, coords);
+    // Drawn over the points, so they can be clicked
+    let links = data_point_links(
+        measurements.iter().filter_map(|data_point| {
+            let (x, y) = to_xy(&data_point);
+            let y = saturate_out_of_range(y, y_min, y_max);
+            y.is_finite().then_some((x, y, &data_point.1))
+        }),
+        coords,
+    );
+
+    let frame = frame.label((
+        opts.title.clone(),
+        opts.x_label.clone(),
+        opts.y_label.clone(),
+    ));
+
+    let mut svg = String::new();
+    tagu::render(
+        poloto::header()
+            .with_dim(dim)
+            .with_viewbox(dim)
+            .append(chart_style(opts.theme))
+            .append(frame)
+            .append(annotations)
+            .append(failed)
+            .append(links),
+        &mut svg,
+    )
+    .expect("Can't fail?");
 
     (
-        frame
-            .append_to(poloto::header().light_theme())
-            .render_string()
-            .expect("Can't fail?"),
-        start_bound_datetime..end_bound_datetime,
+        svg,
+        start_bound_datetime.to_offset(UtcOffset::UTC)
+            ..end_bound_datetime.to_offset(UtcOffset::UTC),
     )
 }
 
+/// Maps data coordinates to positions on the chart, for drawing our own
+/// elements over the plots
+///
+/// Poloto doesn't allow styling (or linking) individual plots or points, so
+/// these are computed the same way it does, from the data bounds of the plots
+/// and its fixed padding.
+#[derive(Clone, Copy)]
+struct ChartCoords {
+    boundx: [f64; 2],
+    boundy: [f64; 2],
+    dim: [f64; 2],
+}
+
+impl ChartCoords {
+    /// Padding around the plot area, as used by [`poloto::frame`]
+    const PADDING_X: f64 = 150.;
+    const PADDING_Y: f64 = 100.;
+
+    fn x(&self, x: f64) -> Option<f64> {
+        let [min, max] = self.boundx;
+        let px = Self::PADDING_X + (x - min) * (self.dim[0] - 2. * Self::PADDING_X) / (max - min);
+        px.is_finite().then_some(px)
+    }
+
+    fn y(&self, y: f64) -> Option<f64> {
+        let [min, max] = self.boundy;
+        let py = self.dim[1]
+            - Self::PADDING_Y
+            - (y - min) * (self.dim[1] - 2. * Self::PADDING_Y) / (max - min);
+        py.is_finite().then_some(py)
+    }
+}
+
+/// Dashed vertical lines at the annotations, labeled at the top
+fn annotation_lines<'a>(
+    annotations: impl Iterator<Item = &'a (Ts, AnnotationRecord)> + 'a,
+    coords: ChartCoords,
+) -> impl Elem + Locked + 'a {
+    let lines = annotations.filter_map(move |(ts, annotation)| {
+        let x = coords.x(ts.to_absolute_secs() as f64)?;
+        let (top, bottom) = (
+            ChartCoords::PADDING_Y,
+            coords.dim[1] - ChartCoords::PADDING_Y,
+        );
+        Some(
+            tagu::build::elem("g")
+                .with(("class", "perfit_annotation"))
+                .append(tagu::build::single("line").with(attrs!(
+                    ("x1", x),
+                    ("y1", top),
+                    ("x2", x),
+                    ("y2", bottom)
+                )))
+                // Labels are user input, `raw` escapes them
+                .append(
+                    tagu::build::elem("text")
+                        .with(attrs!(
+                            ("class", "poloto_text"),
+                            ("x", x + 4.),
+                            ("y", top + 12.)
+                        ))
+                        .append(tagu::build::raw(annotation.label.as_str()))
+                        .inline(),
+                ),
+        )
+    });
+
+    tagu::build::elem("g").append(tagu::build::from_iter(lines))
+}
+
+/// Squares at the failed runs, with their outcome as a tooltip
+fn failed_markers<'a>(
+    points: impl Iterator<Item = (f64, f64, &'a DataPointRecord)> + 'a,
+    coords: ChartCoords,
+) -> impl Elem + Locked + 'a {
+    const SIZE: f64 = 8.;
+
+    let markers = points.filter_map(move |(x, y, record)| {
+        let (x, y) = (coords.x(x)?, coords.y(y)?);
+        Some(
+            tagu::build::elem("rect")
+                .with(attrs!(
+                    ("x", x - SIZE / 2.),
+                    ("y", y - SIZE / 2.),
+                    ("width", SIZE),
+                    ("height", SIZE),
+                    ("class", "perfit_failed")
+                ))
+                .append(
+                    tagu::build::elem("title")
+                        .append(tagu::build::raw(format!(
+                            "{}\nfailed: {}",
+                            record.value.as_f32(),
+                            record.outcome.as_str()
+                        )))
+                        .inline(),
+                ),
+        )
+    });
+
+    tagu::build::elem("g").append(tagu::build::from_iter(markers))
+}
+
+/// Invisible circles over the data points with a commit or CI job in their
+/// metadata, linking to it, with the details as a tooltip
+fn data_point_links<'a>(
+    points: impl Iterator<Item = (f64, f64, &'a DataPointRecord)> + 'a,
+    coords: ChartCoords,
+) -> impl Elem + Locked + 'a {
+    let links = points.filter_map(move |(x, y, record)| {
+        let href = record
+            .metadata
+            .commit_url()
+            .or_else(|| record.metadata.job_url().map(ToOwned::to_owned))?;
+        let (cx, cy) = (coords.x(x)?, coords.y(y)?);
+
+        let mut title = record.value.as_f32().to_string();
+        for key in ["commit", "branch", "job"] {
+            if let Some(value) = record.metadata.get(key) {
+                title.push_str(&format!("\n{key}: {value}"));
+            }
+        }
+
+        Some(
+            tagu::build::elem("a").with(("href", href)).append(
+                tagu::build::elem("circle")
+                    .with(attrs!(
+                        ("cx", cx),
+                        ("cy", cy),
+                        ("r", 8),
+                        ("fill", "transparent"),
+                        ("class", "perfit_link")
+                    ))
+                    .append(tagu::build::elem("title").append(tagu::build::raw(title))),
+            ),
+        )
+    });
+
+    tagu::build::elem("g").append(tagu::build::from_iter(links))
+}
+
+/// Formats Y axis ticks in the original (unscaled) units
+struct YTickFmt<F> {
+    inner: F,
+    scale: YScale,
+}
+
+impl<F> TickFmt<f64> for YTickFmt<F>
+where
+    F: TickFmt<f64>,
+{
+    fn write_tick(&self, writer: &mut dyn fmt::Write, val: &f64) -> fmt::Result {
+        match self.scale {
+            YScale::Linear => self.inner.write_tick(writer, val),
+            YScale::Log => {
+                let val = self.scale.unapply(*val);
+                let precision = if val < 1. {
+                    (-val.log10()).ceil() as usize + 1
+                } else {
+                    0
+                };
+                write!(writer, "{val:.precision$}")
+            }
+        }
+    }
+
+    fn write_where(&self, writer: &mut dyn fmt::Write) -> fmt::Result {
+        match self.scale {
+            YScale::Linear => self.inner.write_where(writer),
+            YScale::Log => Ok(()),
+        }
+    }
+}
+
+/// `<style>` element with the css for the given `theme`
+fn chart_style(theme: ChartTheme) -> impl Elem + Locked {
+    use poloto::render::Theme;
+
+    // Our own elements drawn over the plots, see `ChartCoords`
+    const FAILED_CSS: &str = ".perfit_failed{fill:orange;}";
+    const ANNOTATIONS_CSS: &str =
+        ".perfit_annotation line{stroke:gray;stroke-width:2;stroke-dasharray:6 4;}\
+        .perfit_annotation text{font-size:14px;dominant-baseline:hanging;}";
+
+    let css = match theme {
+        ChartTheme::Light => Theme::light().get_str().to_owned(),
+        ChartTheme::Dark => Theme::dark().get_str().to_owned(),
+        ChartTheme::Auto => format!(
+            "{}\n@media (prefers-color-scheme: dark) {{\n{}\n}}",
+            Theme::light().get_str(),
+            Theme::dark().get_str()
+        ),
+    };
+
+    tagu::build::elem("style").append(tagu::build::raw(css + FAILED_CSS + ANNOTATIONS_CSS))
+}
+
 pub fn static_file_handler(state: SharedAppState) -> Router {
     Router::new()
         .route(
@@ -234,9 +554,28 @@ pub fn route_handler(state: SharedAppState) -> Router {
     Router::new()
         .route("/", get(index))
         .route("/a/", put(account_new))
+        .route("/a/retention", put(account_retention_set))
+        .route("/a/export", put(account_export_set))
+        .route("/a/:account/metrics", get(account_export_prometheus))
+        .route("/a/:account/grafana/", get(account_export_grafana_test))
+        .route(
+            "/a/:account/grafana/search",
+            post(account_export_grafana_search),
+        )
+        .route(
+            "/a/:account/grafana/query",
+            post(account_export_grafana_query),
+        )
         .route("/t/", put(token_new))
+        .route("/backup", get(backup_get))
+        .route("/retention", get(retention_stats_get))
+        .route("/metrics", get(server_metrics_get))
+        .route("/n/", put(annotation_new).get(annotation_list))
+        .route("/n/:annotation", delete(annotation_delete))
         .route("/m/", put(metric_new).get(metric_find))
         .route("/m/:metric", post(metric_post).get(metric_get_default_type))
+        .route("/m/:metric/badge", get(metric_badge))
+        .route("/m/:metric/retention", put(metric_retention_set))
         .route("/m/:metric/:type", get(metric_get))
         .fallback(not_found)
         .with_state(state)
@@ -4,7 +4,7 @@ use serde_json::json;
 use tracing::instrument;
 
 use super::auth::Auth;
-use crate::db::{AccessTokenRecord, AccountRecord, TABLE_ACCESS_TOKENS, TABLE_ACCOUNTS};
+use crate::db::{AccessTokenRecord, AccountRecord};
 use crate::models::access_token::AccessToken;
 use crate::models::ts::Ts;
 use crate::models::{AccessTokenType, AccountId};
@@ -16,19 +16,18 @@ pub async fn account_new(
     State(state): State<SharedAppState>,
     Auth(auth): Auth,
 ) -> RequestResult<Json<serde_json::Value>> {
-    auth.ensure_can_create_accounts()?;
+    auth.ensure_root()?;
     let account_id = AccountId::generate();
     let admin_token = AccessToken::generate();
 
     state
         .db
-        .write_with(|tx| {
-            tx.open_table(&TABLE_ACCOUNTS)?
-                .insert(&account_id, &AccountRecord { created: Ts::now() })?;
+        .write_with(move |db| {
+            db.account_new(account_id, AccountRecord { created: Ts::now() })?;
 
-            tx.open_table(&TABLE_ACCESS_TOKENS)?.insert(
-                &admin_token,
-                &AccessTokenRecord {
+            db.access_token_new(
+                admin_token,
+                AccessTokenRecord {
                     created: Ts::now(),
                     r#type: AccessTokenType::Admin,
                     account_id,
@@ -0,0 +1,201 @@
+use std::ops;
+
+use axum::extract::{Path, Query, State};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::instrument;
+
+use super::auth::Auth;
+use super::{RequestResult, UserRequestError};
+use crate::db::{Annotation, AnnotationLabel, AnnotationRecord, AnnotationUrl, Scan};
+use crate::models::ts::Ts;
+use crate::models::{AccountId, AnnotationId, MetricId};
+use crate::state::SharedAppState;
+
+/// Max number of annotations drawn on a single chart
+const MAX_ANNOTATIONS_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct AnnotationNewPayload {
+    label: AnnotationLabel,
+    #[serde(default)]
+    url: Option<AnnotationUrl>,
+    /// Apply only to a given metric, instead of all metrics of the account
+    #[serde(default)]
+    metric_id: Option<MetricId>,
+    /// Time of the event, defaults to now, can't be in the future
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    ts: Option<OffsetDateTime>,
+}
+
+#[instrument]
+pub async fn annotation_new(
+    State(state): State<SharedAppState>,
+    Auth(auth): Auth,
+    Json(payload): Json<AnnotationNewPayload>,
+) -> RequestResult<Json<AnnotationId>> {
+    auth.ensure_account_admin()?;
+
+    let ts = match payload.ts {
+        Some(ts) => Ts::from_client(ts).ok_or(UserRequestError::InvalidTimestamp)?,
+        None => Ts::now(),
+    };
+
+    let annotation_id = state
+        .db
+        .write_with(move |db| {
+            if let Some(metric_id) = payload.metric_id {
+                let metric_account_id = db.metric_get(metric_id)?.map(|r| r.account_id);
+                if metric_account_id != Some(auth.account_id) {
+                    return Err(UserRequestError::MetricNotFound(metric_id).into());
+                }
+            }
+
+            let annotation = Annotation {
+                account_id: auth.account_id,
+                ts,
+                annotation_id: AnnotationId::generate(),
+            };
+
+            db.annotation_new(
+                annotation,
+                AnnotationRecord {
+                    label: payload.label,
+                    url: payload.url,
+                    metric_id: payload.metric_id,
+                },
+            )?;
+
+            Ok(annotation.annotation_id)
+        })
+        .await?;
+
+    Ok(Json(annotation_id))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct AnnotationListOpts {
+    /// Only annotations that apply to the given metric
+    #[serde(default)]
+    metric_id: Option<MetricId>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AnnotationListBodyRecord {
+    id: AnnotationId,
+    t: Ts,
+    label: AnnotationLabel,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<AnnotationUrl>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metric_id: Option<MetricId>,
+}
+
+#[instrument]
+pub async fn annotation_list(
+    State(state): State<SharedAppState>,
+    Auth(auth): Auth,
+    Query(opts): Query<AnnotationListOpts>,
+) -> RequestResult<Json<Vec<AnnotationListBodyRecord>>> {
+    auth.ensure_can_list_annotations()?;
+
+    let annotations = state
+        .db
+        .read_with(move |db| {
+            let mut annotations = vec![];
+            db.annotations_scan(
+                key_range(auth.account_id, Ts::ZERO..=Ts::MAX),
+                &mut |k, record| {
+                    if opts
+                        .metric_id
+                        .is_none_or(|metric_id| record.applies_to(metric_id))
+                    {
+                        annotations.push(AnnotationListBodyRecord {
+                            id: k.annotation_id,
+                            t: k.ts,
+                            label: record.label,
+                            url: record.url,
+                            metric_id: record.metric_id,
+                        });
+                    }
+                    Scan::Continue
+                },
+            )?;
+            Ok(annotations)
+        })
+        .await?;
+
+    Ok(Json(annotations))
+}
+
+#[instrument]
+pub async fn annotation_delete(
+    State(state): State<SharedAppState>,
+    Auth(auth): Auth,
+    Path(annotation_id): Path<AnnotationId>,
+) -> RequestResult<()> {
+    auth.ensure_account_admin()?;
+
+    state
+        .db
+        .write_with(move |db| {
+            let annotation = db
+                .annotation_get(annotation_id)?
+                .filter(|a| a.account_id == auth.account_id)
+                .ok_or(UserRequestError::AnnotationNotFound(annotation_id))?;
+
+            db.annotation_remove(annotation)
+        })
+        .await?;
+
+    Ok(())
+}
+
+fn key_range(
+    account_id: AccountId,
+    ts: ops::RangeInclusive<Ts>,
+) -> ops::RangeInclusive<Annotation> {
+    Annotation {
+        account_id,
+        ts: *ts.start(),
+        annotation_id: AnnotationId::ZERO,
+    }..=Annotation {
+        account_id,
+        ts: *ts.end(),
+        annotation_id: AnnotationId::LAST,
+    }
+}
+
+/// Annotations of the account owning `metric_id` that apply to it, in the
+/// `ts` range
+pub async fn get_metric_annotations(
+    state: &SharedAppState,
+    metric_id: MetricId,
+    ts: ops::RangeInclusive<Ts>,
+) -> color_eyre::Result<Vec<(Ts, AnnotationRecord)>> {
+    state
+        .db
+        .read_with(move |db| {
+            let metric_record = db
+                .metric_get(metric_id)?
+                .ok_or(UserRequestError::MetricNotFound(metric_id))?;
+
+            let mut annotations = vec![];
+            db.annotations_scan(key_range(metric_record.account_id, ts), &mut |k, record| {
+                if record.applies_to(metric_id) {
+                    annotations.push((k.ts, record));
+                }
+                if annotations.len() < MAX_ANNOTATIONS_LIMIT {
+                    Scan::Continue
+                } else {
+                    Scan::Stop
+                }
+            })?;
+            Ok(annotations)
+        })
+        .await
+}
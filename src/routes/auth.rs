@@ -6,7 +6,7 @@ use axum::http::header::AUTHORIZATION;
 use axum::http::request::Parts;
 
 use super::{RequestError, UserRequestError};
-use crate::db::{self, AccessTokenRecord};
+use crate::db::AccessTokenRecord;
 use crate::models::access_token::AccessToken;
 use crate::state::SharedAppState;
 
@@ -35,12 +35,10 @@ impl FromRequestParts<SharedAppState> for Auth {
 
         let record = state
             .db
-            .read_with(|tx| {
-                Ok(tx
-                    .open_table(&db::TABLE_ACCESS_TOKENS)?
-                    .get(&access_token)?
-                    .ok_or(UserRequestError::InvalidAuthorizationToken)?
-                    .value())
+            .read_with(move |db| {
+                Ok(db
+                    .access_token_get(access_token)?
+                    .ok_or(UserRequestError::InvalidAuthorizationToken)?)
             })
             .await?;
 
@@ -0,0 +1,58 @@
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE};
+use axum::response::IntoResponse;
+use futures::StreamExt as _;
+use tokio_util::io::ReaderStream;
+use tracing::{info, instrument};
+
+use super::auth::Auth;
+use super::RequestResult;
+use crate::models::ts::Ts;
+use crate::state::SharedAppState;
+
+/// Consistent snapshot of the whole database, as a redb file
+#[instrument]
+pub async fn backup_get(
+    State(state): State<SharedAppState>,
+    Auth(auth): Auth,
+) -> RequestResult<impl IntoResponse> {
+    auth.ensure_root()?;
+
+    let tmp_dir = tempfile::tempdir().map_err(color_eyre::eyre::Error::from)?;
+    let path = tmp_dir.path().join("backup.redb");
+
+    state.db.backup_to(path.clone()).await?;
+
+    let file = tokio::fs::File::open(&path)
+        .await
+        .map_err(color_eyre::eyre::Error::from)?;
+    let len = file
+        .metadata()
+        .await
+        .map_err(color_eyre::eyre::Error::from)?
+        .len();
+    info!(len, "Backup created");
+
+    // The temporary directory is removed when the body is dropped, whether it
+    // was sent completely or not
+    let body = Body::from_stream(ReaderStream::new(file).map(move |chunk| {
+        let _ = &tmp_dir;
+        chunk
+    }));
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/octet-stream".to_owned()),
+            (CONTENT_LENGTH, len.to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"perfitd-{}.redb\"",
+                    Ts::now().to_absolute_secs()
+                ),
+            ),
+        ],
+        body,
+    ))
+}
@@ -5,14 +5,24 @@ use thiserror::Error;
 use tracing::info;
 
 use super::AppJson;
-use crate::models::MetricId;
+use crate::models::{AccountId, AnnotationId, MetricId};
 
 #[derive(Debug, Error)]
 pub enum UserRequestError {
     #[error("Metric Not Found: {0}")]
     MetricNotFound(MetricId),
+    #[error("Annotation Not Found: {0}")]
+    AnnotationNotFound(AnnotationId),
+    #[error("Export Not Enabled For Account: {0}")]
+    ExportNotEnabled(AccountId),
     #[error("Invalid Path")]
     InvalidPath,
+    #[error("Bad Request - Invalid Idempotency Key")]
+    InvalidIdempotencyKey,
+    #[error("Bad Request - Time Before The Epoch Or In The Future")]
+    InvalidTimestamp,
+    #[error("Bad Request - Time Past The Retention Policy Of The Metric")]
+    PastRetention,
     #[error("Unauthorized - Missing Authorization Token")]
     MissingAuthorizationToken,
     #[error("Unauthorized - Malformed Authorization Token")]
@@ -62,8 +72,14 @@ impl IntoResponse for &UserRequestError {
             | UserRequestError::RootAccountCantBeUsed
             | UserRequestError::MissingAuthorizationToken
             | UserRequestError::MalformedAuthoraizationToken
-            | UserRequestError::MetricNotFound(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            UserRequestError::FormatNotSupported => (StatusCode::NOT_FOUND, self.to_string()),
+            | UserRequestError::MetricNotFound(_)
+            | UserRequestError::AnnotationNotFound(_)
+            | UserRequestError::InvalidIdempotencyKey
+            | UserRequestError::InvalidTimestamp
+            | UserRequestError::PastRetention => (StatusCode::BAD_REQUEST, self.to_string()),
+            UserRequestError::FormatNotSupported | UserRequestError::ExportNotEnabled(_) => {
+                (StatusCode::NOT_FOUND, self.to_string())
+            }
         };
         (status_code, AppJson(UserErrorResponse { message })).into_response()
     }
@@ -0,0 +1,221 @@
+//! Exporting metrics of an account to Prometheus and Grafana
+//!
+//! Opt-in per account, as it makes the list of its metrics public.
+
+use axum::extract::{Path, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::Json;
+use prometheus::{Encoder as _, GaugeVec, Opts, Registry, TextEncoder};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::instrument;
+
+use super::auth::Auth;
+use super::metric::{get_metric, MetricOpts};
+use super::{RequestResult, UserRequestError};
+use crate::db::{AccountExportRecord, MetricRecord, Scan, Storage};
+use crate::models::ts::Ts;
+use crate::models::{AccountId, MetricId};
+use crate::state::SharedAppState;
+
+/// Enable (`true`) or disable (`false`) exporting metrics of the account
+#[instrument]
+pub async fn account_export_set(
+    State(state): State<SharedAppState>,
+    Auth(auth): Auth,
+    Json(enabled): Json<bool>,
+) -> RequestResult<()> {
+    auth.ensure_account_admin()?;
+
+    let record = enabled.then(|| AccountExportRecord { created: Ts::now() });
+    state
+        .db
+        .write_with(move |db| db.account_export_set(auth.account_id, record))
+        .await?;
+
+    Ok(())
+}
+
+/// Metrics of an account that has exporting enabled
+fn exported_metrics(
+    db: &dyn Storage,
+    account_id: AccountId,
+) -> color_eyre::Result<Vec<(MetricId, MetricRecord)>> {
+    if db.account_export_get(account_id)?.is_none() {
+        return Err(UserRequestError::ExportNotEnabled(account_id).into());
+    }
+
+    db.account_metrics(account_id)
+}
+
+/// Ids of the metrics of an account that has exporting enabled
+async fn exported_metric_ids(
+    state: &SharedAppState,
+    account_id: AccountId,
+) -> color_eyre::Result<Vec<MetricId>> {
+    state
+        .db
+        .read_with(move |db| {
+            Ok(exported_metrics(db, account_id)?
+                .into_iter()
+                .map(|(metric_id, _)| metric_id)
+                .collect())
+        })
+        .await
+}
+
+/// Latest value of every metric of the account, for Prometheus to scrape
+#[instrument]
+pub async fn account_export_prometheus(
+    State(state): State<SharedAppState>,
+    Path(account_id): Path<AccountId>,
+) -> RequestResult<impl IntoResponse> {
+    let opts = MetricOpts::default().successes_by_default();
+    let latest = state
+        .db
+        .read_with(move |db| {
+            let mut latest = vec![];
+            for (metric_id, record) in exported_metrics(db, account_id)? {
+                db.data_points_scan(opts.key_range(record.internal_id), true, &mut |k, v| {
+                    if !opts.includes(&v) {
+                        return Scan::Continue;
+                    }
+                    latest.push((metric_id, k.ts, v.value.as_f32()));
+                    Scan::Stop
+                })?;
+            }
+            Ok(latest)
+        })
+        .await?;
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_prometheus_gauges(&latest)?,
+    ))
+}
+
+fn render_prometheus_gauges(latest: &[(MetricId, Ts, f32)]) -> color_eyre::Result<Vec<u8>> {
+    let registry = Registry::new();
+    let values = GaugeVec::new(
+        Opts::new("perfit_metric_value", "Latest value of the metric"),
+        &["metric_id"],
+    )?;
+    let timestamps = GaugeVec::new(
+        Opts::new(
+            "perfit_metric_timestamp_seconds",
+            "Time of the latest value of the metric",
+        ),
+        &["metric_id"],
+    )?;
+    registry.register(Box::new(values.clone()))?;
+    registry.register(Box::new(timestamps.clone()))?;
+
+    for (metric_id, ts, value) in latest {
+        let metric_id = metric_id.to_string();
+        values
+            .with_label_values(&[&metric_id])
+            .set(f64::from(*value));
+        timestamps
+            .with_label_values(&[&metric_id])
+            .set(ts.to_absolute_secs() as f64);
+    }
+
+    let mut buf = vec![];
+    TextEncoder::new().encode(&registry.gather(), &mut buf)?;
+    Ok(buf)
+}
+
+/// Grafana JSON datasource "test connection" request
+#[instrument]
+pub async fn account_export_grafana_test(
+    State(state): State<SharedAppState>,
+    Path(account_id): Path<AccountId>,
+) -> RequestResult<()> {
+    exported_metric_ids(&state, account_id).await?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GrafanaSearchRequest {
+    #[serde(default)]
+    target: String,
+}
+
+/// Grafana JSON datasource metric search: ids of the metrics of the account
+/// containing the searched string
+#[instrument]
+pub async fn account_export_grafana_search(
+    State(state): State<SharedAppState>,
+    Path(account_id): Path<AccountId>,
+    Json(request): Json<GrafanaSearchRequest>,
+) -> RequestResult<Json<Vec<String>>> {
+    Ok(Json(
+        exported_metric_ids(&state, account_id)
+            .await?
+            .into_iter()
+            .map(|metric_id| metric_id.to_string())
+            .filter(|metric_id| metric_id.contains(&request.target))
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GrafanaQueryRange {
+    #[serde(with = "time::serde::rfc3339")]
+    from: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    to: OffsetDateTime,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GrafanaQueryTarget {
+    target: MetricId,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GrafanaQueryRequest {
+    range: GrafanaQueryRange,
+    targets: Vec<GrafanaQueryTarget>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GrafanaTimeSeries {
+    target: String,
+    /// `[value, unix timestamp in milliseconds]` pairs
+    datapoints: Vec<(f32, u64)>,
+}
+
+/// Grafana JSON datasource query: data points of metrics in a time range
+#[instrument]
+pub async fn account_export_grafana_query(
+    State(state): State<SharedAppState>,
+    Path(account_id): Path<AccountId>,
+    Json(request): Json<GrafanaQueryRequest>,
+) -> RequestResult<Json<Vec<GrafanaTimeSeries>>> {
+    let metric_ids = exported_metric_ids(&state, account_id).await?;
+
+    let opts = MetricOpts {
+        start_fixed: Some(request.range.from),
+        end_fixed: Some(request.range.to),
+        ..Default::default()
+    }
+    .successes_by_default();
+
+    let mut series = vec![];
+    for GrafanaQueryTarget { target } in request.targets {
+        if !metric_ids.contains(&target) {
+            return Err(UserRequestError::MetricNotFound(target).into());
+        }
+        series.push(GrafanaTimeSeries {
+            target: target.to_string(),
+            datapoints: get_metric(&state, target, &opts)
+                .await?
+                .into_iter()
+                .map(|(ts, record)| (record.value.as_f32(), ts.to_absolute_secs() * 1000))
+                .collect(),
+        });
+    }
+
+    Ok(Json(series))
+}
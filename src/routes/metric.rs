@@ -3,25 +3,28 @@ use std::time::Duration;
 
 use axum::extract::{Path, Query, State};
 use axum::http::header::CONTENT_TYPE;
-use axum::http::Uri;
+use axum::http::{HeaderMap, Uri};
 use axum::response::{Html, IntoResponse, Response};
 use axum::Json;
 use reqwest::StatusCode;
-use resiter::AndThen as _;
 use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use time::{OffsetDateTime, UtcOffset};
 use tracing::instrument;
 
 use super::auth::Auth;
 use super::{render_svg, RequestResult, UserRequestError, MAX_DATA_POINTS_LIMIT};
+use crate::badge::{render_badge, BadgeOpts};
 use crate::db::{
-    DataPoint, DataPointMetadata, DataPointRecord, DataPointValue, MetricRecord, TABLE_DATA_POINTS,
-    TABLE_METRICS, TABLE_METRICS_REV,
+    DataPoint, DataPointAppend, DataPointMetadata, DataPointOutcome, DataPointRecord,
+    DataPointValue, IdempotencyKey, MetricRecord, Scan,
 };
 use crate::fragment::render_chart_form;
-use crate::models::ts::Ts;
+use crate::models::ts::{Ts, TzOffset};
 use crate::models::{MetricId, MetricInternalId};
 use crate::state::SharedAppState;
+use crate::stats;
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
@@ -36,12 +39,7 @@ pub async fn metric_find(
 ) -> RequestResult<Response> {
     if let Some(_metric_record) = state
         .db
-        .read_with(|tx| {
-            Ok(tx
-                .open_table(&TABLE_METRICS)?
-                .get(&metric_id)?
-                .map(|record| record.value()))
-        })
+        .read_with(move |db| db.metric_get(metric_id))
         .await?
     {
         Ok(([("HX-Location", format!("/m/{}", metric_id))], Html("")).into_response())
@@ -57,24 +55,10 @@ pub async fn metric_new(
 ) -> RequestResult<Json<MetricId>> {
     let metric_id = state
         .db
-        .write_with(|tx| {
-            let mut table_metric_rev = tx.open_table(&TABLE_METRICS_REV)?;
-            let new_internal_id = table_metric_rev
-                .last()?
-                .map(|(k, _v)| k.value().next())
-                .unwrap_or_default();
-
+        .write_with(move |db| {
             let metric_id = MetricId::generate();
 
-            tx.open_table(&TABLE_METRICS)?.insert(
-                &metric_id,
-                &MetricRecord {
-                    created: Ts::now(),
-                    account_id: auth.account_id,
-                    internal_id: new_internal_id,
-                },
-            )?;
-            table_metric_rev.insert(&new_internal_id, &metric_id)?;
+            db.metric_new(metric_id, auth.account_id)?;
 
             Ok(metric_id)
         })
@@ -88,64 +72,106 @@ pub async fn metric_new(
 pub struct MetricPostPayload {
     value: DataPointValue,
     metadata: Option<DataPointMetadata>,
+    /// How the measured run ended, defaults to success
+    #[serde(default)]
+    outcome: DataPointOutcome,
+    /// Time of the data point, defaults to now
+    ///
+    /// Set when sending data points that couldn't be sent right away. Can't be
+    /// before the epoch or in the future.
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    ts: Option<OffsetDateTime>,
+    /// Alternative to the `Idempotency-Key` header
+    #[serde(default)]
+    idempotency_key: Option<IdempotencyKey>,
 }
 
+/// Posting again with the same `Idempotency-Key` header (or payload field)
+/// returns the time of the data point posted first, instead of appending
+/// another one
 #[instrument]
 pub async fn metric_post(
     State(state): State<SharedAppState>,
     Path(metric_id): Path<MetricId>,
-    Json(MetricPostPayload { value, metadata }): Json<MetricPostPayload>,
+    headers: HeaderMap,
+    Json(MetricPostPayload {
+        value,
+        metadata,
+        outcome,
+        ts,
+        idempotency_key,
+    }): Json<MetricPostPayload>,
 ) -> RequestResult<Json<u64>> {
-    let ts = state
-        .db
-        .write_with(|tx| {
-            let metric_record = tx
-                .open_table(&TABLE_METRICS)?
-                .get(&metric_id)?
-                .ok_or(UserRequestError::MetricNotFound(metric_id))?
-                .value();
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY) {
+        Some(header) => Some(IdempotencyKey::try_new(
+            header
+                .to_str()
+                .map_err(|_| UserRequestError::InvalidIdempotencyKey)?,
+        )?),
+        None => idempotency_key,
+    };
 
-            let ts = Ts::now();
+    let ts = match ts {
+        Some(ts) => Ts::from_client(ts).ok_or(UserRequestError::InvalidTimestamp)?,
+        None => Ts::now(),
+    };
 
-            let mut data_points_table = tx.open_table(&TABLE_DATA_POINTS)?;
+    let data_point = data_point_post(
+        &state,
+        metric_id,
+        ts,
+        DataPointRecord {
+            value,
+            metadata: metadata.unwrap_or_default(),
+            outcome,
+        },
+        idempotency_key,
+    )
+    .await?;
 
-            let idx = data_points_table
-                .range(
-                    &DataPoint {
-                        metric_internal_id: metric_record.internal_id,
-                        ts,
-                        idx: 0,
-                    }..&DataPoint {
-                        metric_internal_id: metric_record.internal_id,
-                        ts: ts.inc(),
-                        idx: 0,
-                    },
-                )?
-                .next_back()
-                .transpose()?
-                .map(|(k, _v)| k.value().idx + 1)
-                .unwrap_or_default();
-
-            data_points_table.insert(
-                &DataPoint {
-                    metric_internal_id: metric_record.internal_id,
-                    ts,
-                    idx,
-                },
-                &DataPointRecord {
-                    value,
-                    metadata: metadata.unwrap_or_default(),
-                },
-            )?;
-
-            Ok(ts)
+    Ok(Json(data_point.ts.to_absolute_secs()))
+}
+
+/// Append a data point to a metric, for every way of posting one
+pub async fn data_point_post(
+    state: &SharedAppState,
+    metric_id: MetricId,
+    ts: Ts,
+    record: DataPointRecord,
+    idempotency_key: Option<IdempotencyKey>,
+) -> color_eyre::Result<DataPoint> {
+    let metric_record = metric_record_get(state, metric_id).await?;
+
+    state
+        .db
+        .data_point_append(DataPointAppend {
+            metric_id,
+            account_id: metric_record.account_id,
+            metric_internal_id: metric_record.internal_id,
+            ts,
+            record,
+            idempotency_key,
         })
-        .await?;
+        .await
+}
 
-    Ok(Json(ts.to_absolute_secs()))
+/// Metrics are never deleted, so the record can be used after the lookup
+/// transaction is gone
+async fn metric_record_get(
+    state: &SharedAppState,
+    metric_id: MetricId,
+) -> color_eyre::Result<MetricRecord> {
+    state
+        .db
+        .read_with(move |db| {
+            Ok(db
+                .metric_get(metric_id)?
+                .ok_or(UserRequestError::MetricNotFound(metric_id))?)
+        })
+        .await
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct MetricOpts {
     #[serde(default, skip_serializing_if = "String::is_empty")]
@@ -166,9 +192,184 @@ pub struct MetricOpts {
     pub min: Option<f64>,
     #[serde(default)]
     pub max: Option<f64>,
+    #[serde(default, skip_serializing_if = "ChartTheme::is_default")]
+    pub theme: ChartTheme,
+    #[serde(default, skip_serializing_if = "ChartStyle::is_default")]
+    pub style: ChartStyle,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "YScale::is_default")]
+    pub y_scale: YScale,
+    #[serde(default, skip_serializing_if = "YZoom::is_default")]
+    pub y_zoom: YZoom,
+    /// Fixed UTC offset to display times in
+    #[serde(default)]
+    pub tz: Option<TzOffset>,
+    /// Only include data points with this outcome
+    #[serde(default)]
+    pub outcome: Option<DataPointOutcome>,
+}
+
+/// Color theme of the rendered chart
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChartTheme {
+    #[default]
+    Light,
+    Dark,
+    /// Follow `prefers-color-scheme` of the viewer
+    Auto,
+}
+
+impl ChartTheme {
+    pub const ALL: &'static [(Self, &'static str)] = &[
+        (Self::Light, "light"),
+        (Self::Dark, "dark"),
+        (Self::Auto, "auto"),
+    ];
+
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Which elements to draw for the data points
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChartStyle {
+    #[default]
+    LineAndPoints,
+    Line,
+    Points,
+}
+
+impl ChartStyle {
+    pub const ALL: &'static [(Self, &'static str)] = &[
+        (Self::LineAndPoints, "line-and-points"),
+        (Self::Line, "line"),
+        (Self::Points, "points"),
+    ];
+
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn has_line(self) -> bool {
+        matches!(self, Self::LineAndPoints | Self::Line)
+    }
+
+    pub fn has_points(self) -> bool {
+        matches!(self, Self::LineAndPoints | Self::Points)
+    }
+}
+
+/// Scale of the Y axis
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum YScale {
+    #[default]
+    Linear,
+    Log,
+}
+
+impl YScale {
+    pub const ALL: &'static [(Self, &'static str)] =
+        &[(Self::Linear, "linear"), (Self::Log, "log")];
+
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Map a value to the axis coordinate
+    ///
+    /// Values that can't be plotted become NaN.
+    pub fn apply(self, val: f64) -> f64 {
+        match self {
+            YScale::Linear => val,
+            YScale::Log if 0. < val => val.log10(),
+            YScale::Log => f64::NAN,
+        }
+    }
+
+    /// Reverse of [`Self::apply`]
+    pub fn unapply(self, val: f64) -> f64 {
+        match self {
+            YScale::Linear => val,
+            YScale::Log => 10f64.powf(val),
+        }
+    }
+}
+
+/// How to pick the Y axis range, when `min` and `max` are not set
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum YZoom {
+    /// Include all the data points
+    #[default]
+    Full,
+    /// Zoom to p1 - p99 range of data points, to ignore outliers
+    Auto,
+}
+
+impl YZoom {
+    pub const ALL: &'static [(Self, &'static str)] = &[(Self::Full, "full"), (Self::Auto, "auto")];
+
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 impl MetricOpts {
+    pub const DEFAULT_WIDTH: u32 = 800;
+    pub const DEFAULT_HEIGHT: u32 = 500;
+    const MIN_DIM: u32 = 200;
+    const MAX_DIM: u32 = 4000;
+
+    /// Chart dimensions, clamped to something reasonable
+    pub fn dim(&self) -> [f64; 2] {
+        [
+            self.width.unwrap_or(Self::DEFAULT_WIDTH),
+            self.height.unwrap_or(Self::DEFAULT_HEIGHT),
+        ]
+        .map(|d| f64::from(d.clamp(Self::MIN_DIM, Self::MAX_DIM)))
+    }
+
+    /// Same options, but only including successful data points unless
+    /// another outcome was asked for, for anything aggregating data points
+    pub fn successes_by_default(&self) -> Self {
+        Self {
+            outcome: Some(self.outcome.unwrap_or_default()),
+            ..self.clone()
+        }
+    }
+
+    pub fn includes(&self, record: &DataPointRecord) -> bool {
+        self.outcome.is_none_or(|outcome| outcome == record.outcome)
+    }
+
+    pub fn tz_offset(&self) -> UtcOffset {
+        self.tz.map(|tz| tz.0).unwrap_or(UtcOffset::UTC)
+    }
+
+    /// Effective Y axis range (before scaling) for the given `values`
+    ///
+    /// Explicit `min`/`max` take precedence over [`YZoom`].
+    pub fn y_range(&self, values: impl IntoIterator<Item = f64>) -> (Option<f64>, Option<f64>) {
+        let (zoom_min, zoom_max) = match self.y_zoom {
+            YZoom::Full => (None, None),
+            YZoom::Auto => {
+                let sorted = stats::sorted(values);
+                (
+                    stats::percentile(&sorted, 0.01),
+                    stats::percentile(&sorted, 0.99),
+                )
+            }
+        };
+        (self.min.or(zoom_min), self.max.or(zoom_max))
+    }
+
     pub fn key_range(&self, metric_internal_id: MetricInternalId) -> ops::Range<DataPoint> {
         let now = std::time::SystemTime::now();
         DataPoint {
@@ -208,31 +409,55 @@ pub async fn get_metric(
     metric_id: MetricId,
     opts: &MetricOpts,
 ) -> color_eyre::Result<Vec<(Ts, DataPointRecord)>> {
+    let range = opts.key_range(metric_record_get(state, metric_id).await?.internal_id);
+    let opts = opts.clone();
     state
         .db
-        .read_with(|tx| {
-            let metric_record = tx
-                .open_table(&TABLE_METRICS)?
-                .get(&metric_id)?
-                .ok_or(UserRequestError::MetricNotFound(metric_id))?
-                .value();
-
-            let data_points: Vec<_> = tx
-                .open_table(&TABLE_DATA_POINTS)?
-                .range(opts.key_range(metric_record.internal_id))?
-                .enumerate()
-                // We don't want ever to stop at the boundary of multiple data points for the same
-                // second so instead of a simple `.take(limit), we need something
-                // more complex
-                .take_while(|(i, d)| {
-                    i < &MAX_DATA_POINTS_LIMIT
-                        || d.as_ref()
-                            .map(|(k, _v)| k.value().idx != 0)
-                            .unwrap_or_default()
-                })
-                .map(|(_, r)| r)
-                .and_then_ok(|(k, v)| Ok((k.value().ts, v.value())))
-                .collect::<Result<_, _>>()?;
+        .read_with(move |db| {
+            let mut data_points = vec![];
+            db.data_points_scan(range, false, &mut |k, v| {
+                // We don't want ever to stop at the boundary of multiple data points for the
+                // same second so instead of a simple limit, we need something more complex
+                if MAX_DATA_POINTS_LIMIT <= data_points.len() && k.idx == 0 {
+                    return Scan::Stop;
+                }
+                if opts.includes(&v) {
+                    data_points.push((k.ts, v));
+                }
+                Scan::Continue
+            })?;
+
+            Ok(data_points)
+        })
+        .await
+}
+
+/// Like [`get_metric`], but returns (up to) `limit` newest data points in the
+/// range, still ordered oldest to newest
+pub async fn get_metric_latest(
+    state: &SharedAppState,
+    metric_id: MetricId,
+    opts: &MetricOpts,
+    limit: usize,
+) -> color_eyre::Result<Vec<(Ts, DataPointRecord)>> {
+    let range = opts.key_range(metric_record_get(state, metric_id).await?.internal_id);
+    let opts = opts.clone();
+    state
+        .db
+        .read_with(move |db| {
+            let limit = limit.min(MAX_DATA_POINTS_LIMIT);
+            let mut data_points = vec![];
+            db.data_points_scan(range, true, &mut |k, v| {
+                if limit <= data_points.len() {
+                    return Scan::Stop;
+                }
+                if opts.includes(&v) {
+                    data_points.push((k.ts, v));
+                }
+                Scan::Continue
+            })?;
+
+            data_points.reverse();
 
             Ok(data_points)
         })
@@ -251,12 +476,45 @@ pub async fn metric_get_default_type(
         .into_response())
 }
 
+#[instrument]
+pub async fn metric_badge(
+    State(state): State<SharedAppState>,
+    Path(metric_id): Path<MetricId>,
+    Query(opts): Query<MetricOpts>,
+    Query(badge_opts): Query<BadgeOpts>,
+) -> RequestResult<impl IntoResponse> {
+    let data_points = get_metric_latest(
+        &state,
+        metric_id,
+        &opts.successes_by_default(),
+        badge_opts.num_data_points(),
+    )
+    .await?;
+
+    Ok((
+        [(CONTENT_TYPE, "image/svg+xml")],
+        render_badge(&data_points, &badge_opts),
+    )
+        .into_response())
+}
+
+/// Options of the `json` format of [`metric_get`]
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricJsonOpts {
+    /// Only return (up to) this many newest data points of the range
+    #[serde(default)]
+    latest: Option<usize>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct RawMetricGetBodyRecord {
     t: Ts,
     v: DataPointValue,
     #[serde(skip_serializing_if = "DataPointMetadata::is_empty")]
     m: DataPointMetadata,
+    #[serde(skip_serializing_if = "DataPointOutcome::is_success")]
+    o: DataPointOutcome,
 }
 
 #[instrument]
@@ -264,6 +522,7 @@ pub async fn metric_get(
     State(state): State<SharedAppState>,
     Path((metric_id, r#type)): Path<(MetricId, String)>,
     Query(opts): Query<MetricOpts>,
+    Query(json_opts): Query<MetricJsonOpts>,
     uri: Uri,
 ) -> RequestResult<impl IntoResponse> {
     Ok(match r#type.as_str() {
@@ -276,14 +535,25 @@ pub async fn metric_get(
             ([(CONTENT_TYPE, "image/svg+xml")], svg).into_response()
         }
         "json" => {
-            let data_points: Vec<RawMetricGetBodyRecord> = get_metric(&state, metric_id, &opts)
-                .await?
+            let data_points = match json_opts.latest {
+                Some(limit) => get_metric_latest(&state, metric_id, &opts, limit).await?,
+                None => get_metric(&state, metric_id, &opts).await?,
+            };
+            let data_points: Vec<RawMetricGetBodyRecord> = data_points
                 .into_iter()
                 .map(
-                    |(ts, DataPointRecord { value, metadata })| RawMetricGetBodyRecord {
+                    |(
+                        ts,
+                        DataPointRecord {
+                            value,
+                            metadata,
+                            outcome,
+                        },
+                    )| RawMetricGetBodyRecord {
                         t: ts,
                         v: value,
                         m: metadata,
+                        o: outcome,
                     },
                 )
                 .collect();
@@ -0,0 +1,65 @@
+use axum::extract::{Path, State};
+use axum::Json;
+use tracing::instrument;
+
+use super::auth::Auth;
+use super::{RequestResult, UserRequestError};
+use crate::db::RetentionPolicy;
+use crate::models::MetricId;
+use crate::retention::RetentionStats;
+use crate::state::SharedAppState;
+
+/// Set (or clear, with `null`) the retention policy of a metric
+#[instrument]
+pub async fn metric_retention_set(
+    State(state): State<SharedAppState>,
+    Auth(auth): Auth,
+    Path(metric_id): Path<MetricId>,
+    Json(policy): Json<Option<RetentionPolicy>>,
+) -> RequestResult<()> {
+    auth.ensure_account_admin()?;
+
+    state
+        .db
+        .write_with(move |db| {
+            let metric_account_id = db.metric_get(metric_id)?.map(|r| r.account_id);
+            if metric_account_id != Some(auth.account_id) {
+                return Err(UserRequestError::MetricNotFound(metric_id).into());
+            }
+
+            db.metric_retention_set(metric_id, policy)
+        })
+        .await?;
+    state.retention.policies_changed();
+
+    Ok(())
+}
+
+/// Set (or clear, with `null`) the default retention policy of the account
+#[instrument]
+pub async fn account_retention_set(
+    State(state): State<SharedAppState>,
+    Auth(auth): Auth,
+    Json(policy): Json<Option<RetentionPolicy>>,
+) -> RequestResult<()> {
+    auth.ensure_account_admin()?;
+
+    state
+        .db
+        .write_with(move |db| db.account_retention_set(auth.account_id, policy))
+        .await?;
+    state.retention.policies_changed();
+
+    Ok(())
+}
+
+/// What the retention enforcement did since the start
+#[instrument]
+pub async fn retention_stats_get(
+    State(state): State<SharedAppState>,
+    Auth(auth): Auth,
+) -> RequestResult<Json<RetentionStats>> {
+    auth.ensure_root()?;
+
+    Ok(Json(state.retention.stats()))
+}
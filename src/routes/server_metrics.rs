@@ -0,0 +1,22 @@
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use tracing::instrument;
+
+use super::auth::Auth;
+use super::RequestResult;
+use crate::state::SharedAppState;
+
+/// Metrics about perfitd itself, for Prometheus to scrape
+#[instrument]
+pub async fn server_metrics_get(
+    State(state): State<SharedAppState>,
+    Auth(auth): Auth,
+) -> RequestResult<impl IntoResponse> {
+    auth.ensure_root()?;
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.server_metrics.render(&state.db).await?,
+    ))
+}
@@ -6,7 +6,7 @@ use tracing::instrument;
 
 use super::auth::Auth;
 use super::error::RequestResult;
-use crate::db::{AccessTokenRecord, TABLE_ACCESS_TOKENS, TABLE_ACCESS_TOKENS_REV};
+use crate::db::AccessTokenRecord;
 use crate::models::access_token::AccessToken;
 use crate::models::ts::Ts;
 use crate::models::AccessTokenType;
@@ -30,20 +30,15 @@ pub async fn token_new(
 
     state
         .db
-        .write_with(|tx| {
-            tx.open_table(&TABLE_ACCESS_TOKENS)?.insert(
-                &token,
-                &AccessTokenRecord {
+        .write_with(move |db| {
+            db.access_token_new(
+                token,
+                AccessTokenRecord {
                     created: Ts::now(),
                     r#type: payload.r#type,
                     account_id,
                 },
-            )?;
-
-            tx.open_table(&TABLE_ACCESS_TOKENS_REV)?
-                .insert(&(account_id, token), &())?;
-
-            Ok(())
+            )
         })
         .await?;
 
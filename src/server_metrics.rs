@@ -0,0 +1,135 @@
+//! Prometheus metrics about perfitd itself
+//!
+//! Not to be confused with the metrics perfitd stores for its users.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use axum::http::{Method, StatusCode};
+use color_eyre::Result;
+use prometheus::{
+    Encoder as _, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::db::Database;
+use crate::line_protocol::LineOutcome;
+
+#[derive(Debug)]
+pub struct ServerMetrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_durations: HistogramVec,
+    rate_limited_requests: IntCounter,
+    line_protocol_lines: IntCounterVec,
+    db_file_size: IntGauge,
+    accounts: IntGauge,
+    metrics: IntGauge,
+    data_points: IntGauge,
+    /// `None` with in-memory storage
+    db_path: Option<PathBuf>,
+}
+
+impl ServerMetrics {
+    pub fn new(db: &Database, db_path: Option<PathBuf>) -> Result<Self> {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("perfitd_http_requests_total", "Number of handled requests"),
+            &["method", "route", "status"],
+        )?;
+        let http_request_durations = HistogramVec::new(
+            HistogramOpts::new(
+                "perfitd_http_request_duration_seconds",
+                "Time it took to handle requests",
+            ),
+            &["method", "route"],
+        )?;
+        let rate_limited_requests = IntCounter::new(
+            "perfitd_rate_limited_requests_total",
+            "Number of requests rejected by the rate limiter",
+        )?;
+        let line_protocol_lines = IntCounterVec::new(
+            Opts::new(
+                "perfitd_line_protocol_lines_total",
+                "Number of received line protocol lines",
+            ),
+            &["outcome"],
+        )?;
+        let db_file_size =
+            IntGauge::new("perfitd_db_file_size_bytes", "Size of the database file")?;
+        let accounts = IntGauge::new("perfitd_accounts", "Number of accounts")?;
+        let metrics = IntGauge::new("perfitd_metrics", "Number of metrics")?;
+        let data_points = IntGauge::new("perfitd_data_points", "Number of data points")?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_request_durations.clone()))?;
+        registry.register(Box::new(rate_limited_requests.clone()))?;
+        registry.register(Box::new(line_protocol_lines.clone()))?;
+        registry.register(Box::new(db.txn_durations()))?;
+        if db_path.is_some() {
+            registry.register(Box::new(db_file_size.clone()))?;
+        }
+        registry.register(Box::new(accounts.clone()))?;
+        registry.register(Box::new(metrics.clone()))?;
+        registry.register(Box::new(data_points.clone()))?;
+
+        Ok(Self {
+            registry,
+            http_requests,
+            http_request_durations,
+            rate_limited_requests,
+            line_protocol_lines,
+            db_file_size,
+            accounts,
+            metrics,
+            data_points,
+            db_path,
+        })
+    }
+
+    /// `route` is the matched route pattern (like `/m/:metric`), to keep the
+    /// number of label values bounded
+    pub fn observe_request(
+        &self,
+        method: &Method,
+        route: &str,
+        status: StatusCode,
+        duration: Duration,
+    ) {
+        self.http_requests
+            .with_label_values(&[method.as_str(), route, status.as_str()])
+            .inc();
+        self.http_request_durations
+            .with_label_values(&[method.as_str(), route])
+            .observe(duration.as_secs_f64());
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            self.rate_limited_requests.inc();
+        }
+    }
+
+    pub fn observe_line_protocol_line(&self, outcome: LineOutcome) {
+        self.line_protocol_lines
+            .with_label_values(&[outcome.as_str()])
+            .inc();
+    }
+
+    /// Current values of everything, in the Prometheus text format
+    pub async fn render(&self, db: &Database) -> Result<String> {
+        if let Some(db_path) = &self.db_path {
+            let len = tokio::fs::metadata(db_path).await?.len();
+            self.db_file_size.set(len.try_into().unwrap_or(i64::MAX));
+        }
+        let counts = db.read_with(|db| db.counts()).await?;
+        self.accounts
+            .set(counts.accounts.try_into().unwrap_or(i64::MAX));
+        self.metrics
+            .set(counts.metrics.try_into().unwrap_or(i64::MAX));
+        self.data_points
+            .set(counts.data_points.try_into().unwrap_or(i64::MAX));
+
+        let mut buf = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
@@ -4,47 +4,33 @@ use std::sync::Arc;
 use tracing::info;
 
 use crate::asset_cache::AssetCache;
-use crate::db::{
-    AccessTokenRecord, AccountRecord, Database, ROOT_ACCOUNT_ID, TABLE_ACCESS_TOKENS,
-    TABLE_ACCESS_TOKENS_REV, TABLE_ACCOUNTS,
-};
+use crate::db::{AccessTokenRecord, AccountRecord, Database, ROOT_ACCOUNT_ID};
 use crate::models::access_token::AccessToken;
 use crate::models::ts::Ts;
 use crate::models::{AccessTokenType, MetricId};
+use crate::retention::RetentionState;
+use crate::server_metrics::ServerMetrics;
 
 #[derive(Debug)]
 pub struct AppState {
     pub db: Database,
     pub assets: AssetCache,
     pub req_counter: AtomicU64,
+    pub retention: RetentionState,
+    pub server_metrics: ServerMetrics,
 }
 
 impl AppState {
     pub async fn init_root_account(&self, access_token: &AccessToken) -> color_eyre::Result<()> {
+        let access_token = *access_token;
         self.db
-            .write_with(|tx| {
-                tx.open_table(&TABLE_ACCOUNTS)?
-                    .insert(&ROOT_ACCOUNT_ID, &AccountRecord { created: Ts::now() })?;
+            .write_with(move |db| {
+                db.account_new(ROOT_ACCOUNT_ID, AccountRecord { created: Ts::now() })?;
 
-                let access_tokens_table = &mut tx.open_table(&TABLE_ACCESS_TOKENS)?;
-
-                let access_tokens_rev_table = &mut tx.open_table(&TABLE_ACCESS_TOKENS_REV)?;
-
-                if access_tokens_table.get(access_token)?.is_none() {
+                if db.access_token_get(access_token)?.is_none() {
                     info!("Setting new root account access token");
-                    let existing_root_account_access_tokens: Vec<_> = access_tokens_rev_table
-                        .range(
-                            &(ROOT_ACCOUNT_ID, AccessToken::ZERO)
-                                ..=&(ROOT_ACCOUNT_ID, AccessToken::LAST),
-                        )?
-                        .map(|existing| {
-                            let (k, _) = existing?;
-                            let (account_id_db, access_token) = k.value();
-                            debug_assert_eq!(ROOT_ACCOUNT_ID, account_id_db);
-
-                            Ok(access_token)
-                        })
-                        .collect::<color_eyre::Result<Vec<_>>>()?;
+                    let existing_root_account_access_tokens =
+                        db.account_access_tokens(ROOT_ACCOUNT_ID)?;
 
                     if !existing_root_account_access_tokens.is_empty() {
                         info!(
@@ -53,15 +39,12 @@ impl AppState {
                         );
                     }
                     for existing in existing_root_account_access_tokens {
-                        access_tokens_rev_table.remove(&(ROOT_ACCOUNT_ID, existing))?;
-                        access_tokens_table.remove(&existing)?;
+                        db.access_token_remove(existing)?;
                     }
 
-                    access_tokens_rev_table.insert(&(ROOT_ACCOUNT_ID, *access_token), &())?;
-
-                    access_tokens_table.insert(
+                    db.access_token_new(
                         access_token,
-                        &AccessTokenRecord {
+                        AccessTokenRecord {
                             created: Ts::now(),
                             r#type: AccessTokenType::Root,
                             account_id: ROOT_ACCOUNT_ID,
@@ -77,6 +60,9 @@ impl AppState {
     pub fn svg_chart_url(&self, metric_id: MetricId) -> String {
         format!("/m/{}/svg", metric_id)
     }
+    pub fn badge_url(&self, metric_id: MetricId) -> String {
+        format!("/m/{}/badge", metric_id)
+    }
     pub fn json_chart_url(&self, metric_id: MetricId) -> String {
         format!("/m/{}/json", metric_id)
     }
@@ -0,0 +1,49 @@
+//! Simple statistics over data point values
+
+/// Sorted copy of `values`, with NaNs removed
+pub fn sorted(values: impl IntoIterator<Item = f64>) -> Vec<f64> {
+    let mut sorted: Vec<_> = values.into_iter().filter(|v| !v.is_nan()).collect();
+    sorted.sort_by(f64::total_cmp);
+    sorted
+}
+
+/// Nearest-rank percentile (`p` in `0.0..=1.0`) of already `sorted` values
+pub fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let idx = (p.clamp(0., 1.) * (sorted.len() - 1) as f64).round() as usize;
+    Some(sorted[idx])
+}
+
+/// Median of already `sorted` values
+pub fn median(sorted: &[f64]) -> Option<f64> {
+    let len = sorted.len();
+    if len == 0 {
+        return None;
+    }
+    Some(if len % 2 == 1 {
+        sorted[len / 2]
+    } else {
+        (sorted[len / 2 - 1] + sorted[len / 2]) / 2.
+    })
+}
+
+/// Arithmetic mean of `values`
+pub fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// Sample standard deviation of `values`, `0` for a single one
+pub fn stddev(values: &[f64]) -> Option<f64> {
+    let mean = mean(values)?;
+    if values.len() == 1 {
+        return Some(0.);
+    }
+    let variance =
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+    Some(variance.sqrt())
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn annotation_markers() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let bin = get_cargo_bin("perfit");
+            let (access_token, metric_id, other_metric_id) = tokio::task::spawn_blocking({
+                let root_access_token = root_access_token.clone();
+                move || -> Result<_> {
+                    let (access_token, metric_id) =
+                        common::new_account_with_metric(addr, &root_access_token)?;
+
+                    let other_metric_id: String = serde_json::from_str(
+                        &duct::cmd!(&bin, "metric", "new")
+                            .env("PERFIT_SERVER", format!("http://{}", addr))
+                            .env("PERFIT_ACCESS_TOKEN", &access_token)
+                            .stdout_capture()
+                            .read()?,
+                    )?;
+
+                    for v in ["1", "2"] {
+                        duct::cmd!(&bin, "post", v)
+                            .env("PERFIT_SERVER", format!("http://{}", addr))
+                            .env("PERFIT_ACCESS_TOKEN", &access_token)
+                            .env("PERFIT_METRIC", &metric_id)
+                            .run()?;
+                    }
+
+                    duct::cmd!(
+                        &bin,
+                        "annotate",
+                        "upgraded compiler",
+                        "--url",
+                        "https://example.com/pr/1"
+                    )
+                    .env("PERFIT_SERVER", format!("http://{}", addr))
+                    .env("PERFIT_ACCESS_TOKEN", &access_token)
+                    .run()?;
+
+                    duct::cmd!(&bin, "annotate", "<script>alert(1)</script> & \"more\"")
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .run()?;
+
+                    duct::cmd!(
+                        &bin,
+                        "annotate",
+                        "other metric only",
+                        "--metric",
+                        &other_metric_id
+                    )
+                    .env("PERFIT_SERVER", format!("http://{}", addr))
+                    .env("PERFIT_ACCESS_TOKEN", &access_token)
+                    .run()?;
+
+                    Ok((access_token, metric_id, other_metric_id))
+                }
+            })
+            .await??;
+
+            let client = reqwest::Client::new();
+
+            let annotations: Vec<serde_json::Value> = client
+                .get(format!("http://{addr}/n/?metric-id={metric_id}"))
+                .bearer_auth(&access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            assert_eq!(annotations.len(), 2);
+            let annotation = annotations
+                .iter()
+                .find(|a| a["label"] == "upgraded compiler")
+                .expect("annotation");
+            assert_eq!(annotation["url"], "https://example.com/pr/1");
+
+            let svg = reqwest::get(format!("http://{addr}/m/{metric_id}/svg"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            // Labels are drawn on the chart, next to their line
+            assert!(svg.contains(">upgraded compiler</text>"));
+            assert_eq!(svg.matches(r#"class="perfit_annotation""#).count(), 2);
+            assert!(!svg.contains("other metric only"));
+            // Labels are user input, and must not inject markup into the svg
+            assert!(svg.contains("&lt;script&gt;alert(1)&lt;/script&gt; &amp; &quot;more&quot;<"));
+            assert!(!svg.contains("<script>"));
+
+            // Can't scope an annotation to a metric of a different account
+            let (foreign_access_token, _) = tokio::task::spawn_blocking(move || {
+                common::new_account_with_metric(addr, &root_access_token)
+            })
+            .await??;
+            let resp = client
+                .put(format!("http://{addr}/n/"))
+                .bearer_auth(&foreign_access_token)
+                .json(&serde_json::json!({
+                    "label": "sneaky",
+                    "metric-id": other_metric_id,
+                }))
+                .send()
+                .await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+            // Post tokens are for sending data points, not for annotating
+            let post_access_token: serde_json::Value = client
+                .put(format!("http://{addr}/t/"))
+                .bearer_auth(&access_token)
+                .json(&serde_json::json!({ "type": "Post" }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let post_access_token = post_access_token["access_token"].as_str().expect("token");
+            let resp = client
+                .put(format!("http://{addr}/n/"))
+                .bearer_auth(post_access_token)
+                .json(&serde_json::json!({ "label": "from post token" }))
+                .send()
+                .await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+            let annotation_id = annotation["id"].as_str().expect("id");
+            let resp = client
+                .delete(format!("http://{addr}/n/{annotation_id}"))
+                .bearer_auth(post_access_token)
+                .send()
+                .await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+            client
+                .delete(format!("http://{addr}/n/{annotation_id}"))
+                .bearer_auth(&access_token)
+                .send()
+                .await?
+                .error_for_status()?;
+
+            let svg = reqwest::get(format!("http://{addr}/m/{metric_id}/svg"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            assert!(!svg.contains("upgraded compiler"));
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn backup_and_restore() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let bin = get_cargo_bin("perfit");
+            let (access_token, metric_id) = tokio::task::spawn_blocking({
+                let root_access_token = root_access_token.clone();
+                move || -> Result<_> {
+                    let (access_token, metric_id) =
+                        common::new_account_with_metric(addr, &root_access_token)?;
+
+                    for v in ["1", "2"] {
+                        duct::cmd!(&bin, "post", v)
+                            .env("PERFIT_SERVER", format!("http://{}", addr))
+                            .env("PERFIT_ACCESS_TOKEN", &access_token)
+                            .env("PERFIT_METRIC", &metric_id)
+                            .run()?;
+                    }
+
+                    Ok((access_token, metric_id))
+                }
+            })
+            .await??;
+
+            let client = reqwest::Client::new();
+
+            let resp = client
+                .get(format!("http://{addr}/backup"))
+                .bearer_auth(&access_token)
+                .send()
+                .await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+            let backup = client
+                .get(format!("http://{addr}/backup"))
+                .bearer_auth(&root_access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+
+            let data_points = reqwest::get(format!("http://{addr}/m/{metric_id}/json"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+
+            let restore_dir = tempfile::tempdir()?;
+            let backup_path = restore_dir.path().join("backup.redb");
+            let db_path = restore_dir.path().join("db.redb");
+            tokio::fs::write(&backup_path, &backup).await?;
+
+            let garbage_path = restore_dir.path().join("garbage.redb");
+            tokio::fs::write(&garbage_path, b"not a database").await?;
+
+            tokio::task::spawn_blocking({
+                let db_path = db_path.clone();
+                let restore_dir_path = restore_dir.path().to_owned();
+                move || -> Result<_> {
+                    let perfitd = get_cargo_bin("perfitd");
+                    assert!(
+                        !duct::cmd!(&perfitd, "--db", &db_path, "restore", &garbage_path)
+                            .stderr_null()
+                            .unchecked()
+                            .run()?
+                            .status
+                            .success()
+                    );
+                    assert!(!db_path.exists());
+
+                    // Backing up a database that doesn't exist must not create it
+                    let missing_path = restore_dir_path.join("missing.redb");
+                    let missing_backup_path = restore_dir_path.join("missing-backup.redb");
+                    assert!(!duct::cmd!(
+                        &perfitd,
+                        "--db",
+                        &missing_path,
+                        "backup",
+                        &missing_backup_path
+                    )
+                    .stderr_null()
+                    .unchecked()
+                    .run()?
+                    .status
+                    .success());
+                    assert!(!missing_path.exists());
+                    assert!(!missing_backup_path.exists());
+
+                    duct::cmd!(&perfitd, "--db", &db_path, "restore", &backup_path).run()?;
+
+                    // The database replaced by the first restore is never overwritten
+                    let backup_copy_path = restore_dir_path.join("backup-copy.redb");
+                    duct::cmd!(&perfitd, "--db", &db_path, "backup", &backup_copy_path).run()?;
+                    duct::cmd!(&perfitd, "--db", &db_path, "restore", &backup_copy_path).run()?;
+                    assert!(
+                        !duct::cmd!(&perfitd, "--db", &db_path, "restore", &backup_copy_path)
+                            .stderr_null()
+                            .unchecked()
+                            .run()?
+                            .status
+                            .success()
+                    );
+                    Ok(())
+                }
+            })
+            .await??;
+
+            let restored = PerfitdFixture::new_with_db(restore_dir, db_path).await?;
+            let restored_addr = restored.addr()?;
+            restored
+                .run(async {
+                    let restored_data_points =
+                        reqwest::get(format!("http://{restored_addr}/m/{metric_id}/json"))
+                            .await?
+                            .error_for_status()?
+                            .text()
+                            .await?;
+                    assert_eq!(data_points, restored_data_points);
+                    Ok(())
+                })
+                .await
+        })
+        .await
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chart_svg_opts() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let bin = get_cargo_bin("perfit");
+            let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                for v in ["1", "2"] {
+                    duct::cmd!(&bin, "post", v)
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .env("PERFIT_METRIC", &metric_id)
+                        .run()?;
+                }
+
+                Ok(metric_id)
+            })
+            .await??;
+
+            let svg = reqwest::get(format!(
+                "http://{addr}/m/{metric_id}/svg?theme=dark&width=400&height=300&style=points"
+            ))
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+            assert!(svg.starts_with(r#"<svg class="poloto" width="400" height="300""#));
+            assert!(svg.contains(".poloto_background{fill:#262626;}"));
+            assert!(!svg.contains("prefers-color-scheme"));
+
+            let svg = reqwest::get(format!("http://{addr}/m/{metric_id}/svg?theme=auto"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+
+            assert!(svg.contains("@media (prefers-color-scheme: dark)"));
+
+            Ok(())
+        })
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chart_badge() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let bin = get_cargo_bin("perfit");
+            let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                for v in ["10", "10", "30"] {
+                    duct::cmd!(&bin, "post", v)
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .env("PERFIT_METRIC", &metric_id)
+                        .run()?;
+                }
+
+                Ok(metric_id)
+            })
+            .await??;
+
+            let badge = |query: &'static str| {
+                let metric_id = metric_id.clone();
+                async move {
+                    reqwest::get(format!("http://{addr}/m/{metric_id}/badge?{query}"))
+                        .await?
+                        .error_for_status()?
+                        .text()
+                        .await
+                }
+            };
+
+            // latest value is 3x the trailing median
+            let svg = badge("label=build&unit=s").await?;
+            assert!(svg.contains("<title>build: 30.0s</title>"));
+            assert!(svg.contains(r##"fill="#e05d44""##));
+            assert!(!svg.contains("<polyline"));
+
+            let svg = badge("warn=40&crit=50&sparkline=true").await?;
+            assert!(svg.contains(r##"fill="#4c1""##));
+            assert!(svg.contains("<polyline"));
+
+            // The chart page links to the badge with its current options
+            let html = reqwest::get(format!("http://{addr}/m/{metric_id}?outcome=failure"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            assert!(html.contains(&format!("/m/{metric_id}/badge?outcome=failure")));
+
+            Ok(())
+        })
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chart_y_axis() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let bin = get_cargo_bin("perfit");
+            let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                for v in ["1", "10", "1000"] {
+                    duct::cmd!(&bin, "post", v)
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .env("PERFIT_METRIC", &metric_id)
+                        .run()?;
+                }
+
+                Ok(metric_id)
+            })
+            .await??;
+
+            let svg = reqwest::get(format!("http://{addr}/m/{metric_id}/svg?y-scale=log"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            assert!(svg.contains(">1000</tspan>"));
+            assert!(!svg.contains("outliers"));
+
+            let svg = reqwest::get(format!("http://{addr}/m/{metric_id}/svg?max=100"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            assert!(svg.contains("outliers"));
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+/// `perfit post` adds the commit, branch and job of a GitHub Actions run to
+/// the metadata, and the chart links the data points to them
+#[tokio::test(flavor = "multi_thread")]
+async fn ci_metadata_is_detected_and_linked() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                let post = |value: &str, extra_args: &[&str]| {
+                    let mut args = vec!["post", "--metadata", "host=ci-1"];
+                    args.extend_from_slice(extra_args);
+                    args.push(value);
+                    duct::cmd(get_cargo_bin("perfit"), args)
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .env("PERFIT_METRIC", &metric_id)
+                        .env("GITHUB_ACTIONS", "true")
+                        .env("GITHUB_SHA", "0123456789abcdef0123456789abcdef01234567")
+                        .env("GITHUB_HEAD_REF", "")
+                        .env("GITHUB_REF_NAME", "main")
+                        .env("GITHUB_SERVER_URL", "https://github.com")
+                        .env("GITHUB_REPOSITORY", "rustshop/perfit")
+                        .env("GITHUB_RUN_ID", "42")
+                        .run()
+                };
+                post("1", &[])?;
+                post("2", &["--no-auto-metadata"])?;
+
+                Ok(metric_id)
+            })
+            .await??;
+
+            let data_points: Vec<serde_json::Value> =
+                reqwest::get(format!("http://{addr}/m/{metric_id}/json"))
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+            assert_eq!(
+                data_points
+                    .iter()
+                    .map(|d| d["m"].as_str().unwrap_or_default())
+                    .collect::<Vec<_>>(),
+                [
+                    "host=ci-1,commit=0123456789abcdef0123456789abcdef01234567,branch=main,\
+                     job=https://github.com/rustshop/perfit/actions/runs/42,\
+                     repo=https://github.com/rustshop/perfit",
+                    "host=ci-1"
+                ]
+            );
+
+            let html = reqwest::get(format!("http://{addr}/m/{metric_id}"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            assert!(html.contains(
+                r#"href="https://github.com/rustshop/perfit/commit/0123456789abcdef0123456789abcdef01234567""#
+            ));
+
+            // The point itself links to the commit, even in the exported chart
+            let svg = reqwest::get(format!("http://{addr}/m/{metric_id}/svg"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            assert!(svg.contains(
+                r#"<a href="https://github.com/rustshop/perfit/commit/0123456789abcdef0123456789abcdef01234567">"#
+            ));
+            assert!(svg.contains("branch: main"));
+            assert!(svg.contains("job: https://github.com/rustshop/perfit/actions/runs/42"));
+            assert_eq!(svg.matches("<a href=").count(), 1);
+
+            Ok(())
+        })
+        .await
+}
@@ -1,10 +1,18 @@
+//! Helpers shared by the integration tests
+//!
+//! Every test binary includes this module but uses only some of it, so the
+//! helpers not used by all of them allow `dead_code`.
+
 use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use color_eyre::Result;
 use futures::Future;
+use insta_cmd::get_cargo_bin;
 use perfitd::models::access_token::AccessToken;
 use perfitd::{opts, Server};
+use serde::Deserialize;
 use tempfile::TempDir;
 use tracing_subscriber::EnvFilter;
 
@@ -25,16 +33,52 @@ pub struct PerfitdFixture {
 }
 
 impl PerfitdFixture {
+    #[allow(dead_code)]
     pub async fn new() -> Result<Self> {
         let test_dir = tempfile::tempdir()?;
+        let db = test_dir.path().join("db.redb");
+
+        Self::new_with_db(test_dir, db).await
+    }
+
+    /// Like [`Self::new`], but keeping the data in memory only
+    #[allow(dead_code)]
+    pub async fn new_in_memory() -> Result<Self> {
+        let test_dir = tempfile::tempdir()?;
+        let db = test_dir.path().join("db.redb");
 
+        Self::new_with_opts(
+            test_dir,
+            opts::Opts {
+                db,
+                in_memory: true,
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Like [`Self::new`], but using an existing database file
+    #[allow(dead_code)]
+    pub async fn new_with_db(test_dir: TempDir, db: PathBuf) -> Result<Self> {
+        Self::new_with_opts(
+            test_dir,
+            opts::Opts {
+                db,
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Like [`Self::new`], but with custom server options
+    pub async fn new_with_opts(test_dir: TempDir, opts: opts::Opts) -> Result<Self> {
         let root_access_token = AccessToken::generate();
 
         let opts = opts::Opts {
             listen: "[::1]:0".into(),
-            db: test_dir.path().join("db.redb"),
             root_access_token: Some(root_access_token),
-            ..Default::default()
+            ..opts
         };
 
         let server = perfitd::Server::init(opts).await?;
@@ -50,6 +94,11 @@ impl PerfitdFixture {
         self.server.addr()
     }
 
+    #[allow(dead_code)]
+    pub fn line_protocol_addr(&self) -> Result<Option<SocketAddr>> {
+        self.server.line_protocol_addr()
+    }
+
     pub fn root_access_token_str(&self) -> String {
         self.root_access_token.to_string()
     }
@@ -65,3 +114,36 @@ impl PerfitdFixture {
         }
     }
 }
+
+#[derive(Deserialize)]
+struct NewAccountOutput {
+    access_token: String,
+}
+
+/// Create a new account and a metric in it using `perfit` CLI
+///
+/// Returns admin access token of the account and the metric id.
+#[allow(dead_code)]
+pub fn new_account_with_metric(
+    addr: SocketAddr,
+    root_access_token: &str,
+) -> Result<(String, String)> {
+    let bin = get_cargo_bin("perfit");
+    let NewAccountOutput { access_token } = serde_json::from_str(
+        &duct::cmd!(&bin, "account", "new")
+            .env("PERFIT_SERVER", format!("http://{}", addr))
+            .env("PERFIT_ACCESS_TOKEN", root_access_token)
+            .stdout_capture()
+            .read()?,
+    )?;
+
+    let metric_id: String = serde_json::from_str(
+        &duct::cmd!(&bin, "metric", "new")
+            .env("PERFIT_SERVER", format!("http://{}", addr))
+            .env("PERFIT_ACCESS_TOKEN", &access_token)
+            .stdout_capture()
+            .read()?,
+    )?;
+
+    Ok((access_token, metric_id))
+}
@@ -0,0 +1,179 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+/// Server, access token and metric come from the configuration files, with
+/// the project one selecting the profile and overriding the metric aliases
+#[tokio::test(flavor = "multi_thread")]
+async fn config_files() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let (metric_id, values) = tokio::task::spawn_blocking(move || -> Result<_> {
+                let bin = get_cargo_bin("perfit");
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                let config_dir = tempfile::tempdir()?;
+                let user_config = config_dir.path().join("config.toml");
+                std::fs::write(
+                    &user_config,
+                    format!(
+                        r#"
+profile = "other"
+
+[profiles.other]
+server = "http://[::1]:1"
+access-token = "{access_token}"
+
+[profiles.ci]
+server = "http://{addr}"
+access-token = "{access_token}"
+
+[metrics]
+build-time = "unknown"
+"#
+                    ),
+                )?;
+
+                let project_dir = tempfile::tempdir()?;
+                std::fs::write(
+                    project_dir.path().join(".perfit.toml"),
+                    format!(
+                        r#"
+profile = "ci"
+
+[metrics]
+build-time = "{metric_id}"
+"#
+                    ),
+                )?;
+                let work_dir = project_dir.path().join("sub");
+                std::fs::create_dir(&work_dir)?;
+
+                let perfit_with_env = |args: &[&str], env: &[(&str, &str)]| {
+                    let mut full_env: std::collections::HashMap<_, _> = std::env::vars()
+                        .filter(|(k, _)| !k.starts_with("PERFIT_"))
+                        .collect();
+                    full_env.insert(
+                        "PERFIT_CONFIG".into(),
+                        user_config.to_string_lossy().into_owned(),
+                    );
+                    for (k, v) in env {
+                        full_env.insert((*k).into(), (*v).into());
+                    }
+                    duct::cmd(&bin, args).dir(&work_dir).full_env(full_env)
+                };
+                let perfit = |args: &[&str]| perfit_with_env(args, &[]);
+
+                perfit(&["post", "--metric", "build-time", "1"]).run()?;
+
+                let config = perfit(&["config"]).read()?;
+                assert!(config.contains(&format!(r#"server = "http://{addr}/""#)));
+                assert!(config.contains(&format!(r#"build-time = "{metric_id}""#)));
+                assert!(config.contains(r#"profile = "ci""#));
+                assert!(!config.contains(&access_token));
+
+                // Env variables take precedence over the files
+                let spool_dir = config_dir.path().join("spool");
+                let res = perfit_with_env(
+                    &["post", "--metric", "build-time", "2", "--retries", "0"],
+                    &[
+                        ("PERFIT_SERVER", "http://[::1]:1"),
+                        ("PERFIT_SPOOL_DIR", &spool_dir.to_string_lossy()),
+                    ],
+                )
+                .stderr_null()
+                .unchecked()
+                .run()?;
+                assert!(!res.status.success());
+
+                let res = perfit(&[
+                    "post",
+                    "--profile",
+                    "missing",
+                    "--metric",
+                    "build-time",
+                    "3",
+                ])
+                .stderr_null()
+                .unchecked()
+                .run()?;
+                assert!(!res.status.success());
+
+                let values = perfit(&["metric", "get", "--metric", "build-time"]).read()?;
+                Ok((metric_id, values))
+            })
+            .await??;
+
+            let values: Vec<serde_json::Value> = serde_json::from_str(&values)?;
+            assert_eq!(values.len(), 1, "{metric_id}");
+            assert_eq!(values[0]["v"], 1.0);
+
+            Ok(())
+        })
+        .await
+}
+
+/// A project file setting just the server of a profile doesn't get the access
+/// token of the user's profile sent to it
+#[test]
+fn config_project_profile_doesnt_inherit_access_token() -> Result<()> {
+    let config_dir = tempfile::tempdir()?;
+    let user_config = config_dir.path().join("config.toml");
+    std::fs::write(
+        &user_config,
+        r#"
+[profiles.default]
+server = "http://[::1]:1"
+access-token = "user-secret"
+"#,
+    )?;
+
+    let project_dir = tempfile::tempdir()?;
+    std::fs::write(
+        project_dir.path().join(".perfit.toml"),
+        r#"
+[profiles.default]
+server = "http://[::1]:2"
+"#,
+    )?;
+
+    let perfit = |args: &[&str]| {
+        let mut env: std::collections::HashMap<_, _> = std::env::vars()
+            .filter(|(k, _)| !k.starts_with("PERFIT_"))
+            .collect();
+        env.insert(
+            "PERFIT_CONFIG".into(),
+            user_config.to_string_lossy().into_owned(),
+        );
+        duct::cmd(get_cargo_bin("perfit"), args)
+            .dir(project_dir.path())
+            .full_env(env)
+    };
+
+    let config = perfit(&["config"]).read()?;
+    assert!(config.contains(r#"server = "http://[::1]:2/""#), "{config}");
+    assert!(!config.contains("access-token"), "{config}");
+
+    let res = perfit(&["post", "--metric", "AH-57edqR8SiMSRr4ArSWg", "1"])
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+    assert!(!res.status.success());
+    assert!(String::from_utf8_lossy(&res.stderr).contains("No access token set"));
+
+    Ok(())
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use color_eyre::Result;
+use perfitd::db::{DataPointAppend, DataPointRecord, Database, RedbStorage, Storage as _};
+use perfitd::models::ts::Ts;
+use perfitd::models::{AccountId, MetricId, MetricInternalId};
+use perfitd::opts;
+use serde_json::json;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+const POSTERS: usize = 16;
+const POSTS_PER_POSTER: usize = 40;
+const READERS: usize = 4;
+
+/// Many concurrent uploads, with charts being rendered at the same time
+///
+/// All the data points need to make it in, including many in the same
+/// second, and reads shouldn't get stuck behind the writes.
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_posts_and_reads() -> Result<()> {
+    common::init_logging()?;
+
+    let test_dir = tempfile::tempdir()?;
+    let db = test_dir.path().join("db.redb");
+    let fixture = PerfitdFixture::new_with_opts(
+        test_dir,
+        opts::Opts {
+            db,
+            rate_limit_replenish_millis: 1,
+            rate_limit_burst: 100_000,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let (_access_token, metric_id) = tokio::task::spawn_blocking(move || {
+                common::new_account_with_metric(addr, &root_access_token)
+            })
+            .await??;
+
+            let client = reqwest::Client::new();
+
+            let start = Instant::now();
+            let posters = (0..POSTERS)
+                .map(|poster| {
+                    let client = client.clone();
+                    let metric_id = metric_id.clone();
+                    tokio::spawn(async move {
+                        for i in 0..POSTS_PER_POSTER {
+                            client
+                                .post(format!("http://{addr}/m/{metric_id}"))
+                                .json(&json!({ "value": (poster * POSTS_PER_POSTER + i) as f32 }))
+                                .send()
+                                .await?
+                                .error_for_status()?;
+                        }
+                        Ok::<_, color_eyre::Report>(())
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let readers = (0..READERS)
+                .map(|_| {
+                    let client = client.clone();
+                    let metric_id = metric_id.clone();
+                    tokio::spawn(async move {
+                        let mut latencies = vec![];
+                        for _ in 0..POSTS_PER_POSTER {
+                            let start = Instant::now();
+                            client
+                                .get(format!("http://{addr}/m/{metric_id}/badge"))
+                                .send()
+                                .await?
+                                .error_for_status()?
+                                .bytes()
+                                .await?;
+                            latencies.push(start.elapsed());
+                        }
+                        Ok::<_, color_eyre::Report>(latencies)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for poster in posters {
+                poster.await??;
+            }
+            info!(elapsed = ?start.elapsed(), "All posts done");
+            let mut latencies = vec![];
+            for reader in readers {
+                latencies.extend(reader.await??);
+            }
+            latencies.sort();
+            let p99 = latencies[latencies.len() * 99 / 100];
+            info!(?p99, max = ?latencies.last(), "Read latencies under write load");
+            // Generous, to not be flaky on busy CI machines; a read stuck
+            // behind all the writes would take way longer
+            assert!(p99 < Duration::from_secs(5), "p99: {p99:?}");
+
+            let data_points: Vec<serde_json::Value> = client
+                .get(format!("http://{addr}/m/{metric_id}/json"))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let mut values = data_points
+                .iter()
+                .map(|data_point| data_point["v"].as_f64().expect("value is a number") as usize)
+                .collect::<Vec<_>>();
+            values.sort();
+            assert_eq!(values, (0..POSTERS * POSTS_PER_POSTER).collect::<Vec<_>>());
+
+            Ok(())
+        })
+        .await
+}
+
+/// Post latency through the database executor vs. running every append in
+/// `block_in_place` on the Tokio worker threads, like before the executor
+#[tokio::test(flavor = "multi_thread")]
+async fn executor_improves_tail_latency() -> Result<()> {
+    common::init_logging()?;
+
+    let test_dir = tempfile::tempdir()?;
+
+    let storage = Arc::new(RedbStorage::open(test_dir.path().join("baseline.redb")).await?);
+    let baseline_p99 = append_p99(move |append| {
+        let storage = storage.clone();
+        async move {
+            tokio::task::block_in_place(|| storage.data_points_append(&[append]))?;
+            Ok(())
+        }
+    })
+    .await?;
+
+    let db = Database::open(test_dir.path().join("db.redb"), None).await?;
+    let p99 = append_p99(move |append| {
+        let db = db.clone();
+        async move {
+            db.data_point_append(append).await?;
+            Ok(())
+        }
+    })
+    .await?;
+
+    info!(?p99, ?baseline_p99, "Append latencies under write load");
+    assert!(
+        p99 < baseline_p99,
+        "p99: {p99:?}, baseline: {baseline_p99:?}"
+    );
+
+    Ok(())
+}
+
+/// p99 latency of `append` called by many concurrent posters
+async fn append_p99<F>(
+    append: impl Fn(DataPointAppend) -> F + Clone + Send + 'static,
+) -> Result<Duration>
+where
+    F: Future<Output = Result<()>> + Send,
+{
+    let posters = (0..POSTERS)
+        .map(|poster| {
+            let append = append.clone();
+            tokio::spawn(async move {
+                let mut latencies = vec![];
+                for i in 0..POSTS_PER_POSTER {
+                    let start = Instant::now();
+                    append(DataPointAppend {
+                        metric_id: MetricId::ZERO,
+                        account_id: AccountId::ZERO,
+                        metric_internal_id: MetricInternalId::default(),
+                        ts: Ts::now(),
+                        record: DataPointRecord {
+                            value: ((poster * POSTS_PER_POSTER + i) as f32).into(),
+                            metadata: Default::default(),
+                            outcome: Default::default(),
+                        },
+                        idempotency_key: None,
+                    })
+                    .await?;
+                    latencies.push(start.elapsed());
+                }
+                Ok::<_, color_eyre::Report>(latencies)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut latencies = vec![];
+    for poster in posters {
+        latencies.extend(poster.await??);
+    }
+    latencies.sort();
+    Ok(latencies[latencies.len() * 99 / 100])
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dump_load_round_trip() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let bin = get_cargo_bin("perfit");
+            tokio::task::spawn_blocking({
+                let root_access_token = root_access_token.clone();
+                move || -> Result<_> {
+                    let (access_token, metric_id) =
+                        common::new_account_with_metric(addr, &root_access_token)?;
+
+                    // Same second data points, non-trivial values and metadata
+                    for v in ["1", "0.1", "123456.79"] {
+                        duct::cmd!(&bin, "post", v, "--metadata", "sha=abc \"quoted\"")
+                            .env("PERFIT_SERVER", format!("http://{}", addr))
+                            .env("PERFIT_ACCESS_TOKEN", &access_token)
+                            .env("PERFIT_METRIC", &metric_id)
+                            .run()?;
+                    }
+                    duct::cmd!(&bin, "token", "new")
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .stdout_null()
+                        .run()?;
+                    duct::cmd!(&bin, "annotate", "event", "--metric", &metric_id)
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .stdout_null()
+                        .run()?;
+
+                    Ok(())
+                }
+            })
+            .await??;
+
+            // The database of a running server is locked, so work on a backup
+            let backup = reqwest::Client::new()
+                .get(format!("http://{addr}/backup"))
+                .bearer_auth(&root_access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+
+            let dir = tempfile::tempdir()?;
+            let db_path = dir.path().join("backup.redb");
+            tokio::fs::write(&db_path, &backup).await?;
+
+            tokio::task::spawn_blocking(move || -> Result<_> {
+                let perfitd = get_cargo_bin("perfitd");
+                let dump_path = dir.path().join("dump.jsonl");
+                let loaded_db_path = dir.path().join("loaded.redb");
+                let redump_path = dir.path().join("redump.jsonl");
+
+                duct::cmd!(&perfitd, "--db", &db_path, "dump", &dump_path).run()?;
+                duct::cmd!(&perfitd, "--db", &loaded_db_path, "load", &dump_path).run()?;
+                duct::cmd!(&perfitd, "--db", &loaded_db_path, "dump", &redump_path).run()?;
+
+                let dump = std::fs::read_to_string(&dump_path)?;
+                assert!(dump.starts_with(r#"{"format":"perfitd-dump","version":1}"#));
+                // header, 2 accounts, 3 tokens, 1 metric, 3 data points, 1 annotation
+                assert_eq!(dump.lines().count(), 11);
+                assert_eq!(dump, std::fs::read_to_string(&redump_path)?);
+
+                // Never overwrites an existing database
+                assert!(
+                    !duct::cmd!(&perfitd, "--db", &loaded_db_path, "load", &dump_path)
+                        .stderr_null()
+                        .unchecked()
+                        .run()?
+                        .status
+                        .success()
+                );
+
+                // Never creates a database that doesn't exist
+                let missing_path = dir.path().join("missing.redb");
+                assert!(!duct::cmd!(&perfitd, "--db", &missing_path, "dump")
+                    .stdout_null()
+                    .stderr_null()
+                    .unchecked()
+                    .run()?
+                    .status
+                    .success());
+                assert!(!missing_path.exists());
+                Ok(())
+            })
+            .await??;
+
+            Ok(())
+        })
+        .await
+}
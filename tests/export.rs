@@ -0,0 +1,150 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use serde_json::json;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn export_to_prometheus_and_grafana() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let bin = get_cargo_bin("perfit");
+            let perfit = {
+                let bin = bin.clone();
+                move |args: Vec<&str>, access_token: &str| {
+                    duct::cmd(&bin, args)
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", access_token)
+                        .read()
+                }
+            };
+            let (account_id, access_token, metric_id) = tokio::task::spawn_blocking({
+                let perfit = perfit.clone();
+                move || -> Result<_> {
+                    let account: serde_json::Value =
+                        serde_json::from_str(&perfit(vec!["account", "new"], &root_access_token)?)?;
+                    let access_token = account["access_token"].as_str().unwrap().to_owned();
+                    let metric_id: String =
+                        serde_json::from_str(&perfit(vec!["metric", "new"], &access_token)?)?;
+
+                    for v in ["1", "2"] {
+                        perfit(
+                            vec!["post", v, &format!("--metric={metric_id}")],
+                            &access_token,
+                        )?;
+                    }
+
+                    Ok((account["account_id"].clone(), access_token, metric_id))
+                }
+            })
+            .await??;
+            let account_id = account_id.as_str().unwrap().to_owned();
+
+            let client = reqwest::Client::new();
+
+            // Not public until enabled
+            let resp = client
+                .get(format!("http://{addr}/a/{account_id}/metrics"))
+                .send()
+                .await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+            tokio::task::spawn_blocking({
+                let perfit = perfit.clone();
+                let access_token = access_token.clone();
+                move || perfit(vec!["account", "export"], &access_token)
+            })
+            .await??;
+
+            let metrics = client
+                .get(format!("http://{addr}/a/{account_id}/metrics"))
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            let expected = format!(r#"perfit_metric_value{{metric_id="{metric_id}"}} 2"#);
+            assert!(
+                metrics.contains(&expected),
+                "Missing `{expected}` in:\n{metrics}"
+            );
+
+            client
+                .get(format!("http://{addr}/a/{account_id}/grafana/"))
+                .send()
+                .await?
+                .error_for_status()?;
+
+            let search: serde_json::Value = client
+                .post(format!("http://{addr}/a/{account_id}/grafana/search"))
+                .json(&json!({ "target": "" }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            assert_eq!(search, json!([metric_id]));
+
+            let now = OffsetDateTime::now_utc();
+            let query = |target: &str| {
+                json!({
+                    "range": {
+                        "from": (now - Duration::hours(1)).format(&Rfc3339).unwrap(),
+                        "to": (now + Duration::hours(1)).format(&Rfc3339).unwrap(),
+                    },
+                    "targets": [{ "target": target, "refId": "A" }],
+                })
+            };
+            let series: serde_json::Value = client
+                .post(format!("http://{addr}/a/{account_id}/grafana/query"))
+                .json(&query(&metric_id))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            assert_eq!(series[0]["target"], json!(metric_id));
+            let values = series[0]["datapoints"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|data_point| data_point[0].clone())
+                .collect::<Vec<_>>();
+            assert_eq!(values, vec![json!(1.0), json!(2.0)]);
+
+            // Metrics of other accounts can't be queried
+            let resp = client
+                .post(format!("http://{addr}/a/{account_id}/grafana/query"))
+                .json(&query("AQEBAQEBAQEBAQEBAQEBAQ"))
+                .send()
+                .await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+            tokio::task::spawn_blocking(move || {
+                perfit(vec!["account", "export", "--disable"], &access_token)
+            })
+            .await??;
+            let resp = client
+                .get(format!("http://{addr}/a/{account_id}/metrics"))
+                .send()
+                .await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+            Ok(())
+        })
+        .await
+}
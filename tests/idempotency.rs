@@ -0,0 +1,165 @@
+mod common;
+
+use std::time::Duration;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use perfitd::opts;
+use serde_json::json;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+async fn data_point_values(
+    client: &reqwest::Client,
+    addr: std::net::SocketAddr,
+    metric_id: &str,
+) -> Result<Vec<serde_json::Value>> {
+    let data_points: Vec<serde_json::Value> = client
+        .get(format!("http://{addr}/m/{metric_id}/json"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(data_points.iter().map(|d| d["v"].clone()).collect())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn repeated_posts_are_recorded_once() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                for _ in 0..2 {
+                    duct::cmd!(
+                        get_cargo_bin("perfit"),
+                        "post",
+                        "--idempotency-key",
+                        "cli",
+                        "1"
+                    )
+                    .env("PERFIT_SERVER", format!("http://{}", addr))
+                    .env("PERFIT_ACCESS_TOKEN", &access_token)
+                    .env("PERFIT_METRIC", &metric_id)
+                    .run()?;
+                }
+
+                Ok(metric_id)
+            })
+            .await??;
+
+            let client = reqwest::Client::new();
+            let post = |payload: serde_json::Value, key: Option<&'static str>| {
+                let client = client.clone();
+                let metric_id = metric_id.clone();
+                async move {
+                    let mut request = client.post(format!("http://{addr}/m/{metric_id}"));
+                    if let Some(key) = key {
+                        request = request.header("Idempotency-Key", key);
+                    }
+                    Ok::<_, color_eyre::Report>(request.json(&payload).send().await?)
+                }
+            };
+
+            let ts: u64 = post(
+                json!({ "value": 2.0, "ts": "2024-01-01T00:00:00Z" }),
+                Some("a"),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+            // The original time is returned, even though the payload differs
+            let repeated_ts: u64 = post(json!({ "value": 3.0 }), Some("a"))
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            assert_eq!(ts, repeated_ts);
+            let repeated_ts: u64 = post(json!({ "value": 3.0, "idempotency-key": "a" }), None)
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            assert_eq!(ts, repeated_ts);
+
+            post(json!({ "value": 4.0 }), Some("b"))
+                .await?
+                .error_for_status()?;
+
+            let too_long = "k".repeat(200);
+            let resp = post(json!({ "value": 5.0, "idempotency-key": too_long }), None).await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+
+            let mut values = data_point_values(&client, addr, &metric_id).await?;
+            values.sort_by(|a, b| a.as_f64().partial_cmp(&b.as_f64()).expect("not NaN"));
+            assert_eq!(values, vec![json!(1.0), json!(2.0), json!(4.0)]);
+
+            Ok(())
+        })
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idempotency_keys_expire() -> Result<()> {
+    common::init_logging()?;
+
+    let test_dir = tempfile::tempdir()?;
+    let db = test_dir.path().join("db.redb");
+    let fixture = PerfitdFixture::new_with_opts(
+        test_dir,
+        opts::Opts {
+            db,
+            retention_interval_secs: 1,
+            idempotency_key_ttl_secs: 0,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let (_access_token, metric_id) = tokio::task::spawn_blocking(move || {
+                common::new_account_with_metric(addr, &root_access_token)
+            })
+            .await??;
+
+            let client = reqwest::Client::new();
+            let post = || {
+                client
+                    .post(format!("http://{addr}/m/{metric_id}"))
+                    .header("Idempotency-Key", "a")
+                    .json(&json!({ "value": 1.0 }))
+                    .send()
+            };
+
+            post().await?.error_for_status()?;
+            post().await?.error_for_status()?;
+            assert_eq!(data_point_values(&client, addr, &metric_id).await?.len(), 1);
+
+            // Gone after the next pruning
+            tokio::time::sleep(Duration::from_millis(2500)).await;
+            post().await?.error_for_status()?;
+            assert_eq!(data_point_values(&client, addr, &metric_id).await?.len(), 2);
+
+            Ok(())
+        })
+        .await
+}
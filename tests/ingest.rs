@@ -0,0 +1,245 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+async fn data_point_values(
+    client: &reqwest::Client,
+    addr: std::net::SocketAddr,
+    metric_id: &str,
+) -> Result<Vec<f64>> {
+    let data_points: Vec<serde_json::Value> = client
+        .get(format!("http://{addr}/m/{metric_id}/json"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(data_points
+        .iter()
+        .map(|d| d["v"].as_f64().expect("a number"))
+        .collect())
+}
+
+/// Every format is parsed, and with `--create-metrics` every benchmark gets a
+/// metric, recorded in the mapping file for the next runs
+#[tokio::test(flavor = "multi_thread")]
+async fn ingest_creates_metrics_and_reports_benchmarks() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+    let test_dir = tempfile::tempdir()?;
+    let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ingest");
+
+    let cases = [
+        (
+            "criterion",
+            "criterion",
+            vec![("parse/small", 1500e-9), ("startup", 2500e-6)],
+        ),
+        (
+            "hyperfine",
+            "hyperfine.json",
+            vec![("build", 1.25), ("test", 0.5)],
+        ),
+        (
+            "libtest-bench",
+            "libtest-bench.txt",
+            vec![("bench_parse", 1234e-9), ("bench_render", 98765.5e-9)],
+        ),
+        (
+            "junit",
+            "junit.xml",
+            vec![
+                ("perfitd::sanity/sanity_check", 0.512),
+                ("perfitd::sanity/slow_check", 2.048),
+            ],
+        ),
+    ];
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let (access_token, _) = {
+                let root_access_token = root_access_token.clone();
+                tokio::task::spawn_blocking(move || {
+                    common::new_account_with_metric(addr, &root_access_token)
+                })
+                .await??
+            };
+
+            let client = reqwest::Client::new();
+            for (format, path, expected) in cases {
+                let mapping_path = test_dir.path().join(format!("{format}.toml"));
+                std::fs::write(&mapping_path, "# Benchmarks of the project\n")?;
+
+                let cmd = duct::cmd!(
+                    get_cargo_bin("perfit"),
+                    "ingest",
+                    "--format",
+                    format,
+                    "--mapping",
+                    &mapping_path,
+                    "--create-metrics",
+                    fixtures_dir.join(path),
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token);
+                tokio::task::spawn_blocking(move || cmd.run()).await??;
+
+                let mapping = std::fs::read_to_string(&mapping_path)?;
+                assert!(mapping.starts_with("# Benchmarks of the project\n"));
+                let mapping: BTreeMap<String, String> = toml::from_str(&mapping)?;
+                assert_eq!(
+                    mapping.keys().map(String::as_str).collect::<Vec<_>>(),
+                    expected.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+                    "{format}"
+                );
+
+                for (name, value) in expected {
+                    let values = data_point_values(&client, addr, &mapping[name]).await?;
+                    assert_eq!(values.len(), 1, "{format}: {name}");
+                    assert!(
+                        (values[0] - value).abs() <= value * 1e-6,
+                        "{format}: {name}: {} != {value}",
+                        values[0]
+                    );
+                }
+            }
+
+            Ok(())
+        })
+        .await
+}
+
+/// Benchmarks missing from the mapping file are skipped without
+/// `--create-metrics`
+#[tokio::test(flavor = "multi_thread")]
+async fn ingest_skips_unmapped_benchmarks() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+    let test_dir = tempfile::tempdir()?;
+    let mapping_path = test_dir.path().join("mapping.toml");
+    let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ingest");
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let cmd_mapping_path = mapping_path.clone();
+            let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+                std::fs::write(&cmd_mapping_path, format!("build = \"{metric_id}\"\n"))?;
+
+                duct::cmd!(
+                    get_cargo_bin("perfit"),
+                    "ingest",
+                    "--format",
+                    "hyperfine",
+                    "--mapping",
+                    &cmd_mapping_path,
+                    fixtures_dir.join("hyperfine.json"),
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .run()?;
+
+                Ok(metric_id)
+            })
+            .await??;
+
+            let client = reqwest::Client::new();
+            assert_eq!(
+                data_point_values(&client, addr, &metric_id).await?,
+                vec![1.25]
+            );
+            assert_eq!(
+                std::fs::read_to_string(&mapping_path)?,
+                format!("build = \"{metric_id}\"\n")
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+/// Running ingest again with the same `--idempotency-key` (e.g. after some
+/// benchmarks failed to send) doesn't duplicate the ones already sent, even
+/// if the tool lists them in a different order
+#[tokio::test(flavor = "multi_thread")]
+async fn ingest_retries_are_idempotent() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+    let test_dir = tempfile::tempdir()?;
+    let mapping_path = test_dir.path().join("mapping.toml");
+    let results_path = test_dir.path().join("hyperfine.json");
+    let fixtures_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ingest");
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let cmd_mapping_path = mapping_path.clone();
+            let access_token = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, _) = common::new_account_with_metric(addr, &root_access_token)?;
+                std::fs::write(&cmd_mapping_path, "")?;
+                Ok(access_token)
+            })
+            .await??;
+
+            let mut results: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(
+                fixtures_dir.join("hyperfine.json"),
+            )?)?;
+            for _ in 0..2 {
+                std::fs::write(&results_path, results.to_string())?;
+                let cmd = duct::cmd!(
+                    get_cargo_bin("perfit"),
+                    "ingest",
+                    "--format",
+                    "hyperfine",
+                    "--mapping",
+                    &mapping_path,
+                    "--create-metrics",
+                    "--idempotency-key",
+                    "ci-run-1",
+                    &results_path,
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token);
+                tokio::task::spawn_blocking(move || cmd.run()).await??;
+
+                results["results"]
+                    .as_array_mut()
+                    .expect("a list of results")
+                    .reverse();
+            }
+
+            let client = reqwest::Client::new();
+            let mapping: BTreeMap<String, String> =
+                toml::from_str(&std::fs::read_to_string(&mapping_path)?)?;
+            assert_eq!(
+                data_point_values(&client, addr, &mapping["build"]).await?,
+                vec![1.25]
+            );
+            assert_eq!(
+                data_point_values(&client, addr, &mapping["test"]).await?,
+                vec![0.5]
+            );
+
+            Ok(())
+        })
+        .await
+}
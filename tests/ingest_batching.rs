@@ -0,0 +1,104 @@
+// Runs `perfitd` as a separate process, so only uses the free helpers
+#[allow(dead_code)]
+mod common;
+
+use std::net::{SocketAddr, TcpListener};
+use std::time::{Duration, Instant};
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use perfitd::models::access_token::AccessToken;
+use serde_json::json;
+use tracing::info;
+
+const NUM_POINTS: usize = 25;
+
+/// Posts are acknowledged only after their batch is committed, so they must
+/// survive perfitd getting killed right after
+#[tokio::test(flavor = "multi_thread")]
+async fn acknowledged_points_survive_kill() -> Result<()> {
+    common::init_logging()?;
+
+    let test_dir = tempfile::tempdir()?;
+    let db_path = test_dir.path().join("db.redb");
+    let root_access_token = AccessToken::generate().to_string();
+    let addr: SocketAddr = TcpListener::bind("[::1]:0")?.local_addr()?;
+
+    let server = duct::cmd!(
+        get_cargo_bin("perfitd"),
+        "--listen",
+        addr.to_string(),
+        "--db",
+        &db_path,
+        "--root-access-token",
+        &root_access_token,
+        "--rate-limit-burst",
+        "1000",
+        "--ingest-batch-millis",
+        "200",
+        "--ingest-batch-points",
+        "10"
+    )
+    .stdout_null()
+    .start()?;
+
+    let res = async {
+        let client = reqwest::Client::new();
+        while client.get(format!("http://{addr}/")).send().await.is_err() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        info!("Staring test");
+        let (_access_token, metric_id) = tokio::task::spawn_blocking(move || {
+            common::new_account_with_metric(addr, &root_access_token)
+        })
+        .await??;
+
+        let post = |value: usize| {
+            let client = client.clone();
+            let metric_id = metric_id.clone();
+            async move {
+                client
+                    .post(format!("http://{addr}/m/{metric_id}"))
+                    .json(&json!({ "value": value as f32 }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok::<_, color_eyre::Report>(())
+            }
+        };
+
+        // A lone point waits for the batch to fill up, until the deadline
+        let start = Instant::now();
+        post(0).await?;
+        assert!(Duration::from_millis(200) <= start.elapsed());
+
+        // Many of them land in the same second, and need distinct keys
+        let posts = (1..NUM_POINTS)
+            .map(|value| tokio::spawn(post(value)))
+            .collect::<Vec<_>>();
+        for post in posts {
+            post.await??;
+        }
+        Ok::<_, color_eyre::Report>(())
+    }
+    .await;
+    server.kill()?;
+    res?;
+
+    let dump = duct::cmd!(get_cargo_bin("perfitd"), "--db", &db_path, "dump").read()?;
+    let mut values = dump
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .filter(|entry| entry.as_ref().map_or(true, |e| e["table"] == "data_points"))
+        .map(|entry| {
+            Ok(entry?["value"]["value"]
+                .as_f64()
+                .expect("value is a number") as usize)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    values.sort();
+    assert_eq!(values, (0..NUM_POINTS).collect::<Vec<_>>());
+
+    Ok(())
+}
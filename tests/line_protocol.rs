@@ -0,0 +1,202 @@
+mod common;
+
+use std::time::Duration;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use perfitd::opts;
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+#[derive(Deserialize)]
+struct NewTokenOutput {
+    access_token: String,
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn line_protocol() -> Result<()> {
+    common::init_logging()?;
+
+    let test_dir = tempfile::tempdir()?;
+    let db = test_dir.path().join("db.redb");
+    let fixture = PerfitdFixture::new_with_opts(
+        test_dir,
+        opts::Opts {
+            db,
+            line_protocol_listen: Some("[::1]:0".into()),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let addr = fixture.addr()?;
+    let line_protocol_addr = fixture
+        .line_protocol_addr()?
+        .expect("line protocol enabled");
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let (post_access_token, metric_id) = tokio::task::spawn_blocking({
+                let root_access_token = root_access_token.clone();
+                move || -> Result<_> {
+                    let (access_token, metric_id) =
+                        common::new_account_with_metric(addr, &root_access_token)?;
+
+                    let NewTokenOutput {
+                        access_token: post_access_token,
+                    } = serde_json::from_str(
+                        &duct::cmd!(get_cargo_bin("perfit"), "token", "new")
+                            .env("PERFIT_SERVER", format!("http://{}", addr))
+                            .env("PERFIT_ACCESS_TOKEN", &access_token)
+                            .read()?,
+                    )?;
+
+                    Ok((post_access_token, metric_id))
+                }
+            })
+            .await??;
+
+            let mut tcp = TcpStream::connect(line_protocol_addr).await?;
+            tcp.write_all(
+                format!(
+                    "# comment\n\
+                     {metric_id},token={post_access_token},host=ci\\ 1 value=3i 1700000000000000000\n\
+                     {metric_id},token={post_access_token} novalue=1\n\
+                     {metric_id},token={root_access_token} value=5\n\
+                     {metric_id},token={post_access_token} value=6 99999999999000000000\n"
+                )
+                .as_bytes(),
+            )
+            .await?;
+            tcp.shutdown().await?;
+
+            let udp = UdpSocket::bind("[::1]:0").await?;
+            udp.send_to(
+                format!("{metric_id},token={post_access_token} value=1.5,other=\"a b\"\n")
+                    .as_bytes(),
+                line_protocol_addr,
+            )
+            .await?;
+
+            let client = reqwest::Client::new();
+
+            // Nothing gets acknowledged, so wait for the points to show up
+            let data_points = loop {
+                let data_points: Vec<serde_json::Value> = client
+                    .get(format!("http://{addr}/m/{metric_id}/json"))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                if data_points.len() == 2 {
+                    break data_points;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            };
+            let mut points = data_points
+                .iter()
+                .map(|d| (d["v"].as_f64().unwrap_or_default(), d["m"].clone()))
+                .collect::<Vec<_>>();
+            points.sort_by(|a, b| a.0.total_cmp(&b.0));
+            assert_eq!(
+                points,
+                vec![
+                    (1.5, serde_json::Value::Null),
+                    (3.0, serde_json::json!("host=ci 1")),
+                ]
+            );
+            // The one with a timestamp is the oldest
+            assert_eq!(data_points[0]["t"], 1_700_000_000);
+
+            let metrics = client
+                .get(format!("http://{addr}/metrics"))
+                .bearer_auth(&root_access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            assert!(metrics.contains(r#"perfitd_line_protocol_lines_total{outcome="accepted"} 2"#));
+            assert!(metrics.contains(r#"perfitd_line_protocol_lines_total{outcome="malformed"} 1"#));
+            assert!(metrics.contains(r#"perfitd_line_protocol_lines_total{outcome="rejected"} 2"#));
+
+            Ok(())
+        })
+        .await
+}
+
+/// Lines are rate limited per peer, and overlong lines close the connection
+#[tokio::test(flavor = "multi_thread")]
+async fn line_protocol_limits() -> Result<()> {
+    common::init_logging()?;
+
+    let test_dir = tempfile::tempdir()?;
+    let db = test_dir.path().join("db.redb");
+    let fixture = PerfitdFixture::new_with_opts(
+        test_dir,
+        opts::Opts {
+            db,
+            line_protocol_listen: Some("[::1]:0".into()),
+            rate_limit_burst: 2,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let addr = fixture.addr()?;
+    let line_protocol_addr = fixture
+        .line_protocol_addr()?
+        .expect("line protocol enabled");
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let mut tcp = TcpStream::connect(line_protocol_addr).await?;
+            // Doesn't even need a newline
+            let res = tcp.write_all(&vec![b'a'; 1024 * 1024]).await;
+            if res.is_ok() {
+                let mut buf = vec![];
+                // Closed (or reset) by perfitd, instead of waiting for more
+                let _ = tcp.read_to_end(&mut buf).await;
+            }
+
+            let udp = UdpSocket::bind("[::1]:0").await?;
+            udp.send_to(
+                "malformed\nmalformed\nmalformed\nmalformed\n".as_bytes(),
+                line_protocol_addr,
+            )
+            .await?;
+
+            let client = reqwest::Client::new();
+            // Nothing gets acknowledged, so wait for the lines to be counted
+            loop {
+                let metrics = client
+                    .get(format!("http://{addr}/metrics"))
+                    .bearer_auth(&root_access_token)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await?;
+                if metrics.contains(r#"perfitd_line_protocol_lines_total{outcome="limited"} 2"#) {
+                    assert!(metrics
+                        .contains(r#"perfitd_line_protocol_lines_total{outcome="malformed"} 2"#));
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+
+            Ok(())
+        })
+        .await
+}
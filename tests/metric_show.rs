@@ -0,0 +1,58 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+/// `perfit metric show` prints a sparkline and statistics of the latest
+/// successful data points
+#[tokio::test(flavor = "multi_thread")]
+async fn metric_show_renders_latest_data_points() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+                let perfit = |args: &[&str]| {
+                    duct::cmd(get_cargo_bin("perfit"), args)
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .env("PERFIT_METRIC", &metric_id)
+                        .env("PERFIT_NO_AUTO_METADATA", "true")
+                };
+
+                let empty = perfit(&["metric", "show"]).read()?;
+                assert_eq!(empty, format!("{metric_id}: no data points"));
+
+                for value in ["9", "1", "3", "2"] {
+                    perfit(&["post", value]).run()?;
+                }
+                perfit(&["post", "--outcome", "failure", "100"]).run()?;
+
+                let out = perfit(&["metric", "show", "--last", "3"]).read()?;
+                let lines: Vec<_> = out.lines().collect();
+                assert_eq!(lines[0], format!("{metric_id}: 3 data points"));
+                assert_eq!(lines[1], "▁█▅");
+                assert!(
+                    lines[2].starts_with("min 1  median 2  max 3  latest 2 ("),
+                    "{out}"
+                );
+                assert_eq!(lines.len(), 3);
+
+                Ok(())
+            })
+            .await??;
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,112 @@
+use std::io::Read as _;
+use std::path::{Path, PathBuf};
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use perfitd::db::{RedbStorage, Storage as _};
+
+/// Every fixture database, created by a previous version of perfitd, must
+/// open and migrate cleanly with the current one
+#[tokio::test(flavor = "multi_thread")]
+async fn migrate_fixture_databases() -> Result<()> {
+    let fixtures_dir = fixtures_dir();
+    let perfitd = get_cargo_bin("perfitd");
+
+    let mut fixtures: Vec<_> = std::fs::read_dir(&fixtures_dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<_>>()?;
+    fixtures.retain(|name| name.starts_with('v') && name.ends_with(".redb.gz"));
+    fixtures.sort();
+    assert!(!fixtures.is_empty());
+
+    for fixture in fixtures {
+        let name = fixture.trim_end_matches(".redb.gz");
+
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("db.redb");
+        let db = read_fixture(&fixture)?;
+        std::fs::write(&db_path, &db)?;
+
+        let log = duct::cmd!(&perfitd, "--db", &db_path, "--migrate-dry-run")
+            .stderr_to_stdout()
+            .read()?;
+        assert!(
+            !log.contains("Applied migration"),
+            "{name}: dry run claims to have applied migrations"
+        );
+        assert_eq!(
+            db,
+            std::fs::read(&db_path)?,
+            "{name}: dry run changed the db"
+        );
+
+        // Only reads, so it has to be migrated first
+        assert!(
+            !duct::cmd!(&perfitd, "--db", &db_path, "dump")
+                .stdout_null()
+                .stderr_null()
+                .unchecked()
+                .run()?
+                .status
+                .success(),
+            "{name}: dumped without migrating"
+        );
+        let storage = RedbStorage::open(&db_path).await?;
+        for (metric_id, record) in storage.metrics()? {
+            assert!(
+                storage
+                    .account_metrics(record.account_id)?
+                    .iter()
+                    .any(|(id, _)| *id == metric_id),
+                "{name}: metric {metric_id} not indexed by its account"
+            );
+        }
+        drop(storage);
+
+        let dump = duct::cmd!(&perfitd, "--db", &db_path, "dump")
+            .stderr_null()
+            .read()?;
+        insta::assert_snapshot!(format!("{name}-dump"), dump);
+    }
+
+    Ok(())
+}
+
+/// Dry run of the baseline database reports the migrations it would apply
+#[test]
+fn migrate_dry_run_v0() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("db.redb");
+    std::fs::write(&db_path, read_fixture("v0.redb.gz")?)?;
+
+    let log = duct::cmd!(
+        get_cargo_bin("perfitd"),
+        "--db",
+        &db_path,
+        "--migrate-dry-run"
+    )
+    .stderr_to_stdout()
+    .read()?;
+    assert!(
+        log.contains("Would apply migration data-point-outcome: 3 records"),
+        "{log}"
+    );
+    assert!(
+        log.contains("Would apply migration metrics-by-account: 1 records"),
+        "{log}"
+    );
+
+    Ok(())
+}
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+/// Fixture databases are compressed, as they're mostly empty pages
+fn read_fixture(name: &str) -> Result<Vec<u8>> {
+    let mut db = vec![];
+    flate2::read::GzDecoder::new(std::fs::File::open(fixtures_dir().join(name))?)
+        .read_to_end(&mut db)?;
+    Ok(db)
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+/// Failed runs are stored with their outcome, filterable in the JSON API,
+/// drawn separately on the chart, and left out of the badge
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_runs_are_recorded_distinctly() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+                let perfit = |args: &[&str]| {
+                    duct::cmd(get_cargo_bin("perfit"), args)
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .env("PERFIT_METRIC", &metric_id)
+                        .env("PERFIT_NO_AUTO_METADATA", "true")
+                };
+
+                perfit(&["post", "1.5"]).run()?;
+                let res = perfit(&["run", "--send-on-failure", "--", "false"])
+                    .unchecked()
+                    .run()?;
+                assert_eq!(res.status.code(), Some(1));
+                perfit(&["post", "--outcome", "timeout", "99"]).run()?;
+                perfit(&["post", "--outcome", "interrupted", "98"]).run()?;
+
+                Ok(metric_id)
+            })
+            .await??;
+
+            let outcomes = |query: &'static str| {
+                let metric_id = metric_id.clone();
+                async move {
+                    let data_points: Vec<serde_json::Value> =
+                        reqwest::get(format!("http://{addr}/m/{metric_id}/json{query}"))
+                            .await?
+                            .error_for_status()?
+                            .json()
+                            .await?;
+                    Ok::<_, color_eyre::Report>(
+                        data_points
+                            .iter()
+                            .map(|d| d["o"].as_str().unwrap_or("success").to_owned())
+                            .collect::<Vec<_>>(),
+                    )
+                }
+            };
+            assert_eq!(
+                outcomes("").await?,
+                ["success", "failure", "timeout", "interrupted"]
+            );
+            assert_eq!(outcomes("?outcome=failure").await?, ["failure"]);
+            assert_eq!(outcomes("?outcome=interrupted").await?, ["interrupted"]);
+            assert_eq!(outcomes("?outcome=success").await?, ["success"]);
+
+            let svg = reqwest::get(format!("http://{addr}/m/{metric_id}/svg"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            assert_eq!(svg.matches(r#"class="perfit_failed""#).count(), 3);
+            assert!(svg.contains("failed: timeout</title>"));
+
+            let badge = reqwest::get(format!("http://{addr}/m/{metric_id}/badge"))
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            assert!(badge.contains("1.5"), "{badge}");
+            assert!(!badge.contains("99"), "{badge}");
+            assert!(!badge.contains("98"), "{badge}");
+
+            Ok(())
+        })
+        .await
+}
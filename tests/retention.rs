@@ -0,0 +1,206 @@
+mod common;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use serde_json::json;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+const ACCOUNT_ID: &str = "_JPXB9qUTbe7qd533zu-DA";
+const ADMIN_ACCESS_TOKEN: &str = "fK6PQ_V3fqRVMeoy_eMTyM6zG1cL0gnov4SR0ujroWs";
+const DELETE_METRIC_ID: &str = "VMnZ_lOfQ8yHEBmjhwkxIQ";
+const AGGREGATE_METRIC_ID: &str = "AQEBAQEBAQEBAQEBAQEBAQ";
+
+const DAY: u64 = 24 * 60 * 60;
+const OVERSIZED_DAY_LEN: u64 = 1500;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retention_prunes_old_data_points() -> Result<()> {
+    common::init_logging()?;
+
+    // Data points can only be posted with the current time, so create a
+    // database with old ones from a dump
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let old_day = now - now % DAY - 100 * DAY;
+    let data_point = |metric_internal_id: u64, ts: u64, idx: u64, value: f32| {
+        json!({
+            "table": "data_points",
+            "key": {"metric_internal_id": metric_internal_id, "ts": ts, "idx": idx},
+            "value": {"value": value, "metadata": ""},
+        })
+    };
+    let metric = |key: &str, internal_id: u64| {
+        json!({
+            "table": "metrics",
+            "key": key,
+            "value": {"created": old_day, "account_id": ACCOUNT_ID, "internal_id": internal_id},
+        })
+    };
+    let dump = [
+        json!({"format": "perfitd-dump", "version": 1}),
+        json!({"table": "accounts", "key": ACCOUNT_ID, "value": {"created": old_day}}),
+        json!({
+            "table": "access_tokens",
+            "key": ADMIN_ACCESS_TOKEN,
+            "value": {"created": old_day, "account_id": ACCOUNT_ID, "type": "Admin"},
+        }),
+        metric(DELETE_METRIC_ID, 0),
+        metric(AGGREGATE_METRIC_ID, 1),
+        data_point(0, old_day, 0, 1.),
+        data_point(0, old_day + 1, 0, 2.),
+        data_point(0, old_day + DAY, 0, 3.),
+        data_point(0, now, 0, 4.),
+        data_point(1, old_day, 0, 1.),
+        data_point(1, old_day, 1, 10.),
+        data_point(1, old_day + 60, 0, 2.),
+        data_point(1, old_day + DAY + 60, 0, 3.),
+        data_point(1, now, 0, 4.),
+        json!({
+            "table": "account_retention",
+            "key": ACCOUNT_ID,
+            "value": {"raw-days": 30, "then": "daily-aggregate"},
+        }),
+        json!({
+            "table": "metric_retention",
+            "key": DELETE_METRIC_ID,
+            "value": {"raw-days": 30, "then": "delete"},
+        }),
+    ]
+    .into_iter()
+    // A day with more data points than are aggregated in one transaction
+    .chain((0..OVERSIZED_DAY_LEN).map(|idx| data_point(1, old_day + 2 * DAY, idx, idx as f32)))
+    .map(|entry| entry.to_string() + "\n")
+    .collect::<String>();
+
+    let test_dir = tempfile::tempdir()?;
+    let dump_path = test_dir.path().join("dump.jsonl");
+    let db_path = test_dir.path().join("db.redb");
+    tokio::fs::write(&dump_path, dump).await?;
+    duct::cmd!(
+        get_cargo_bin("perfitd"),
+        "--db",
+        &db_path,
+        "load",
+        &dump_path
+    )
+    .run()?;
+
+    // Retention is enforced right after the start
+    let fixture = PerfitdFixture::new_with_db(test_dir, db_path).await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let client = reqwest::Client::new();
+
+            let stats = loop {
+                let stats: serde_json::Value = client
+                    .get(format!("http://{addr}/retention"))
+                    .bearer_auth(&root_access_token)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                if stats["runs"] != 0 {
+                    break stats;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            };
+            // 3 deleted, and all but one on each aggregated day
+            assert_eq!(stats["deleted"], 3 + 2 + (OVERSIZED_DAY_LEN - 1));
+            assert_eq!(stats["aggregated-days"], 2);
+
+            let values = |metric_id| {
+                let client = client.clone();
+                async move {
+                    let data_points: Vec<serde_json::Value> = client
+                        .get(format!("http://{addr}/m/{metric_id}/json"))
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json()
+                        .await?;
+                    Ok::<_, color_eyre::Report>(
+                        data_points
+                            .iter()
+                            .map(|data_point| (data_point["t"].clone(), data_point["v"].clone()))
+                            .collect::<Vec<_>>(),
+                    )
+                }
+            };
+            assert_eq!(
+                values(DELETE_METRIC_ID).await?,
+                vec![(json!(now), json!(4.))]
+            );
+            assert_eq!(
+                values(AGGREGATE_METRIC_ID).await?,
+                vec![
+                    (json!(old_day), json!(2.)),
+                    (json!(old_day + DAY + 60), json!(3.)),
+                    (json!(old_day + 2 * DAY), json!(749.5)),
+                    (json!(now), json!(4.)),
+                ]
+            );
+
+            // Can't post into days that are already aggregated
+            let post = |ts: u64| {
+                client
+                    .post(format!("http://{addr}/m/{AGGREGATE_METRIC_ID}"))
+                    .json(&json!({
+                        "value": 5.0,
+                        "ts": time::OffsetDateTime::from_unix_timestamp(ts as i64)
+                            .expect("valid")
+                            .format(&time::format_description::well_known::Rfc3339)
+                            .expect("valid"),
+                    }))
+                    .send()
+            };
+            assert_eq!(
+                post(old_day).await?.status(),
+                reqwest::StatusCode::BAD_REQUEST
+            );
+            post(now - 2 * DAY).await?.error_for_status()?;
+
+            // Only the root account can see the stats
+            let resp = client
+                .get(format!("http://{addr}/retention"))
+                .bearer_auth(ADMIN_ACCESS_TOKEN)
+                .send()
+                .await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+            tokio::task::spawn_blocking(move || -> Result<_> {
+                let bin = get_cargo_bin("perfit");
+                duct::cmd!(
+                    &bin,
+                    "metric",
+                    "retention",
+                    "--raw-days",
+                    "7",
+                    "--then",
+                    "daily-aggregate"
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", ADMIN_ACCESS_TOKEN)
+                .env("PERFIT_METRIC", DELETE_METRIC_ID)
+                .run()?;
+                duct::cmd!(&bin, "account", "retention", "--clear")
+                    .env("PERFIT_SERVER", format!("http://{}", addr))
+                    .env("PERFIT_ACCESS_TOKEN", ADMIN_ACCESS_TOKEN)
+                    .run()?;
+                Ok(())
+            })
+            .await??;
+
+            Ok(())
+        })
+        .await
+}
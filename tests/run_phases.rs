@@ -0,0 +1,99 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+async fn data_point_values(
+    client: &reqwest::Client,
+    addr: std::net::SocketAddr,
+    metric_id: &str,
+) -> Result<Vec<f64>> {
+    let data_points: Vec<serde_json::Value> = client
+        .get(format!("http://{addr}/m/{metric_id}/json"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(data_points
+        .iter()
+        .map(|d| d["v"].as_f64().expect("a number"))
+        .collect())
+}
+
+/// Phases marked with `perfit mark` inside `perfit run` get reported to their
+/// `--phase-metric`
+#[tokio::test(flavor = "multi_thread")]
+async fn run_reports_marked_phases() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let metric_ids = tokio::task::spawn_blocking(move || -> Result<_> {
+                let bin = get_cargo_bin("perfit");
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+                let new_metric = || -> Result<String> {
+                    Ok(serde_json::from_str(
+                        &duct::cmd!(&bin, "metric", "new")
+                            .env("PERFIT_SERVER", format!("http://{}", addr))
+                            .env("PERFIT_ACCESS_TOKEN", &access_token)
+                            .stdout_capture()
+                            .read()?,
+                    )?)
+                };
+                let build_metric_id = new_metric()?;
+                let test_metric_id = new_metric()?;
+
+                duct::cmd!(
+                    &bin,
+                    "run",
+                    "--phase-metric",
+                    format!("build={build_metric_id}"),
+                    "--phase-metric",
+                    format!("test={test_metric_id}"),
+                    "--",
+                    "sh",
+                    "-c",
+                    "\"$0\" mark build && sleep 0.3 && \"$0\" mark test && sleep 0.1",
+                    &bin,
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .env("PERFIT_METRIC", &metric_id)
+                .run()?;
+
+                // Only meant to run under `perfit run`
+                let res = duct::cmd!(&bin, "mark", "build")
+                    .env_remove("PERFIT_MARK")
+                    .stderr_null()
+                    .unchecked()
+                    .run()?;
+                assert!(!res.status.success());
+
+                Ok((metric_id, build_metric_id, test_metric_id))
+            })
+            .await??;
+
+            let (metric_id, build_metric_id, test_metric_id) = metric_ids;
+            let client = reqwest::Client::new();
+            let total = data_point_values(&client, addr, &metric_id).await?;
+            let build = data_point_values(&client, addr, &build_metric_id).await?;
+            let test = data_point_values(&client, addr, &test_metric_id).await?;
+            assert_eq!((total.len(), build.len(), test.len()), (1, 1, 1));
+            assert!(0.3 <= build[0], "{build:?}");
+            assert!(0.1 <= test[0], "{test:?}");
+            assert!(build[0] + test[0] <= total[0], "{total:?}");
+
+            Ok(())
+        })
+        .await
+}
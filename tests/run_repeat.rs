@@ -0,0 +1,115 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+async fn data_point_count(
+    client: &reqwest::Client,
+    addr: std::net::SocketAddr,
+    metric_id: &str,
+) -> Result<usize> {
+    let data_points: Vec<serde_json::Value> = client
+        .get(format!("http://{addr}/m/{metric_id}/json"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(data_points.len())
+}
+
+/// `perfit run --repeat` runs the command after the warmup runs, and sends the
+/// aggregate, every sample and the standard deviation to their metrics
+#[tokio::test(flavor = "multi_thread")]
+async fn run_repeat_sends_aggregate_samples_and_stddev() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+    let test_dir = tempfile::tempdir()?;
+    let runs_path = test_dir.path().join("runs");
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let cmd_runs_path = runs_path.clone();
+            let metric_ids = tokio::task::spawn_blocking(move || -> Result<_> {
+                let bin = get_cargo_bin("perfit");
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+                let new_metric = || -> Result<String> {
+                    Ok(serde_json::from_str(
+                        &duct::cmd!(&bin, "metric", "new")
+                            .env("PERFIT_SERVER", format!("http://{}", addr))
+                            .env("PERFIT_ACCESS_TOKEN", &access_token)
+                            .stdout_capture()
+                            .read()?,
+                    )?)
+                };
+                let samples_metric_id = new_metric()?;
+                let stddev_metric_id = new_metric()?;
+
+                duct::cmd!(
+                    &bin,
+                    "run",
+                    "--repeat",
+                    "3",
+                    "--warmup",
+                    "1",
+                    "--samples-metric",
+                    &samples_metric_id,
+                    "--stddev-metric",
+                    &stddev_metric_id,
+                    "--",
+                    "sh",
+                    "-c",
+                    "echo >> \"$0\"",
+                    &cmd_runs_path,
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .env("PERFIT_METRIC", &metric_id)
+                .run()?;
+
+                // Fails on the first run, so doesn't get to the other ones
+                let res = duct::cmd!(
+                    &bin,
+                    "run",
+                    "--repeat",
+                    "3",
+                    "--",
+                    "sh",
+                    "-c",
+                    "echo >> \"$0\"; exit 3",
+                    &cmd_runs_path,
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .env("PERFIT_METRIC", &metric_id)
+                .unchecked()
+                .run()?;
+                assert_eq!(res.status.code(), Some(3));
+
+                Ok((metric_id, samples_metric_id, stddev_metric_id))
+            })
+            .await??;
+
+            assert_eq!(std::fs::read_to_string(&runs_path)?.lines().count(), 5);
+
+            let (metric_id, samples_metric_id, stddev_metric_id) = metric_ids;
+            let client = reqwest::Client::new();
+            assert_eq!(data_point_count(&client, addr, &metric_id).await?, 1);
+            assert_eq!(
+                data_point_count(&client, addr, &samples_metric_id).await?,
+                3
+            );
+            assert_eq!(data_point_count(&client, addr, &stddev_metric_id).await?, 1);
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,183 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+async fn data_point_outcomes(
+    addr: std::net::SocketAddr,
+    metric_id: &str,
+) -> Result<Vec<serde_json::Value>> {
+    let data_points: Vec<serde_json::Value> =
+        reqwest::get(format!("http://{addr}/m/{metric_id}/json"))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+    Ok(data_points.iter().map(|d| d["o"].clone()).collect())
+}
+
+/// `--timeout` stops every process of the command, reports the run as timed
+/// out, and exits like the command was killed with SIGTERM
+#[tokio::test(flavor = "multi_thread")]
+async fn run_timeout_stops_command() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                let start = Instant::now();
+                let res = duct::cmd!(
+                    get_cargo_bin("perfit"),
+                    "run",
+                    "--timeout",
+                    "500ms",
+                    "--",
+                    "sh",
+                    "-c",
+                    "sleep 30 & sleep 30",
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .env("PERFIT_METRIC", &metric_id)
+                .env("PERFIT_NO_AUTO_METADATA", "true")
+                .unchecked()
+                .run()?;
+                assert_eq!(res.status.code(), Some(128 + libc::SIGTERM));
+                assert!(start.elapsed() < Duration::from_secs(10));
+
+                Ok(metric_id)
+            })
+            .await??;
+
+            assert_eq!(
+                data_point_outcomes(addr, &metric_id).await?,
+                vec![serde_json::json!("timeout")]
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+/// SIGTERM sent to perfit gets forwarded to the command, and the run is
+/// reported as interrupted
+#[tokio::test(flavor = "multi_thread")]
+async fn run_forwards_signals() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+    let test_dir = tempfile::tempdir()?;
+    let started_path = test_dir.path().join("started");
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                let handle = duct::cmd!(
+                    get_cargo_bin("perfit"),
+                    "run",
+                    "--",
+                    "sh",
+                    "-c",
+                    "touch \"$0\" && sleep 30",
+                    &started_path,
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .env("PERFIT_METRIC", &metric_id)
+                .env("PERFIT_NO_AUTO_METADATA", "true")
+                .unchecked()
+                .start()?;
+
+                while !started_path.exists() {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                let pid = libc::pid_t::try_from(handle.pids()[0])?;
+                // SAFETY: no memory is involved
+                assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+
+                let res = handle.wait()?;
+                assert_eq!(res.status.code(), Some(128 + libc::SIGTERM));
+
+                Ok(metric_id)
+            })
+            .await??;
+
+            assert_eq!(
+                data_point_outcomes(addr, &metric_id).await?,
+                vec![serde_json::json!("interrupted")]
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+/// Only with `--timeout` the command gets its own process group, otherwise
+/// it has to stay in the foreground one, to be able to read the terminal
+#[tokio::test(flavor = "multi_thread")]
+async fn run_process_group() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+    let addr = fixture.addr()?;
+    let root_access_token = fixture.root_access_token_str();
+    let test_dir = tempfile::tempdir()?;
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            tokio::task::spawn_blocking(move || -> Result<_> {
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                let command_pgid = |timeout_args: &[&str]| -> Result<libc::pid_t> {
+                    let pgid_path = test_dir.path().join("pgid");
+                    let mut args = vec!["run"];
+                    args.extend(timeout_args);
+                    args.extend(["--", "sh", "-c", "ps -o pgid= -p $$ > \"$0\""]);
+                    let mut args: Vec<std::ffi::OsString> =
+                        args.into_iter().map(Into::into).collect();
+                    args.push(pgid_path.clone().into());
+                    duct::cmd(get_cargo_bin("perfit"), args)
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .env("PERFIT_METRIC", &metric_id)
+                        .env("PERFIT_NO_AUTO_METADATA", "true")
+                        .run()?;
+                    Ok(std::fs::read_to_string(pgid_path)?.trim().parse()?)
+                };
+
+                // SAFETY: no memory is involved
+                let own_pgid = unsafe { libc::getpgrp() };
+                assert_eq!(command_pgid(&[])?, own_pgid);
+                assert_ne!(command_pgid(&["--timeout", "30s"])?, own_pgid);
+
+                Ok(())
+            })
+            .await??;
+
+            Ok(())
+        })
+        .await
+}
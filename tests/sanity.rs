@@ -77,6 +77,7 @@ async fn sanity_post_data_points() -> Result<()> {
                     .env("PERFIT_SERVER", format!("http://{}", addr))
                     .env("PERFIT_ACCESS_TOKEN", &access_token)
                     .env("PERFIT_METRIC", &metric_id)
+                    .env("PERFIT_NO_AUTO_METADATA", "true")
                     .run()?;
 
                 insta::assert_yaml_snapshot!("one data point", duct::cmd!(&bin, "metric", "get")
@@ -91,12 +92,14 @@ async fn sanity_post_data_points() -> Result<()> {
                     .env("PERFIT_SERVER", format!("http://{}", addr))
                     .env("PERFIT_ACCESS_TOKEN", &access_token)
                     .env("PERFIT_METRIC", &metric_id)
+                    .env("PERFIT_NO_AUTO_METADATA", "true")
                     .run()?;
 
                 duct::cmd!(&bin, "post", "12")
                     .env("PERFIT_SERVER", format!("http://{}", addr))
                     .env("PERFIT_ACCESS_TOKEN", &access_token)
                     .env("PERFIT_METRIC", &metric_id)
+                    .env("PERFIT_NO_AUTO_METADATA", "true")
                     .run()?;
 
                 insta::assert_yaml_snapshot!("three data points", duct::cmd!(&bin, "metric", "get")
@@ -0,0 +1,87 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn server_metrics() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let access_token = tokio::task::spawn_blocking({
+                let root_access_token = root_access_token.clone();
+                move || -> Result<_> {
+                    let (access_token, metric_id) =
+                        common::new_account_with_metric(addr, &root_access_token)?;
+
+                    duct::cmd!(get_cargo_bin("perfit"), "post", "1")
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .env("PERFIT_METRIC", &metric_id)
+                        .run()?;
+
+                    Ok(access_token)
+                }
+            })
+            .await??;
+
+            let client = reqwest::Client::new();
+
+            // Exhaust the rate limit of some other client
+            for _ in 0..100 {
+                client
+                    .get(format!("http://{addr}/"))
+                    .header("X-Forwarded-For", "192.0.2.1")
+                    .send()
+                    .await?;
+            }
+
+            let resp = client
+                .get(format!("http://{addr}/metrics"))
+                .bearer_auth(&access_token)
+                .send()
+                .await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+            let metrics = client
+                .get(format!("http://{addr}/metrics"))
+                .bearer_auth(&root_access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+
+            for expected in [
+                r#"perfitd_http_requests_total{method="POST",route="/m/:metric",status="200"} 1"#,
+                r#"perfitd_http_requests_total{method="GET",route="/",status="429"}"#,
+                r#"perfitd_http_request_duration_seconds_count{method="PUT",route="/m/"} 1"#,
+                r#"perfitd_db_txn_duration_seconds_count{kind="append"} 1"#,
+                "perfitd_rate_limited_requests_total 40",
+                "perfitd_db_file_size_bytes ",
+                // Root one, and the new one
+                "perfitd_accounts 2",
+                "perfitd_metrics 1",
+                "perfitd_data_points 1",
+            ] {
+                assert!(
+                    metrics.contains(expected),
+                    "Missing `{expected}` in:\n{metrics}"
+                );
+            }
+
+            Ok(())
+        })
+        .await
+}
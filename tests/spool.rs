@@ -0,0 +1,182 @@
+mod common;
+
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use perfitd::models::access_token::AccessToken;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+/// Data points that couldn't be sent while perfitd was down get sent later by
+/// `perfit flush`, at their original time
+#[tokio::test(flavor = "multi_thread")]
+async fn flush_after_server_comes_back() -> Result<()> {
+    common::init_logging()?;
+
+    let test_dir = tempfile::tempdir()?;
+    let db_path = test_dir.path().join("db.redb");
+    let spool_dir = test_dir.path().join("spool");
+    let root_access_token = AccessToken::generate().to_string();
+    let addr: SocketAddr = TcpListener::bind("[::1]:0")?.local_addr()?;
+    let bin = get_cargo_bin("perfit");
+
+    // Nothing is listening yet
+    let res = duct::cmd!(&bin, "post", "--retries", "1", "--metric", "unknown", "7")
+        .env("PERFIT_SERVER", format!("http://{}", addr))
+        .env("PERFIT_ACCESS_TOKEN", &root_access_token)
+        .env("PERFIT_SPOOL_DIR", &spool_dir)
+        .stderr_null()
+        .unchecked()
+        .run()?;
+    assert!(!res.status.success());
+    let spooled = std::fs::read_dir(&spool_dir)?
+        .map(|entry| Ok(serde_json::from_slice(&std::fs::read(entry?.path())?)?))
+        .collect::<Result<Vec<serde_json::Value>>>()?;
+    assert_eq!(spooled.len(), 1);
+    assert_eq!(spooled[0]["value"], 7.0);
+
+    let server = duct::cmd!(
+        get_cargo_bin("perfitd"),
+        "--listen",
+        addr.to_string(),
+        "--db",
+        &db_path,
+        "--root-access-token",
+        &root_access_token,
+    )
+    .stdout_null()
+    .start()?;
+
+    let res = async {
+        let client = reqwest::Client::new();
+        while client.get(format!("http://{addr}/")).send().await.is_err() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        info!("Staring test");
+        let spool_dir = spool_dir.clone();
+        let metric_id = tokio::task::spawn_blocking(move || -> Result<_> {
+            let (access_token, metric_id) =
+                common::new_account_with_metric(addr, &root_access_token)?;
+
+            // Point the kept data point at the new metric, as it couldn't have
+            // been created while the server was down
+            let path = std::fs::read_dir(&spool_dir)?
+                .next()
+                .expect("one file")?
+                .path();
+            let mut data_point: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+            data_point["metric"] = metric_id.clone().into();
+            std::fs::write(&path, serde_json::to_vec(&data_point)?)?;
+
+            // Flushing with a different server leaves it alone
+            duct::cmd!(&bin, "flush")
+                .env("PERFIT_SERVER", format!("http://localhost:{}", addr.port()))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .env("PERFIT_SPOOL_DIR", &spool_dir)
+                .run()?;
+            assert!(path.exists());
+
+            // One the server rejects gets moved away, with the reason shown
+            let unknown_metric_path = spool_dir.join("unknown-metric.json");
+            data_point["metric"] = "AH-57edqR8SiMSRr4ArSWg".into();
+            data_point["idempotency-key"] = "unknown-metric".into();
+            std::fs::write(&unknown_metric_path, serde_json::to_vec(&data_point)?)?;
+
+            let res = duct::cmd!(&bin, "flush")
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .env("PERFIT_SPOOL_DIR", &spool_dir)
+                .stderr_capture()
+                .unchecked()
+                .run()?;
+            assert!(!res.status.success());
+            assert!(String::from_utf8_lossy(&res.stderr).contains("Metric Not Found"));
+            assert!(!path.exists());
+            assert!(!unknown_metric_path.exists());
+            assert!(spool_dir
+                .join("rejected")
+                .join("unknown-metric.json")
+                .exists());
+
+            // Nothing left to fail on
+            duct::cmd!(&bin, "flush")
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .env("PERFIT_SPOOL_DIR", &spool_dir)
+                .run()?;
+
+            Ok(metric_id)
+        })
+        .await??;
+
+        let data_points: Vec<serde_json::Value> = client
+            .get(format!("http://{addr}/m/{metric_id}/json"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(data_points.len(), 1);
+        assert_eq!(data_points[0]["v"], 7.0);
+        let original_ts =
+            OffsetDateTime::parse(spooled[0]["ts"].as_str().expect("ts is a string"), &Rfc3339)?;
+        assert_eq!(data_points[0]["t"], original_ts.unix_timestamp());
+
+        Ok::<_, color_eyre::Report>(())
+    }
+    .await;
+    server.kill()?;
+    res
+}
+
+/// Data points can be sent later, but not before the epoch or in the future
+#[tokio::test(flavor = "multi_thread")]
+async fn post_with_out_of_range_ts_is_rejected() -> Result<()> {
+    common::init_logging()?;
+
+    let fixture = PerfitdFixture::new().await?;
+
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    fixture
+        .run(async {
+            info!("Staring test");
+            let (_, metric_id) = tokio::task::spawn_blocking(move || {
+                common::new_account_with_metric(addr, &root_access_token)
+            })
+            .await??;
+
+            let client = reqwest::Client::new();
+            for ts in ["1969-12-31T23:59:59Z", "9999-12-31T23:59:59Z"] {
+                let resp = client
+                    .post(format!("http://{addr}/m/{metric_id}"))
+                    .json(&serde_json::json!({ "value": 1.0, "ts": ts }))
+                    .send()
+                    .await?;
+                assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+            }
+
+            client
+                .post(format!("http://{addr}/m/{metric_id}"))
+                .json(&serde_json::json!({ "value": 1.0, "ts": "1970-01-01T00:00:00Z" }))
+                .send()
+                .await?
+                .error_for_status()?;
+
+            // Charts still render
+            reqwest::get(format!("http://{addr}/m/{metric_id}/svg"))
+                .await?
+                .error_for_status()?;
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use color_eyre::Result;
+use insta_cmd::get_cargo_bin;
+use serde_json::json;
+use tracing::info;
+
+use crate::common::PerfitdFixture;
+
+/// Go through the main operations, returning a summary of the results that
+/// doesn't depend on ids or time
+async fn exercise(fixture: PerfitdFixture) -> Result<serde_json::Value> {
+    let addr = fixture.addr()?;
+
+    let root_access_token = fixture.root_access_token_str();
+
+    let mut summary = json!(null);
+    fixture
+        .run(async {
+            info!("Staring test");
+            let (access_token, metric_id) = tokio::task::spawn_blocking(move || -> Result<_> {
+                let bin = get_cargo_bin("perfit");
+                let (access_token, metric_id) =
+                    common::new_account_with_metric(addr, &root_access_token)?;
+
+                for v in ["3", "1", "2"] {
+                    duct::cmd!(&bin, "post", v, "--metadata", "sha=abc")
+                        .env("PERFIT_SERVER", format!("http://{}", addr))
+                        .env("PERFIT_ACCESS_TOKEN", &access_token)
+                        .env("PERFIT_METRIC", &metric_id)
+                        .env("PERFIT_NO_AUTO_METADATA", "true")
+                        .run()?;
+                }
+                duct::cmd!(&bin, "annotate", "event")
+                    .env("PERFIT_SERVER", format!("http://{}", addr))
+                    .env("PERFIT_ACCESS_TOKEN", &access_token)
+                    .stdout_null()
+                    .run()?;
+                duct::cmd!(
+                    &bin,
+                    "metric",
+                    "retention",
+                    "--raw-days",
+                    "7",
+                    "--then",
+                    "delete"
+                )
+                .env("PERFIT_SERVER", format!("http://{}", addr))
+                .env("PERFIT_ACCESS_TOKEN", &access_token)
+                .env("PERFIT_METRIC", &metric_id)
+                .run()?;
+
+                Ok((access_token, metric_id))
+            })
+            .await??;
+
+            let client = reqwest::Client::new();
+
+            // Rejected by the append itself, in the same transaction that
+            // would write it
+            let past_retention = client
+                .post(format!("http://{addr}/m/{metric_id}"))
+                .json(&json!({
+                    "value": 4.0,
+                    "ts": (time::OffsetDateTime::now_utc() - time::Duration::days(30))
+                        .format(&time::format_description::well_known::Rfc3339)?,
+                }))
+                .send()
+                .await?
+                .status();
+
+            let data_points: Vec<serde_json::Value> = client
+                .get(format!("http://{addr}/m/{metric_id}/json"))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let annotations: Vec<serde_json::Value> = client
+                .get(format!("http://{addr}/n/"))
+                .bearer_auth(&access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let badge = client
+                .get(format!("http://{addr}/m/{metric_id}/badge"))
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+
+            summary = json!({
+                "values": data_points.iter().map(|d| d["v"].clone()).collect::<Vec<_>>(),
+                "metadata": data_points.iter().map(|d| d["m"].clone()).collect::<Vec<_>>(),
+                "annotations": annotations.iter().map(|a| a["label"].clone()).collect::<Vec<_>>(),
+                "badge": badge,
+                "past_retention": past_retention.as_u16(),
+            });
+            Ok(())
+        })
+        .await?;
+
+    Ok(summary)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn in_memory_storage_matches_redb() -> Result<()> {
+    common::init_logging()?;
+
+    let redb = exercise(PerfitdFixture::new().await?).await?;
+    let in_memory = exercise(PerfitdFixture::new_in_memory().await?).await?;
+
+    assert_eq!(redb["values"], json!([3.0, 1.0, 2.0]));
+    assert_eq!(redb["metadata"], json!(["sha=abc", "sha=abc", "sha=abc"]));
+    assert_eq!(redb["annotations"], json!(["event"]));
+    assert_eq!(redb["past_retention"], 400);
+    assert_eq!(redb, in_memory);
+
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use color_eyre::Result;
+use perfitd::models::ts::{TimeStep, TzOffset};
+use time::macros::datetime;
+
+#[test]
+fn time_step_for_range() {
+    const HOUR: u64 = 60 * 60;
+    const DAY: u64 = 24 * HOUR;
+
+    assert_eq!(TimeStep::for_range(10 * 60, 4), TimeStep::Minutes(5));
+    assert_eq!(TimeStep::for_range(5 * HOUR, 4), TimeStep::Hours(2));
+    assert_eq!(TimeStep::for_range(20 * DAY, 4), TimeStep::Weeks(1));
+    assert_eq!(TimeStep::for_range(200 * DAY, 4), TimeStep::Months(2));
+    assert_eq!(TimeStep::for_range(3000 * DAY, 4), TimeStep::Months(36));
+}
+
+#[test]
+fn time_step_calendar() {
+    let dt = datetime!(2024-05-15 13:47:12 UTC);
+
+    let step = TimeStep::Minutes(15);
+    assert_eq!(step.round_down(dt), datetime!(2024-05-15 13:45 UTC));
+    assert_eq!(step.format(step.round_down(dt)), "05-15 13:45");
+
+    // 2024-05-13 was a Monday
+    let step = TimeStep::Weeks(1);
+    assert_eq!(step.round_down(dt), datetime!(2024-05-13 0:00 UTC));
+    assert_eq!(step.format(step.round_down(dt)), "2024-05-13");
+
+    let step = TimeStep::Months(3);
+    assert_eq!(step.round_down(dt), datetime!(2024-04-01 0:00 UTC));
+    assert_eq!(
+        step.next(step.next(step.next(step.round_down(dt)))),
+        datetime!(2025-01-01 0:00 UTC)
+    );
+    assert_eq!(step.format(step.round_down(dt)), "2024-04");
+
+    let step = TimeStep::Months(12);
+    assert_eq!(step.format(step.round_down(dt)), "2024");
+}
+
+#[test]
+fn tz_offset_parse() -> Result<()> {
+    assert_eq!("+02:00".parse::<TzOffset>()?.to_string(), "+02:00");
+    // `+` decoded from a url query as a space
+    assert_eq!(" 05:30".parse::<TzOffset>()?.to_string(), "+05:30");
+    assert_eq!("UTC-5".parse::<TzOffset>()?.to_string(), "-05:00");
+    assert_eq!("Z".parse::<TzOffset>()?.to_string(), "+00:00");
+    assert!("+2:75".parse::<TzOffset>().is_err());
+    Ok(())
+}